@@ -0,0 +1,295 @@
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, Spacing, TokenStream, TokenTree};
+
+use std::collections::{BTreeMap};
+use std::fmt::{Write as FmtWrite};
+
+// The wire tags of the built-in and planned `Msg` variants, shared with
+// `service_base` (see `service_base::msg::BUILTIN_TAGS` and
+// `service_base::tag::FUTURE_TAGS`).
+include!("../../src/reserved_tags.rs");
+
+fn is_reserved_tag(b: &[u8]) -> bool {
+  RESERVED_BUILTIN_TAGS.iter().chain(RESERVED_FUTURE_TAGS.iter()).any(|&(t, _)| t[..] == *b)
+}
+
+// Derives `MsgCodex` for an enum whose variants are each annotated with
+// a 3-byte wire tag, e.g. `#[tag = "FOO"]`.
+//
+// The payload of a unit variant is empty; the payload of a variant with
+// a single unnamed field is the JSON encoding of that field; otherwise,
+// the payload is the JSON encoding of the tuple of the variant fields, in
+// declaration order.
+#[proc_macro_derive(MsgCodex, attributes(tag))]
+pub fn derive_msg_codex(input: TokenStream) -> TokenStream {
+  match expand(input) {
+    Ok(ts) => ts,
+    Err(e) => format!("compile_error!({:?});", format!("#[derive(MsgCodex)]: {}", e)).parse().unwrap()
+  }
+}
+
+enum Fields {
+  Unit,
+  Unnamed(Vec<String>),
+  Named(Vec<(String, String)>),
+}
+
+struct Variant {
+  name: String,
+  tag: [u8; 3],
+  fields: Fields,
+}
+
+fn is_punct(tt: &TokenTree, c: char) -> bool {
+  match tt {
+    &TokenTree::Punct(ref p) => p.as_char() == c,
+    _ => false
+  }
+}
+
+fn is_ident(tt: &TokenTree, s: &str) -> bool {
+  match tt {
+    &TokenTree::Ident(ref id) => id.to_string() == s,
+    _ => false
+  }
+}
+
+fn to_string(tts: &[TokenTree]) -> String {
+  tts.iter().cloned().collect::<TokenStream>().to_string()
+}
+
+// Splits at top-level commas, also tracking angle brackets (which are not
+// token groups) so that e.g. `BTreeMap<K, V>` stays in one piece.
+fn split_commas(tts: Vec<TokenTree>) -> Vec<Vec<TokenTree>> {
+  let mut parts = Vec::new();
+  let mut part = Vec::new();
+  let mut depth = 0_i32;
+  let mut prev_arrow = false;
+  for tt in tts.into_iter() {
+    let mut arrow = false;
+    if let &TokenTree::Punct(ref p) = &tt {
+      match p.as_char() {
+        '<' => depth += 1,
+        '>' if !prev_arrow => depth -= 1,
+        '-' if p.spacing() == Spacing::Joint => arrow = true,
+        ',' if depth <= 0 => {
+          parts.push(part);
+          part = Vec::new();
+          prev_arrow = false;
+          continue;
+        }
+        _ => {}
+      }
+    }
+    prev_arrow = arrow;
+    part.push(tt);
+  }
+  if !part.is_empty() {
+    parts.push(part);
+  }
+  parts
+}
+
+// Strips leading outer attributes, returning them.
+fn strip_attrs(tts: &[TokenTree]) -> (Vec<TokenStream>, &[TokenTree]) {
+  let mut attrs = Vec::new();
+  let mut i = 0;
+  while i + 1 < tts.len() && is_punct(&tts[i], '#') {
+    match &tts[i + 1] {
+      &TokenTree::Group(ref g) if g.delimiter() == Delimiter::Bracket => {
+        attrs.push(g.stream());
+        i += 2;
+      }
+      _ => break
+    }
+  }
+  (attrs, &tts[i ..])
+}
+
+fn strip_vis(tts: &[TokenTree]) -> &[TokenTree] {
+  if tts.len() > 0 && is_ident(&tts[0], "pub") {
+    match tts.get(1) {
+      Some(&TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Parenthesis => &tts[2 ..],
+      _ => &tts[1 ..]
+    }
+  } else {
+    tts
+  }
+}
+
+fn parse_tag(variant: &str, attrs: &[TokenStream]) -> Result<[u8; 3], String> {
+  let mut tag = None;
+  for attr in attrs.iter() {
+    let tts: Vec<TokenTree> = attr.clone().into_iter().collect();
+    if tts.len() == 0 || !is_ident(&tts[0], "tag") {
+      continue;
+    }
+    if tag.is_some() {
+      return Err(format!("variant `{}` has more than one #[tag]", variant));
+    }
+    let lit = match (tts.len(), tts.get(1), tts.get(2)) {
+      (3, Some(eq), Some(&TokenTree::Literal(ref lit))) if is_punct(eq, '=') => lit.to_string(),
+      _ => return Err(format!("variant `{}`: expected #[tag = \"ABC\"]", variant))
+    };
+    let s = if lit.len() >= 2 && lit.starts_with('"') && lit.ends_with('"') && !lit.contains('\\') {
+      &lit[1 .. lit.len() - 1]
+    } else {
+      return Err(format!("variant `{}`: the tag must be a plain string literal, got {}", variant, lit));
+    };
+    let b = s.as_bytes();
+    if b.len() != 3 || !b.iter().all(|&x| x >= 0x21 && x <= 0x7e) {
+      return Err(format!("variant `{}`: the tag must be exactly 3 printable ASCII characters, got {:?}", variant, s));
+    }
+    if is_reserved_tag(b) {
+      return Err(format!("variant `{}`: the tag {:?} is reserved for a built-in `Msg` variant", variant, s));
+    }
+    if b[0].is_ascii_punctuation() {
+      return Err(format!("variant `{}`: tags starting with punctuation, like {:?}, are reserved", variant, s));
+    }
+    tag = Some([b[0], b[1], b[2]]);
+  }
+  tag.ok_or_else(|| format!("variant `{}` is missing a #[tag = \"ABC\"] attribute", variant))
+}
+
+fn parse_variant(tts: Vec<TokenTree>) -> Result<Variant, String> {
+  let (attrs, rest) = strip_attrs(&tts);
+  let name = match rest.get(0) {
+    Some(&TokenTree::Ident(ref id)) => id.to_string(),
+    _ => return Err(format!("unexpected tokens in enum body: {}", to_string(rest)))
+  };
+  let fields = match rest.get(1) {
+    None => Fields::Unit,
+    Some(tt) if is_punct(tt, '=') => Fields::Unit,
+    Some(&TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Parenthesis => {
+      let mut tys = Vec::new();
+      for field in split_commas(g.stream().into_iter().collect()) {
+        let (_, field) = strip_attrs(&field);
+        tys.push(to_string(strip_vis(field)));
+      }
+      if tys.is_empty() {
+        return Err(format!("variant `{}` has no fields; make it a unit variant", name));
+      }
+      Fields::Unnamed(tys)
+    }
+    Some(&TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Brace => {
+      let mut named = Vec::new();
+      for field in split_commas(g.stream().into_iter().collect()) {
+        let (_, field) = strip_attrs(&field);
+        let field = strip_vis(field);
+        match (field.get(0), field.get(1)) {
+          (Some(&TokenTree::Ident(ref id)), Some(colon)) if is_punct(colon, ':') => {
+            named.push((id.to_string(), to_string(&field[2 ..])));
+          }
+          _ => return Err(format!("variant `{}`: unexpected field: {}", name, to_string(field)))
+        }
+      }
+      if named.is_empty() {
+        return Err(format!("variant `{}` has no fields; make it a unit variant", name));
+      }
+      Fields::Named(named)
+    }
+    Some(tt) => return Err(format!("variant `{}`: unexpected token: {}", name, tt))
+  };
+  let tag = parse_tag(&name, &attrs)?;
+  Ok(Variant{name, tag, fields})
+}
+
+fn expand(input: TokenStream) -> Result<TokenStream, String> {
+  let tts: Vec<TokenTree> = input.into_iter().collect();
+  let mut decl = None;
+  for (i, tt) in tts.iter().enumerate() {
+    if is_ident(tt, "struct") || is_ident(tt, "union") {
+      return Err("only enums are supported".to_string());
+    }
+    if is_ident(tt, "enum") {
+      decl = Some(i);
+      break;
+    }
+  }
+  let i = decl.ok_or_else(|| "only enums are supported".to_string())?;
+  let ty = match tts.get(i + 1) {
+    Some(&TokenTree::Ident(ref id)) => id.to_string(),
+    _ => return Err("expected the enum name".to_string())
+  };
+  let body = match tts.get(i + 2) {
+    Some(&TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Brace => g.stream(),
+    Some(tt) if is_punct(tt, '<') => return Err("generic enums are not supported".to_string()),
+    _ => return Err("expected the enum body".to_string())
+  };
+  let mut variants = Vec::new();
+  let mut tags = BTreeMap::new();
+  for tts in split_commas(body.into_iter().collect()) {
+    let v = parse_variant(tts)?;
+    if let Some(prev) = tags.insert(v.tag, v.name.clone()) {
+      return Err(format!("variants `{}` and `{}` have the same tag {:?}",
+          prev, v.name, String::from_utf8_lossy(&v.tag)));
+    }
+    variants.push(v);
+  }
+  Ok(codegen(&ty, &variants).parse().unwrap())
+}
+
+fn codegen(ty: &str, variants: &[Variant]) -> String {
+  let rs = "::service_base::__rustc_serialize";
+  let mut enc = String::new();
+  let mut dec = String::new();
+  let mut tags = Vec::new();
+  for v in variants.iter() {
+    let tag = format!("[{}u8, {}u8, {}u8]", v.tag[0], v.tag[1], v.tag[2]);
+    tags.push(tag.clone());
+    // The match pattern, the expression to encode, the binding and type
+    // to decode into, and the constructor arguments.
+    let (pat, encode, bind, tys, build) = match &v.fields {
+      &Fields::Unit => {
+        writeln!(&mut enc, "&{}::{} => ::std::result::Result::Ok({}),", ty, v.name, tag).unwrap();
+        writeln!(&mut dec, "{} => {{ \
+            if !buf.is_empty() {{ return ::std::result::Result::Err(()); }} \
+            ::std::result::Result::Ok({}::{}) }}", tag, ty, v.name).unwrap();
+        continue;
+      }
+      &Fields::Unnamed(ref tys) if tys.len() == 1 => {
+        ("(ref f0)".to_string(), "f0".to_string(), "f0".to_string(), tys[0].clone(), "(f0)".to_string())
+      }
+      &Fields::Unnamed(ref tys) => {
+        let fs: Vec<_> = (0 .. tys.len()).map(|k| format!("f{}", k)).collect();
+        let refs: Vec<_> = fs.iter().map(|f| format!("ref {}", f)).collect();
+        (format!("({})", refs.join(", ")),
+         format!("&({},)", fs.join(", ")),
+         format!("({},)", fs.join(", ")),
+         format!("({},)", tys.join(", ")),
+         format!("({})", fs.join(", ")))
+      }
+      &Fields::Named(ref named) => {
+        let fs: Vec<_> = (0 .. named.len()).map(|k| format!("f{}", k)).collect();
+        let refs: Vec<_> = named.iter().zip(fs.iter()).map(|(&(ref n, _), f)| format!("{}: ref {}", n, f)).collect();
+        let tys: Vec<_> = named.iter().map(|&(_, ref t)| t.clone()).collect();
+        let inits: Vec<_> = named.iter().zip(fs.iter()).map(|(&(ref n, _), f)| format!("{}: {}", n, f)).collect();
+        (format!("{{{}}}", refs.join(", ")),
+         format!("&({},)", fs.join(", ")),
+         format!("({},)", fs.join(", ")),
+         format!("({},)", tys.join(", ")),
+         format!("{{{}}}", inits.join(", ")))
+      }
+    };
+    writeln!(&mut enc, "&{}::{}{} => {{ \
+        let mut enc = {}::json::JsonEncoder::new(buf); \
+        {}::Encodable::encode({}, &mut enc).map_err(|_| ())?; \
+        ::std::result::Result::Ok({}) }}", ty, v.name, pat, rs, rs, encode, tag).unwrap();
+    writeln!(&mut dec, "{} => {{ \
+        let j = {}::json::Json::from_reader(::std::io::Cursor::new(buf)).map_err(|_| ())?; \
+        let {}: {} = j.decode_into().map_err(|_| ())?; \
+        ::std::result::Result::Ok({}::{}{}) }}", tag, rs, bind, tys, ty, v.name, build).unwrap();
+  }
+  format!("impl ::service_base::msg::MsgCodex for {} {{\n\
+      fn wire_tags() -> ::std::vec::Vec<[u8; 3]> {{\n\
+        ::std::vec![{}]\n\
+      }}\n\
+      fn encode_wire(&self, buf: &mut ::std::string::String) -> ::std::result::Result<[u8; 3], ()> {{\n\
+        match self {{\n{}}}\n\
+      }}\n\
+      fn decode_wire(tag: [u8; 3], buf: &[u8]) -> ::std::result::Result<Self, ()> {{\n\
+        match tag {{\n{}_ => ::std::result::Result::Err(())\n}}\n\
+      }}\n\
+    }}\n", ty, tags.join(", "), enc, dec)
+}
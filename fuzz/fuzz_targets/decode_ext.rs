@@ -0,0 +1,116 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use service_base::frame::{decode_frame, decode_msg, encode_frame, FrameMeta, FRAME_MAX_LEN};
+use service_base::msg::{CodecErr, Either, Msg, MsgCodex, WireCodex};
+
+use std::convert::{TryInto};
+use std::str::{from_utf8};
+
+// A text codec, through the `MsgCodex` adapter: `key=value`. Only `KV?`
+// is declared, so other `KV` tags reach it through the `Either` fallback.
+#[derive(Debug)]
+struct Kv(String, String);
+
+impl MsgCodex for Kv {
+  fn wire_tags() -> Vec<[u8; 3]> {
+    vec![*b"KV?"]
+  }
+
+  fn encode_wire(&self, buf: &mut String) -> Result<[u8; 3], ()> {
+    if self.0.contains('=') {
+      return Err(());
+    }
+    buf.push_str(&self.0);
+    buf.push('=');
+    buf.push_str(&self.1);
+    Ok(*b"KV?")
+  }
+
+  fn decode_wire(tag: [u8; 3], buf: &[u8]) -> Result<Kv, ()> {
+    if &tag[.. 2] != b"KV" {
+      return Err(());
+    }
+    let s = from_utf8(buf).map_err(|_| ())?;
+    let i = s.find('=').ok_or(())?;
+    Ok(Kv(s[.. i].to_string(), s[i + 1 ..].to_string()))
+  }
+}
+
+// A binary codec: a u16 count, then that many u32s, little-endian.
+#[derive(Debug)]
+struct Blob(Vec<u32>);
+
+impl WireCodex for Blob {
+  fn wire_tags() -> Vec<[u8; 3]> {
+    vec![*b"BL?"]
+  }
+
+  fn encode_bytes(&self, buf: &mut Vec<u8>) -> Result<[u8; 3], CodecErr> {
+    if self.0.len() > u16::max_value() as usize {
+      return Err(CodecErr::Unsupported);
+    }
+    buf.extend_from_slice(&(self.0.len() as u16).to_le_bytes());
+    for x in self.0.iter() {
+      buf.extend_from_slice(&x.to_le_bytes());
+    }
+    Ok(*b"BL?")
+  }
+
+  fn decode_bytes(tag: [u8; 3], buf: &[u8]) -> Result<Blob, CodecErr> {
+    if &tag != b"BL?" {
+      return Err(CodecErr::Tag(tag));
+    }
+    if buf.len() < 2 {
+      return Err(CodecErr::Truncated);
+    }
+    let n = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+    let rest = &buf[2 ..];
+    if rest.len() < 4 * n {
+      return Err(CodecErr::Truncated);
+    }
+    if rest.len() > 4 * n {
+      return Err(CodecErr::Trailing);
+    }
+    Ok(Blob(rest.chunks(4).map(|c| u32::from_le_bytes(c.try_into().unwrap())).collect()))
+  }
+}
+
+type Ext = Either<Kv, Blob>;
+
+// The first byte picks the entry point: a message (the next 3 bytes are
+// the tag, the rest is the payload), or a stream of frames. Whatever
+// decodes must encode and decode again.
+fuzz_target!(|data: &[u8]| {
+  if data.is_empty() {
+    return;
+  }
+  let (mode, data) = (data[0], &data[1 ..]);
+  let mut msgs = Vec::new();
+  if mode & 1 == 0 {
+    // Larger payloads could not have come in a frame.
+    if data.len() < 3 || data.len() - 3 >= FRAME_MAX_LEN {
+      return;
+    }
+    let tag = [data[0], data[1], data[2]];
+    if let Ok(msg) = decode_msg::<Ext>(tag, &data[3 ..]) {
+      msgs.push(msg);
+    }
+  } else {
+    let mut buf = data;
+    while let Ok((msg, _, n)) = decode_frame::<Ext>(buf) {
+      msgs.push(msg);
+      buf = &buf[n ..];
+    }
+  }
+  for msg in msgs.iter() {
+    if let &Msg::Ext(_) = msg {
+      let mut out = Vec::new();
+      encode_frame(msg, 1, &FrameMeta::default(), &mut out).unwrap();
+      match decode_frame::<Ext>(&out) {
+        Ok((Msg::Ext(_), _, n)) if n == out.len() => {}
+        x => panic!("re-decoding {:?} gave {:?}", msg, x),
+      }
+    }
+  }
+});
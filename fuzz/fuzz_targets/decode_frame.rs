@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use service_base::frame::{decode_frame};
+
+fuzz_target!(|data: &[u8]| {
+  let mut buf = data;
+  while let Ok((_, _, n)) = decode_frame::<()>(buf) {
+    buf = &buf[n ..];
+  }
+});
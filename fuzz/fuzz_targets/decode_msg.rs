@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use service_base::frame::{decode_msg};
+
+// The first 3 bytes are the tag, the rest is the payload.
+fuzz_target!(|data: &[u8]| {
+  if data.len() < 3 {
+    return;
+  }
+  let tag = [data[0], data[1], data[2]];
+  let _ = decode_msg::<()>(tag, &data[3 ..]);
+});
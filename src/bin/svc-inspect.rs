@@ -0,0 +1,284 @@
+extern crate byteorder;
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::capture::{CaptureDir, CaptureReader};
+use service_base::frame::{FRAME_HDR_LEN, decode_frame_fixed, frame_ext_len};
+use service_base::msg::{BUILTIN_TAGS, builtin_tag_name};
+use service_base::tag::{FUTURE_TAGS};
+
+use byteorder::{WriteBytesExt, LittleEndian as LE};
+use rustc_serialize::json::{Json};
+
+use std::env::{args};
+use std::io::{Read, Write, BufReader, BufWriter, Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{exit};
+use std::str::{from_utf8};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn};
+use std::time::{UNIX_EPOCH};
+
+fn usage() -> ! {
+  eprintln!("usage:");
+  eprintln!("  svc-inspect capture <capture-file>");
+  eprintln!("  svc-inspect send <addr> <tag> [<payload>]");
+  eprintln!("  svc-inspect proxy <listen-addr> <upstream-addr>");
+  eprintln!("  svc-inspect tags");
+  eprintln!();
+  eprintln!("examples:");
+  eprintln!("  svc-inspect send 127.0.0.1:9000 'OK?'");
+  eprintln!("  svc-inspect send 127.0.0.1:9000 JSO '{{\"op\":\"stats\"}}'");
+  eprintln!("  svc-inspect send 127.0.0.1:9000 FOO 0xdeadbeef");
+  exit(2)
+}
+
+struct RawFrame {
+  seq: u64,
+  tag: [u8; 3],
+  flags: u8,
+  ext: Vec<u8>,
+  payload: Vec<u8>,
+}
+
+// The header is checked as by `Chan` (e.g. the length is bounded by
+// `FRAME_MAX_LEN`) before anything is allocated for the frame.
+fn read_frame<R: Read>(r: &mut R) -> Result<Option<RawFrame>, IoError> {
+  let mut fixed_buf = [0; FRAME_HDR_LEN];
+  match r.read_exact(&mut fixed_buf) {
+    Err(ref e) if e.kind() == IoErrorKind::UnexpectedEof => return Ok(None),
+    Err(e) => return Err(e),
+    Ok(_) => {}
+  }
+  let fixed = decode_frame_fixed(&fixed_buf)
+    .map_err(|e| IoError::new(IoErrorKind::InvalidData, format!("invalid frame header: {:?}", e)))?;
+  let mut ext = vec![0; frame_ext_len(fixed.flags)];
+  r.read_exact(&mut ext)?;
+  let mut payload = vec![0; fixed.len];
+  r.read_exact(&mut payload)?;
+  Ok(Some(RawFrame{seq: fixed.seq, tag: fixed.tag, flags: fixed.flags, ext, payload}))
+}
+
+fn write_frame<W: Write>(w: &mut W, frame: &RawFrame) -> Result<(), IoError> {
+  w.write_u64::<LE>(frame.seq)?;
+  w.write_u32::<LE>(u32::from_le_bytes([frame.tag[0], frame.tag[1], frame.tag[2], frame.flags]))?;
+  w.write_u32::<LE>(frame.payload.len() as u32)?;
+  w.write_all(&frame.ext)?;
+  w.write_all(&frame.payload)?;
+  w.flush()
+}
+
+fn tag_str(tag: &[u8; 3]) -> String {
+  match from_utf8(tag) {
+    Ok(s) => s.to_string(),
+    Err(_) => format!("0x{:02x}{:02x}{:02x}", tag[0], tag[1], tag[2])
+  }
+}
+
+fn hexdump(buf: &[u8]) -> String {
+  let mut s = String::new();
+  for (i, row) in buf.chunks(16).enumerate() {
+    s.push_str(&format!("  {:08x} ", i * 16));
+    for x in row.iter() {
+      s.push_str(&format!(" {:02x}", x));
+    }
+    for _ in row.len() .. 16 {
+      s.push_str("   ");
+    }
+    s.push_str("  |");
+    for &x in row.iter() {
+      s.push(if x >= 0x20 && x < 0x7f { x as char } else { '.' });
+    }
+    s.push_str("|\n");
+  }
+  s
+}
+
+fn print_frame(prefix: &str, seq: u64, tag: &[u8; 3], flags: u8, payload: &[u8]) {
+  let name = builtin_tag_name(tag).unwrap_or("Ext");
+  if flags != 0 {
+    println!("{} seq={} tag={:?} ({}) flags=0x{:02x} len={}", prefix, seq, tag_str(tag), name, flags, payload.len());
+  } else {
+    println!("{} seq={} tag={:?} ({}) len={}", prefix, seq, tag_str(tag), name, payload.len());
+  }
+  if payload.is_empty() {
+    return;
+  }
+  match tag {
+    b"H1?" | b"H1." | b"JSO" => {
+      match from_utf8(payload).ok().and_then(|s| Json::from_str(s).ok()) {
+        Some(j) => {
+          for line in format!("{}", j.pretty()).lines() {
+            println!("  {}", line);
+          }
+        }
+        None => {
+          println!("  (invalid json)");
+          print!("{}", hexdump(payload));
+        }
+      }
+    }
+    _ => {
+      print!("{}", hexdump(payload));
+    }
+  }
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+  if s.len() % 2 != 0 {
+    return None;
+  }
+  let mut buf = Vec::with_capacity(s.len() / 2);
+  for i in (0 .. s.len()).step_by(2) {
+    buf.push(u8::from_str_radix(s.get(i .. i + 2)?, 16).ok()?);
+  }
+  Some(buf)
+}
+
+fn parse_msg(tag_s: &str, payload_s: Option<&str>) -> Result<RawFrame, String> {
+  let tag_b = tag_s.as_bytes();
+  if tag_b.len() != 3 {
+    return Err(format!("tag must be exactly 3 bytes: {:?}", tag_s));
+  }
+  let tag = [tag_b[0], tag_b[1], tag_b[2]];
+  let payload = match (&tag, payload_s) {
+    (_, None) => Vec::new(),
+    (b"H1?", Some(s)) | (b"H1.", Some(s)) | (b"JSO", Some(s)) => {
+      if let Err(e) = Json::from_str(s) {
+        return Err(format!("invalid json payload: {:?}", e));
+      }
+      s.as_bytes().to_owned()
+    }
+    (_, Some(s)) => {
+      if s.starts_with("0x") {
+        parse_hex(&s[2 ..]).ok_or_else(|| format!("invalid hex payload: {:?}", s))?
+      } else {
+        s.as_bytes().to_owned()
+      }
+    }
+  };
+  Ok(RawFrame{seq: 1, tag, flags: 0, ext: Vec::new(), payload})
+}
+
+fn run_capture(path: &str) -> Result<(), IoError> {
+  for rec in CaptureReader::open(path)? {
+    let rec = rec?;
+    let dir = match rec.dir {
+      CaptureDir::Send => "send",
+      CaptureDir::Recv => "recv",
+    };
+    let t = rec.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let prefix = format!("[{}.{:06}] stream={} {}", t.as_secs(), t.subsec_micros(), rec.stream, dir);
+    print_frame(&prefix, rec.seq, &rec.tag, 0, &rec.payload);
+  }
+  Ok(())
+}
+
+// Extension tags are declared by the types of a service, so only the
+// reserved tags are known here.
+fn run_tags() -> Result<(), IoError> {
+  for &(tag, name) in BUILTIN_TAGS.iter() {
+    println!("{:?}\tbuiltin\t{}", tag_str(&tag), name);
+  }
+  for &(tag, name) in FUTURE_TAGS.iter() {
+    println!("{:?}\treserved\t{}", tag_str(&tag), name);
+  }
+  println!("(tags starting with punctuation, or with non-printable bytes, are also reserved)");
+  Ok(())
+}
+
+fn run_send(addr: &str, query: RawFrame) -> Result<(), IoError> {
+  let stream = TcpStream::connect(addr)?;
+  let mut rx = BufReader::new(stream.try_clone()?);
+  let mut tx = BufWriter::new(stream);
+  print_frame(">>", query.seq, &query.tag, query.flags, &query.payload);
+  write_frame(&mut tx, &query)?;
+  match read_frame(&mut rx)? {
+    None => {
+      println!("<< (connection closed)");
+    }
+    Some(reply) => {
+      print_frame("<<", reply.seq, &reply.tag, reply.flags, &reply.payload);
+    }
+  }
+  Ok(())
+}
+
+fn forward(conn: usize, prefix: &'static str, src: TcpStream, dst: TcpStream, out: Arc<Mutex<()>>) {
+  let mut rx = BufReader::new(src);
+  let mut tx = BufWriter::new(dst);
+  loop {
+    match read_frame(&mut rx) {
+      Ok(Some(frame)) => {
+        {
+          let _out = out.lock().unwrap();
+          print_frame(&format!("conn={} {}", conn, prefix), frame.seq, &frame.tag, frame.flags, &frame.payload);
+        }
+        if write_frame(&mut tx, &frame).is_err() {
+          break;
+        }
+      }
+      Ok(None) => break,
+      Err(e) => {
+        let _out = out.lock().unwrap();
+        println!("conn={} {} error: {}", conn, prefix, e);
+        break;
+      }
+    }
+  }
+  let _ = tx.get_ref().shutdown(Shutdown::Write);
+}
+
+fn run_proxy(listen_addr: &str, upstream_addr: &str) -> Result<(), IoError> {
+  let bind = TcpListener::bind(listen_addr)?;
+  let out = Arc::new(Mutex::new(()));
+  for (conn, stream) in bind.incoming().enumerate() {
+    let client = match stream {
+      Err(_) => continue,
+      Ok(s) => s
+    };
+    let upstream = match TcpStream::connect(upstream_addr) {
+      Err(e) => {
+        eprintln!("conn={} failed to connect upstream: {}", conn, e);
+        continue;
+      }
+      Ok(s) => s
+    };
+    let (client2, upstream2) = (client.try_clone()?, upstream.try_clone()?);
+    let out2 = out.clone();
+    spawn(move || forward(conn, ">>", client, upstream, out2));
+    let out2 = out.clone();
+    spawn(move || forward(conn, "<<", upstream2, client2, out2));
+  }
+  Ok(())
+}
+
+fn main() {
+  let args: Vec<String> = args().collect();
+  let res = match args.get(1).map(|s| s.as_str()) {
+    Some("capture") if args.len() == 3 => {
+      run_capture(&args[2])
+    }
+    Some("send") if args.len() == 4 || args.len() == 5 => {
+      let query = match parse_msg(&args[3], args.get(4).map(|s| s.as_str())) {
+        Err(e) => {
+          eprintln!("svc-inspect: {}", e);
+          exit(2);
+        }
+        Ok(q) => q
+      };
+      run_send(&args[2], query)
+    }
+    Some("proxy") if args.len() == 4 => {
+      run_proxy(&args[2], &args[3])
+    }
+    Some("tags") if args.len() == 2 => {
+      run_tags()
+    }
+    _ => usage()
+  };
+  if let Err(e) = res {
+    eprintln!("svc-inspect: {}", e);
+    exit(1);
+  }
+}
@@ -0,0 +1,238 @@
+use crate::chan::*;
+use crate::frame::*;
+use crate::msg::*;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian as LE};
+
+use std::fs::{File};
+use std::io::{Read, Write, BufReader, BufWriter, Error as IoError, ErrorKind as IoErrorKind};
+use std::path::{Path};
+use std::sync::{Mutex};
+use std::sync::atomic::{AtomicU32, Ordering as AtomicOrdering};
+use std::time::{Duration as StdDuration, SystemTime, UNIX_EPOCH};
+
+// A capture file starts with this magic, followed by a sequence of
+// records, each of which is laid out as:
+//
+//     time_us: u64, stream: u32, dir: u8, tag: [u8; 3], seq: u64,
+//     len: u32, payload: [u8; len]
+//
+// with all integers little-endian.
+pub const CAPTURE_MAGIC: &'static [u8; 8] = b"SVCCAP01";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum CaptureDir {
+  Send = 0,
+  Recv,
+}
+
+impl CaptureDir {
+  pub fn flip(self) -> CaptureDir {
+    match self {
+      CaptureDir::Send => CaptureDir::Recv,
+      CaptureDir::Recv => CaptureDir::Send,
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct CaptureRecord {
+  pub time: SystemTime,
+  // Distinguishes the `Chan`s tapped into the same capture file.
+  pub stream: u32,
+  pub dir: CaptureDir,
+  pub tag: [u8; 3],
+  pub seq: u64,
+  pub payload: Vec<u8>,
+}
+
+impl CaptureRecord {
+  pub fn decode<MsgX: WireCodex>(&self) -> Result<Msg<MsgX>, RecvErr> {
+    decode_msg(self.tag, &self.payload)
+  }
+
+  pub fn write_to<W: Write>(&self, w: &mut W) -> Result<(), IoError> {
+    let time_us = self.time.duration_since(UNIX_EPOCH)
+      .map(|d| d.as_micros() as u64).unwrap_or(0);
+    w.write_u64::<LE>(time_us)?;
+    w.write_u32::<LE>(self.stream)?;
+    w.write_u8(self.dir as u8)?;
+    w.write_all(&self.tag)?;
+    w.write_u64::<LE>(self.seq)?;
+    w.write_u32::<LE>(self.payload.len() as u32)?;
+    w.write_all(&self.payload)?;
+    Ok(())
+  }
+
+  // Returns `Ok(None)` at a clean end of input, i.e. between records.
+  pub fn read_from<R: Read>(r: &mut R) -> Result<Option<CaptureRecord>, IoError> {
+    let mut time_buf = [0; 8];
+    let mut n = 0;
+    while n < time_buf.len() {
+      match r.read(&mut time_buf[n ..]) {
+        Ok(0) if n == 0 => return Ok(None),
+        Ok(0) => return Err(IoError::new(IoErrorKind::UnexpectedEof, "truncated capture record")),
+        Ok(k) => n += k,
+        Err(ref e) if e.kind() == IoErrorKind::Interrupted => {}
+        Err(e) => return Err(e)
+      }
+    }
+    let time_us = u64::from_le_bytes(time_buf);
+    let stream = r.read_u32::<LE>()?;
+    let dir = match r.read_u8()? {
+      0 => CaptureDir::Send,
+      1 => CaptureDir::Recv,
+      _ => return Err(IoError::new(IoErrorKind::InvalidData, "invalid capture dir"))
+    };
+    let mut tag = [0; 3];
+    r.read_exact(&mut tag)?;
+    let seq = r.read_u64::<LE>()?;
+    let len = r.read_u32::<LE>()? as u64;
+    let mut payload = Vec::new();
+    r.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+      return Err(IoError::new(IoErrorKind::UnexpectedEof, "truncated capture record"));
+    }
+    Ok(Some(CaptureRecord{
+      time: UNIX_EPOCH + StdDuration::from_micros(time_us),
+      stream,
+      dir,
+      tag,
+      seq,
+      payload,
+    }))
+  }
+}
+
+pub struct CaptureWriter {
+  file: Mutex<BufWriter<File>>,
+  stream_ctr: AtomicU32,
+}
+
+impl CaptureWriter {
+  pub fn create<P: AsRef<Path>>(path: P) -> Result<CaptureWriter, IoError> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(CAPTURE_MAGIC)?;
+    file.flush()?;
+    Ok(CaptureWriter{
+      file: Mutex::new(file),
+      stream_ctr: AtomicU32::new(0),
+    })
+  }
+
+  pub fn next_stream(&self) -> u32 {
+    self.stream_ctr.fetch_add(1, AtomicOrdering::Relaxed)
+  }
+
+  pub fn record(&self, stream: u32, dir: CaptureDir, tag: [u8; 3], seq: u64, payload: &[u8]) {
+    let rec = CaptureRecord{
+      time: SystemTime::now(),
+      stream,
+      dir,
+      tag,
+      seq,
+      payload: payload.to_owned(),
+    };
+    let mut file = self.file.lock().unwrap();
+    // Capturing is best-effort and never fails the tapped `Chan`.
+    let _ = rec.write_to(&mut *file).and_then(|_| file.flush());
+  }
+}
+
+pub struct CaptureReader<R=BufReader<File>> {
+  r: R,
+}
+
+impl CaptureReader {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<CaptureReader, IoError> {
+    CaptureReader::new(BufReader::new(File::open(path)?))
+  }
+}
+
+impl<R: Read> CaptureReader<R> {
+  pub fn new(mut r: R) -> Result<CaptureReader<R>, IoError> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != CAPTURE_MAGIC {
+      return Err(IoError::new(IoErrorKind::InvalidData, "not a capture file"));
+    }
+    Ok(CaptureReader{r})
+  }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+  type Item = Result<CaptureRecord, IoError>;
+
+  fn next(&mut self) -> Option<Result<CaptureRecord, IoError>> {
+    CaptureRecord::read_from(&mut self.r).transpose()
+  }
+}
+
+#[derive(Debug)]
+pub struct ReplayItem<MsgX=()> {
+  pub stream: u32,
+  pub seq: u64,
+  pub query: Msg<MsgX>,
+  pub reply: Msg<MsgX>,
+  // Whether the replayed reply is byte-for-byte equal to the recorded
+  // reply; `None` if no reply was recorded.
+  pub matched: Option<bool>,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ReplayErr {
+  Recv(RecvErr),
+  Send(SendErr),
+  Query(QueryErr),
+}
+
+fn replay_match_raw(records: &[CaptureRecord], query: &CaptureRecord, tag: [u8; 3], payload: &[u8]) -> Option<bool> {
+  let recorded = records.iter().find(|r| {
+    r.stream == query.stream && r.dir == query.dir.flip() && r.seq == query.seq
+  })?;
+  Some(tag == recorded.tag && payload == &recorded.payload[..])
+}
+
+fn replay_match<MsgX: WireCodex>(records: &[CaptureRecord], query: &CaptureRecord, reply: &Msg<MsgX>) -> Result<Option<bool>, ReplayErr> {
+  let mut buf = Vec::new();
+  let tag = encode_msg(reply, &mut buf).map_err(ReplayErr::Send)?;
+  Ok(replay_match_raw(records, query, tag, &buf))
+}
+
+// Feeds the recorded queries (i.e. the frames in direction `query_dir`)
+// to a handler, and compares its replies with the recorded replies.
+pub fn replay_handler<MsgX: WireCodex, P: Fn(&Msg<MsgX>) -> Msg<MsgX>>(records: &[CaptureRecord], query_dir: CaptureDir, proc_: P) -> Result<Vec<ReplayItem<MsgX>>, ReplayErr> {
+  let mut items = Vec::new();
+  for rec in records.iter().filter(|r| r.dir == query_dir) {
+    let query = rec.decode().map_err(ReplayErr::Recv)?;
+    let reply = (proc_)(&query);
+    let matched = replay_match(records, rec, &reply)?;
+    items.push(ReplayItem{stream: rec.stream, seq: rec.seq, query, reply, matched});
+  }
+  Ok(items)
+}
+
+// Re-sends the recorded queries (i.e. the frames in direction
+// `query_dir`) to a live service, and compares its replies with the
+// recorded replies.
+pub fn replay_live<MsgX: WireCodex>(records: &[CaptureRecord], query_dir: CaptureDir, chan: &mut Chan<MsgX>) -> Result<Vec<ReplayItem<MsgX>>, ReplayErr> {
+  let mut items = Vec::new();
+  for rec in records.iter().filter(|r| r.dir == query_dir) {
+    let query = rec.decode().map_err(ReplayErr::Recv)?;
+    let (reply, matched) = match chan.query(&query) {
+      // A Bot with details is a reply like any other; compare it as sent.
+      Err(QueryErr::Bot(details)) => {
+        (Msg::Bot, replay_match_raw(records, rec, *b"!!!", details.as_bytes()))
+      }
+      Err(e) => return Err(ReplayErr::Query(e)),
+      Ok(reply) => {
+        let matched = replay_match(records, rec, &reply)?;
+        (reply, matched)
+      }
+    };
+    items.push(ReplayItem{stream: rec.stream, seq: rec.seq, query, reply, matched});
+  }
+  Ok(items)
+}
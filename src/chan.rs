@@ -1,16 +1,30 @@
+use crate::capture::{CaptureDir, CaptureWriter};
+use crate::ctx::{ConnCtx, PeerAddr};
+use crate::deadline::{min_deadline, with_deadline};
+use crate::frame::*;
+use crate::logging::{LogLevel, log};
+use crate::metrics::{Counter, Histogram, metrics};
+use crate::middleware::{Layered, Middleware, MiddlewareChain};
 use crate::msg::*;
+use crate::ratelimit::{ConnLimiter, PeerKey, RateLimiter, ThrottleAction};
+use crate::service::{FnService, Outcome, Service, ServiceFactory};
+use crate::session::*;
+use crate::tag::{register_tags_or_panic};
+use crate::trace::{SpanRecord, export_span, with_trace};
+use crate::watchdog::{QueryBudget};
 
-use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian as LE};
-use rustc_serialize::{Encodable};
-use rustc_serialize::json::{Json, JsonEncoder};
+use rustc_serialize::json::{Json};
 
-use std::fmt::{Write as FmtWrite};
-use std::io::{Read, Write, BufReader, BufWriter, Cursor};
+use std::any::{Any};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Read, Write, BufReader, BufWriter, Error as IoError};
 use std::marker::{PhantomData};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixStream};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Mutex};
 use std::thread::{spawn};
-use std::time::{Duration as StdDuration};
+use std::time::{Duration as StdDuration, Instant, SystemTime};
 
 #[derive(Debug)]
 #[non_exhaustive]
@@ -20,7 +34,17 @@ pub enum SendErr {
   IO,
   Seq,
   Overflow,
+  Tag,
   JsonWrite,
+  Codec(CodecErr),
+  // The flow control window is exhausted.
+  WouldBlock,
+  // The deadline passed while waiting for the flow control window.
+  Deadline,
+  // Receiving while waiting for the flow control window failed.
+  Recv(RecvErr),
+  // An earlier error left the stream misframed; reconnect.
+  Broken,
 }
 
 #[derive(Debug)]
@@ -30,15 +54,156 @@ pub enum RecvErr {
   Top,
   IO,
   Seq,
+  Flags,
+  Overflow,
+  Truncated,
   Trailing,
   JsonBuild,
   JsonDecode,
+  Codec(CodecErr),
+  // An earlier error left the stream misframed; reconnect.
+  Broken,
+}
+
+impl SendErr {
+  // A short name of the error kind, e.g. for metric labels.
+  pub fn kind(&self) -> &'static str {
+    match self {
+      &SendErr::Top => "top",
+      &SendErr::IO => "io",
+      &SendErr::Seq => "seq",
+      &SendErr::Overflow => "overflow",
+      &SendErr::Tag => "tag",
+      &SendErr::JsonWrite => "json_write",
+      &SendErr::Codec(_) => "codec",
+      &SendErr::WouldBlock => "would_block",
+      &SendErr::Deadline => "deadline",
+      &SendErr::Recv(_) => "recv",
+      &SendErr::Broken => "broken",
+    }
+  }
+}
+
+impl RecvErr {
+  pub fn kind(&self) -> &'static str {
+    match self {
+      &RecvErr::Top => "top",
+      &RecvErr::IO => "io",
+      &RecvErr::Seq => "seq",
+      &RecvErr::Flags => "flags",
+      &RecvErr::Overflow => "overflow",
+      &RecvErr::Truncated => "truncated",
+      &RecvErr::Trailing => "trailing",
+      &RecvErr::JsonBuild => "json_build",
+      &RecvErr::JsonDecode => "json_decode",
+      &RecvErr::Codec(_) => "codec",
+      &RecvErr::Broken => "broken",
+    }
+  }
+}
+
+const HANDLER_PANICS: &'static str = "chan_handler_panics_total";
+
+// The number of handler panics caught (see `Chan::reply_ctx`) in this
+// process.
+pub fn handler_panics() -> u64 {
+  handler_panics_counter().get()
+}
+
+fn handler_panics_counter() -> Counter {
+  metrics().counter(HANDLER_PANICS, "Handler panics caught.", &[])
+}
+
+fn expired(deadline: Option<Instant>) -> bool {
+  match deadline {
+    None => false,
+    Some(d) => d <= Instant::now()
+  }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+  if let Some(s) = payload.downcast_ref::<&'static str>() {
+    return s.to_string();
+  }
+  if let Some(s) = payload.downcast_ref::<String>() {
+    return s.clone();
+  }
+  "(non-string panic payload)".to_string()
+}
+
+// Handles to the global metrics that every `Chan` reports into. Labeled
+// metrics are looked up on first use, then kept, as each lookup takes
+// the registry lock.
+struct ChanMetrics {
+  frames_sent: Counter,
+  bytes_sent: Counter,
+  frames_recv: Counter,
+  bytes_recv: Counter,
+  panics: Counter,
+  send_errs: BTreeMap<&'static str, Counter>,
+  recv_errs: BTreeMap<&'static str, Counter>,
+  query_latency: BTreeMap<[u8; 3], Histogram>,
+}
+
+impl ChanMetrics {
+  fn new() -> ChanMetrics {
+    let m = metrics();
+    ChanMetrics{
+      frames_sent: m.counter("chan_frames_sent_total", "Frames sent.", &[]),
+      bytes_sent: m.counter("chan_bytes_sent_total", "Frame payload bytes sent.", &[]),
+      frames_recv: m.counter("chan_frames_received_total", "Frames received.", &[]),
+      bytes_recv: m.counter("chan_bytes_received_total", "Frame payload bytes received.", &[]),
+      panics: handler_panics_counter(),
+      send_errs: BTreeMap::new(),
+      recv_errs: BTreeMap::new(),
+      query_latency: BTreeMap::new(),
+    }
+  }
+
+  fn send_err(&mut self, e: &SendErr) {
+    let kind = e.kind();
+    self.send_errs.entry(kind).or_insert_with(|| {
+      metrics().counter("chan_send_errors_total", "Errors sending frames, by kind.", &[("kind", kind)])
+    }).inc();
+  }
+
+  fn recv_err(&mut self, e: &RecvErr) {
+    let kind = e.kind();
+    self.recv_errs.entry(kind).or_insert_with(|| {
+      metrics().counter("chan_recv_errors_total", "Errors receiving frames, by kind.", &[("kind", kind)])
+    }).inc();
+  }
+
+  fn query_latency(&mut self, tag: [u8; 3]) -> &Histogram {
+    self.query_latency.entry(tag).or_insert_with(|| {
+      metrics().histogram("chan_query_duration_seconds", "Time to handle and reply to a query, by query tag.",
+          &[("tag", &String::from_utf8_lossy(&tag))])
+    })
+  }
+
+  fn sent(&self, len: usize) {
+    self.frames_sent.inc();
+    self.bytes_sent.add(len as u64);
+  }
+
+  fn recv(&self, len: usize) {
+    self.frames_recv.inc();
+    self.bytes_recv.add(len as u64);
+  }
 }
 
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum QueryErr {
   Seq,
+  Connect,
+  Open,
+  // The deadline passed before the reply arrived. The `Chan` is broken
+  // afterwards, as the late reply would be taken for the next one.
+  Deadline,
+  // The peer answered with `Bot` and error details (see
+  // `frame::bot_details`), e.g. as its handler panicked.
+  Bot(String),
   Send(SendErr),
   Recv(RecvErr),
 }
@@ -75,209 +240,707 @@ impl From<RecvErr> for ReplyErr {
   }
 }
 
+// The byte stream underlying a `Chan`. The receive and send halves of a
+// `Chan` are separate handles obtained by `try_clone_stream`.
+pub trait ChanStream: Read + Write + Send {
+  fn try_clone_stream(&self) -> Result<Box<dyn ChanStream>, IoError>;
+  fn set_read_timeout(&self, timeout: Option<StdDuration>) -> Result<(), IoError>;
+
+  fn peer(&self) -> PeerAddr {
+    PeerAddr::Unknown
+  }
+
+  // Shuts down both directions, for all handles to the stream.
+  fn shutdown(&self) -> Result<(), IoError> {
+    Ok(())
+  }
+}
+
+impl ChanStream for TcpStream {
+  fn try_clone_stream(&self) -> Result<Box<dyn ChanStream>, IoError> {
+    Ok(Box::new(self.try_clone()?))
+  }
+
+  fn set_read_timeout(&self, timeout: Option<StdDuration>) -> Result<(), IoError> {
+    TcpStream::set_read_timeout(self, timeout)
+  }
+
+  fn peer(&self) -> PeerAddr {
+    self.peer_addr().map(PeerAddr::Tcp).unwrap_or(PeerAddr::Unknown)
+  }
+
+  fn shutdown(&self) -> Result<(), IoError> {
+    TcpStream::shutdown(self, Shutdown::Both)
+  }
+}
+
+impl ChanStream for UnixStream {
+  fn try_clone_stream(&self) -> Result<Box<dyn ChanStream>, IoError> {
+    Ok(Box::new(self.try_clone()?))
+  }
+
+  fn set_read_timeout(&self, timeout: Option<StdDuration>) -> Result<(), IoError> {
+    UnixStream::set_read_timeout(self, timeout)
+  }
+
+  fn peer(&self) -> PeerAddr {
+    PeerAddr::Unix
+  }
+
+  fn shutdown(&self) -> Result<(), IoError> {
+    UnixStream::shutdown(self, Shutdown::Both)
+  }
+}
+
+// Sends a reply on behalf of a `Chan` whose thread is busy in a handler
+// (see `watchdog`), going through the session reply cache, the capture
+// and the metrics like the `Chan` itself would.
+pub(crate) struct SideReply {
+  tx: Arc<Mutex<Box<dyn ChanStream>>>,
+  cap: Option<(Arc<CaptureWriter>, u32)>,
+  sess: Option<(Arc<SessionStore>, Arc<Session>)>,
+  frames_sent: Counter,
+  bytes_sent: Counter,
+}
+
+impl SideReply {
+  pub fn send(&self, seq: u64, tag: [u8; 3], payload: &[u8]) -> Result<(), SendErr> {
+    if let Some(&(ref store, ref state)) = self.sess.as_ref() {
+      store.record(state, seq, tag, payload);
+    }
+    let mut buf = Vec::with_capacity(FRAME_HDR_LEN + payload.len());
+    encode_frame_hdr(&mut buf, seq, tag, &FrameMeta::default(), payload.len())?;
+    buf.extend_from_slice(payload);
+    {
+      let mut tx = self.tx.lock().unwrap();
+      tx.write_all(&buf).and_then(|_| tx.flush()).map_err(|_| SendErr::IO)?;
+    }
+    self.frames_sent.inc();
+    self.bytes_sent.add(payload.len() as u64);
+    if let Some(&(ref cap, stream)) = self.cap.as_ref() {
+      cap.record(stream, CaptureDir::Send, tag, seq, payload);
+    }
+    Ok(())
+  }
+
+  // Shuts down both directions of the connection.
+  pub fn shutdown(&self) {
+    let _ = self.tx.lock().unwrap().shutdown();
+  }
+}
+
 pub struct Chan<MsgX=()> {
-  rx:   BufReader<TcpStream>,
-  tx:   BufWriter<TcpStream>,
+  rx:   BufReader<Box<dyn ChanStream>>,
+  tx:   BufWriter<Box<dyn ChanStream>>,
   rseq: u64,
   tseq: u64,
   // FIXME: should be able to reuse the buffers.
   rbuf: Vec<u8>,
-  //tbuf: Vec<u8>,
-  tbuf: String,
+  tbuf: Vec<u8>,
+  cap:  Option<(Arc<CaptureWriter>, u32)>,
+  sess: Option<ChanSession>,
+  flow: Option<FlowWindow>,
+  limit: Option<ConnLimiter>,
+  budget: Option<(QueryBudget, Arc<Mutex<Box<dyn ChanStream>>>)>,
+  ctx:  ConnCtx,
+  stat: ChanMetrics,
+  // Replies received by a blocked `send`, not yet returned by `recv`.
+  rqueue: VecDeque<(FrameHdr, Vec<u8>)>,
+  // Set when a partial read or write, or an abandoned query, leaves the
+  // stream misframed; all later frames fail with `Broken`.
+  broken: bool,
   _mrk: PhantomData<fn (MsgX) -> MsgX>,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FlowMode {
+  // `send` receives replies (queued for `recv`) until the window opens.
+  Block,
+  // `send` fails with `SendErr::WouldBlock`.
+  WouldBlock,
+}
+
+// Bounds the number of unanswered queries sent on a client `Chan`, so
+// that a pipelining client cannot outrun the peer.
+#[derive(Clone, Copy, Debug)]
+pub struct FlowWindow {
+  // Zero means no limit.
+  pub max_unanswered: u64,
+  pub mode: FlowMode,
+}
+
+// The server side of session resumption; the state is set by the
+// session handshake.
+struct ChanSession {
+  store: Arc<SessionStore>,
+  state: Option<Arc<Session>>,
+}
+
 impl<MsgX> Chan<MsgX> {
+  // Taps this `Chan`, recording every frame sent and received from now
+  // on into the capture.
+  pub fn set_capture(&mut self, cap: Option<Arc<CaptureWriter>>) {
+    self.cap = cap.map(|cap| {
+      let stream = cap.next_stream();
+      (cap, stream)
+    });
+  }
+
+  // Accepts session handshakes (see `session`) on this server-side
+  // `Chan`; must be called before the first frame is received.
+  pub fn set_sessions(&mut self, store: Option<Arc<SessionStore>>) {
+    self.sess = store.map(|store| ChanSession{store, state: None});
+  }
+
+  pub fn ctx(&self) -> &ConnCtx {
+    &self.ctx
+  }
+
+  pub fn ctx_mut(&mut self) -> &mut ConnCtx {
+    &mut self.ctx
+  }
+
+  // Rate limits the queries handled by `reply` on this `Chan`.
+  pub fn set_rate_limit(&mut self, limit: Option<ConnLimiter>) {
+    self.limit = limit;
+  }
+
+  // Limits the time that `reply` lets a handler take; see `watchdog`.
+  // Fails, leaving no budget, if the stream cannot be cloned for the
+  // watchdog to answer on (e.g. out of file descriptors).
+  pub fn set_query_budget(&mut self, budget: Option<QueryBudget>) -> Result<(), IoError> {
+    self.budget = None;
+    if let Some(b) = budget {
+      let tx = self.tx.get_ref().try_clone_stream()?;
+      self.budget = Some((b, Arc::new(Mutex::new(tx))));
+    }
+    Ok(())
+  }
+
+  pub fn set_flow_window(&mut self, flow: Option<FlowWindow>) {
+    // A zero window would never open.
+    self.flow = flow.filter(|f| f.max_unanswered > 0);
+  }
+
+  // The number of queries sent whose replies have not been received;
+  // always zero on the replying side.
+  pub fn unanswered(&self) -> u64 {
+    self.tseq.saturating_sub(self.rseq)
+  }
+
+  // The seqs of the last received and the last sent frames.
+  pub fn seqs(&self) -> (u64, u64) {
+    (self.rseq, self.tseq)
+  }
+
+  pub fn is_broken(&self) -> bool {
+    self.broken
+  }
+
+  pub(crate) fn reset_seqs(&mut self, rseq: u64, tseq: u64) {
+    self.rseq = rseq;
+    self.tseq = tseq;
+  }
+}
+
+impl<MsgX: WireCodex> Chan<MsgX> {
   pub fn new(stream: TcpStream) -> Chan<MsgX> {
     //stream.set_read_timeout(Some(StdDuration::from_secs(2))).unwrap();
     //stream.set_write_timeout(Some(StdDuration::from_secs(2))).unwrap();
-    let rx_stm = stream.try_clone().unwrap();
+    Chan::from_stream(Box::new(stream))
+  }
+
+  pub fn from_stream(stream: Box<dyn ChanStream>) -> Chan<MsgX> {
+    register_tags_or_panic::<MsgX>();
+    let ctx = ConnCtx::new(stream.peer());
+    let rx_stm = stream.try_clone_stream().unwrap();
     let rx = BufReader::with_capacity(0x10000, rx_stm);
     let tx_stm = stream;
     let tx = BufWriter::with_capacity(0x10000, tx_stm);
     let rseq = 0;
     let tseq = 0;
     let rbuf = Vec::new();
-    //let tbuf = Vec::new();
-    let tbuf = String::new();
-    Chan{rx, tx, rseq, tseq, rbuf, tbuf, _mrk: PhantomData}
+    let tbuf = Vec::new();
+    let cap = None;
+    let sess = None;
+    let flow = None;
+    let limit = None;
+    let budget = None;
+    let stat = ChanMetrics::new();
+    let rqueue = VecDeque::new();
+    let broken = false;
+    Chan{rx, tx, rseq, tseq, rbuf, tbuf, cap, sess, flow, limit, budget, ctx, stat, rqueue, broken, _mrk: PhantomData}
+  }
+
+  pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Chan<MsgX>, IoError> {
+    let stream = TcpStream::connect(addr)?;
+    Ok(Chan::new(stream))
   }
-}
 
-impl<MsgX: MsgCodex> Chan<MsgX> {
   pub fn send(&mut self, item: &Msg<MsgX>) -> Result<u64, SendErr> {
-    let tseq = self.tseq + 1;
-    self.tseq = tseq;
-    self.tbuf.clear();
-    let tag = match &item {
-      &Msg::Top => *b"...",
-      &Msg::HUP => *b"HUP",
-      &Msg::OKQ => *b"OK?",
-      &Msg::OKR => *b"OK.",
-      /*&Msg::NTP(ref j) => {
-        write!(&mut self.tbuf, "{}", j)
-          .map_err(|_| SendErr::JsonWrite)?;
-        *b"NTP"
-      }*/
-      &Msg::H1Q(ref req) => {
-        let mut enc = JsonEncoder::new(&mut self.tbuf);
-        req.encode(&mut enc)
-          .map_err(|_| SendErr::JsonWrite)?;
-        *b"H1?"
-      }
-      &Msg::H1P(ref rep) => {
-        let mut enc = JsonEncoder::new(&mut self.tbuf);
-        rep.encode(&mut enc)
-          .map_err(|_| SendErr::JsonWrite)?;
-        *b"H1."
-      }
-      &Msg::JSO(ref j) => {
-        write!(&mut self.tbuf, "{}", j)
-          .map_err(|_| SendErr::JsonWrite)?;
-        *b"JSO"
-      }
-      &Msg::Ext(ref x) => {
-        match x.encode_wire(&mut self.tbuf) {
-          Err(_) => {
-            return Err(SendErr::Top);
+    self.send_with(item, &FrameMeta::default())
+  }
+
+  pub fn send_with(&mut self, item: &Msg<MsgX>, meta: &FrameMeta) -> Result<u64, SendErr> {
+    let res = self.send_frame(item, meta);
+    if let Err(ref e) = res {
+      self.stat.send_err(e);
+    }
+    res
+  }
+
+  fn send_frame(&mut self, item: &Msg<MsgX>, meta: &FrameMeta) -> Result<u64, SendErr> {
+    self.send_encoded(meta, |buf| encode_msg(item, buf))
+  }
+
+  // Sends `Msg::Bot` with error details (see `frame::bot_details`) as its
+  // payload, which `query` returns as `QueryErr::Bot`.
+  pub fn send_bot(&mut self, details: &str) -> Result<u64, SendErr> {
+    let res = self.send_encoded(&FrameMeta::default(), |buf| {
+      buf.extend_from_slice(details.as_bytes());
+      Ok(*b"!!!")
+    });
+    if let Err(ref e) = res {
+      self.stat.send_err(e);
+    }
+    res
+  }
+
+  fn send_encoded<E: FnOnce(&mut Vec<u8>) -> Result<[u8; 3], SendErr>>(&mut self, meta: &FrameMeta, encode: E) -> Result<u64, SendErr> {
+    if let Some(flow) = self.flow {
+      while self.unanswered() >= flow.max_unanswered {
+        match flow.mode {
+          FlowMode::WouldBlock => return Err(SendErr::WouldBlock),
+          FlowMode::Block => {
+            self.recv_blocked(meta.deadline)?;
           }
-          Ok(tag) => tag
         }
       }
-      &Msg::Bot => *b"!!!",
-      _ => return Err(SendErr::Top)
-    };
-    let mask_bytes = [tag[0], tag[1], tag[2], 0];
-    let mask = u32::from_le_bytes(mask_bytes);
-    // FIXME: configure max message size.
-    if self.tbuf.len() >= 16 * (u16::max_value() as usize) {
-      return Err(SendErr::Overflow);
-    }
-    let len = self.tbuf.len() as u32;
-    self.tx.write_u64::<LE>(tseq).map_err(|_| SendErr::IO)?;
-    self.tx.write_u32::<LE>(mask).map_err(|_| SendErr::IO)?;
-    self.tx.write_u32::<LE>(len).map_err(|_| SendErr::IO)?;
-    self.tx.write_all(self.tbuf.as_bytes()).map_err(|_| SendErr::IO)?;
-    self.tx.flush().map_err(|_| SendErr::IO)?;
+    }
+    let tseq = self.tseq + 1;
+    self.tbuf.clear();
+    // A value that fails to encode does not use up a seq, which would put
+    // the replies out of step with the queries.
+    let tag = (encode)(&mut self.tbuf)?;
+    self.tseq = tseq;
+    // The reply is cached before it is written, so that it can be
+    // replayed if the connection drops.
+    if let Some(&ChanSession{ref store, state: Some(ref state)}) = self.sess.as_ref() {
+      store.record(state, tseq, tag, &self.tbuf);
+    }
+    self.write_frame(tseq, tag, meta)?;
+    self.stat.sent(self.tbuf.len());
+    if let Some(&(ref cap, stream)) = self.cap.as_ref() {
+      cap.record(stream, CaptureDir::Send, tag, tseq, &self.tbuf);
+    }
     Ok(tseq)
   }
 
-  pub fn recv(&mut self) -> Result<(Msg<MsgX>, u64), RecvErr> {
-    let rseq = self.rx.read_u64::<LE>().map_err(|_| RecvErr::IO)?;
-    let mask = self.rx.read_u32::<LE>().map_err(|_| RecvErr::IO)?;
-    let mask_bytes = mask.to_le_bytes();
-    let tag = [mask_bytes[0], mask_bytes[1], mask_bytes[2]];
-    let len = self.rx.read_u32::<LE>().map_err(|_| RecvErr::IO)? as usize;
-    self.rbuf.clear();
-    self.rbuf.resize(len, 0);
-    self.rx.read_exact(&mut self.rbuf).map_err(|_| RecvErr::IO)?;
-    if self.rseq >= rseq {
-      return Err(RecvErr::Seq);
-    }
-    self.rseq = rseq;
-    let msg = match &tag {
-      b"..." => {
-        if len > 0 {
-          return Err(RecvErr::Trailing);
-        }
-        Msg::Top
-      }
-      b"HUP" => {
-        if len > 0 {
-          return Err(RecvErr::Trailing);
+  // Receives a reply into `rqueue` while the flow window is full, waiting
+  // until the deadline at most.
+  fn recv_blocked(&mut self, deadline: Option<Instant>) -> Result<(), SendErr> {
+    let timeout = match deadline {
+      None => None,
+      Some(d) => {
+        let now = Instant::now();
+        if d <= now {
+          return Err(SendErr::Deadline);
         }
-        Msg::HUP
+        Some(d - now)
       }
-      b"OK?" => {
-        if len > 0 {
-          return Err(RecvErr::Trailing);
-        }
-        Msg::OKQ
-      }
-      b"OK." => {
-        if len > 0 {
-          return Err(RecvErr::Trailing);
+    };
+    if timeout.is_some() {
+      self.rx.get_ref().set_read_timeout(timeout).map_err(|_| SendErr::IO)?;
+    }
+    let res = self.recv_wire();
+    if timeout.is_some() {
+      let _ = self.rx.get_ref().set_read_timeout(None);
+    }
+    match res {
+      Err(e) => {
+        if let Some(d) = deadline {
+          if d <= Instant::now() {
+            return Err(SendErr::Deadline);
+          }
         }
-        Msg::OKR
+        Err(SendErr::Recv(e))
       }
-      /*b"NTP" => {
-        let j = Json::from_reader(Cursor::new(&self.rbuf))
-          .map_err(|_| RecvErr::JsonBuild)?;
-        // TODO TODO
-        Msg::NTP(j)
-      }*/
-      b"H1?" => {
-        let j = Json::from_reader(Cursor::new(&self.rbuf))
-          .map_err(|_| RecvErr::JsonBuild)?;
-        let req = j.decode_into()
-          .map_err(|_| RecvErr::JsonDecode)?;
-        Msg::H1Q(req)
+      Ok(hdr) => {
+        let buf = self.rbuf.clone();
+        self.rqueue.push_back((hdr, buf));
+        Ok(())
       }
-      b"H1." => {
-        let j = Json::from_reader(Cursor::new(&self.rbuf))
-          .map_err(|_| RecvErr::JsonBuild)?;
-        let rep = j.decode_into()
-          .map_err(|_| RecvErr::JsonDecode)?;
-        Msg::H1P(rep)
+    }
+  }
+
+  // Writes a frame with the payload in `tbuf`.
+  fn write_frame(&mut self, seq: u64, tag: [u8; 3], meta: &FrameMeta) -> Result<(), SendErr> {
+    if self.broken {
+      return Err(SendErr::Broken);
+    }
+    let res = encode_frame_hdr(&mut self.tx, seq, tag, meta, self.tbuf.len())
+      .and_then(|_| self.tx.write_all(&self.tbuf).map_err(|_| SendErr::IO))
+      .and_then(|_| self.tx.flush().map_err(|_| SendErr::IO));
+    if res.is_err() {
+      // Part of the frame may have been written.
+      self.broken = true;
+    }
+    res
+  }
+
+  // Sends a frame with an already encoded payload and an explicit seq.
+  pub(crate) fn send_raw(&mut self, seq: u64, tag: [u8; 3], payload: &[u8]) -> Result<(), SendErr> {
+    self.tbuf.clear();
+    self.tbuf.extend_from_slice(payload);
+    self.write_frame(seq, tag, &FrameMeta::default())?;
+    self.stat.sent(payload.len());
+    if let Some(&(ref cap, stream)) = self.cap.as_ref() {
+      cap.record(stream, CaptureDir::Send, tag, seq, payload);
+    }
+    Ok(())
+  }
+
+  pub fn recv(&mut self) -> Result<(Msg<MsgX>, u64), RecvErr> {
+    let (msg, hdr) = self.recv_with()?;
+    Ok((msg, hdr.seq))
+  }
+
+  pub fn recv_with(&mut self) -> Result<(Msg<MsgX>, FrameHdr), RecvErr> {
+    let hdr = self.recv_raw()?.0;
+    let msg = self.decode_rbuf(hdr.tag)?;
+    Ok((msg, hdr))
+  }
+
+  fn decode_rbuf(&mut self, tag: [u8; 3]) -> Result<Msg<MsgX>, RecvErr> {
+    let res = decode_msg(tag, &self.rbuf);
+    if let Err(ref e) = res {
+      self.stat.recv_err(e);
+    }
+    res
+  }
+
+  // Receives a frame without decoding the payload, which is returned as
+  // a view of the receive buffer, e.g. to decode with `WireDecodeRef`.
+  pub fn recv_raw(&mut self) -> Result<(FrameHdr, &[u8]), RecvErr> {
+    let hdr = match self.rqueue.pop_front() {
+      Some((hdr, buf)) => {
+        self.rbuf = buf;
+        hdr
       }
-      b"JSO" => {
-        let j = Json::from_reader(Cursor::new(&self.rbuf))
-          .map_err(|_| RecvErr::JsonBuild)?;
-        // TODO TODO
-        Msg::JSO(j)
+      None => self.recv_wire()?
+    };
+    Ok((hdr, &self.rbuf[..]))
+  }
+
+  // Receives a frame into `rbuf`, bypassing `rqueue`.
+  fn recv_wire(&mut self) -> Result<FrameHdr, RecvErr> {
+    let hdr = self.recv_frame()?.0;
+    if self.rseq >= hdr.seq {
+      self.stat.recv_err(&RecvErr::Seq);
+      return Err(RecvErr::Seq);
+    }
+    self.rseq = hdr.seq;
+    Ok(hdr)
+  }
+
+  // Like `recv_raw`, but without checking or advancing the seq.
+  pub(crate) fn recv_frame(&mut self) -> Result<(FrameHdr, &[u8]), RecvErr> {
+    match self.read_frame() {
+      Err(e) => {
+        self.stat.recv_err(&e);
+        Err(e)
       }
-      b"!!!" => {
-        // TODO: allow an error message.
-        /*if len > 0 {
-          return Err(RecvErr::Trailing);
-        }*/
-        Msg::Bot
+      Ok(hdr) => Ok((hdr, &self.rbuf[..]))
+    }
+  }
+
+  fn read_frame(&mut self) -> Result<FrameHdr, RecvErr> {
+    if self.broken {
+      return Err(RecvErr::Broken);
+    }
+    let res = self.read_frame_parts();
+    if res.is_err() {
+      // Never resume reading from the middle of a frame.
+      self.broken = true;
+    }
+    res
+  }
+
+  fn read_frame_parts(&mut self) -> Result<FrameHdr, RecvErr> {
+    let mut fixed_buf = [0; FRAME_HDR_LEN];
+    self.rx.read_exact(&mut fixed_buf).map_err(|_| RecvErr::IO)?;
+    let fixed = decode_frame_fixed(&fixed_buf)?;
+    let rseq = fixed.seq;
+    let tag = fixed.tag;
+    let mut ext_buf = [0; FRAME_EXT_MAX_LEN];
+    let ext_buf = &mut ext_buf[ .. frame_ext_len(fixed.flags)];
+    self.rx.read_exact(ext_buf).map_err(|_| RecvErr::IO)?;
+    let meta = decode_frame_ext(fixed.flags, ext_buf)?;
+    self.rbuf.clear();
+    self.rbuf.resize(fixed.len, 0);
+    self.rx.read_exact(&mut self.rbuf).map_err(|_| RecvErr::IO)?;
+    self.stat.recv(self.rbuf.len());
+    if let Some(&(ref cap, stream)) = self.cap.as_ref() {
+      cap.record(stream, CaptureDir::Recv, tag, rseq, &self.rbuf);
+    }
+    Ok(FrameHdr{seq: rseq, tag, meta})
+  }
+
+  pub fn query(&mut self, query: &Msg<MsgX>) -> Result<Msg<MsgX>, QueryErr> {
+    self.query_with(query, &FrameMeta::inherit())
+  }
+
+  pub fn query_timeout(&mut self, query: &Msg<MsgX>, timeout: StdDuration) -> Result<Msg<MsgX>, QueryErr> {
+    let mut meta = FrameMeta::inherit();
+    meta.deadline = min_deadline(meta.deadline, Some(Instant::now() + timeout));
+    self.query_with(query, &meta)
+  }
+
+  pub fn query_with(&mut self, query: &Msg<MsgX>, meta: &FrameMeta) -> Result<Msg<MsgX>, QueryErr> {
+    let timeout = match meta.deadline {
+      None => None,
+      Some(d) => {
+        let now = Instant::now();
+        if d <= now {
+          return Err(QueryErr::Deadline);
+        }
+        Some(d - now)
       }
-      _ => {
-        match MsgX::decode_wire(tag, &self.rbuf) {
-          Err(_) => {
-            return Err(RecvErr::Top);
+    };
+    let tseq = match self.send_with(query, meta) {
+      Err(SendErr::Deadline) => return Err(QueryErr::Deadline),
+      Err(e) => return Err(e.into()),
+      Ok(tseq) => tseq
+    };
+    if timeout.is_some() {
+      self.rx.get_ref().set_read_timeout(timeout)
+        .map_err(|_| QueryErr::Recv(RecvErr::IO))?;
+    }
+    let res = self.recv_raw().map(|(hdr, _)| hdr);
+    if timeout.is_some() {
+      let _ = self.rx.get_ref().set_read_timeout(None);
+    }
+    let hdr = match res {
+      Err(e) => {
+        if let Some(d) = meta.deadline {
+          if d <= Instant::now() {
+            // The reply may still arrive; it must not be taken for the
+            // reply to the next query.
+            self.broken = true;
+            return Err(QueryErr::Deadline);
           }
-          Ok(x) => Msg::Ext(x)
         }
+        return Err(e.into());
       }
+      Ok(x) => x
     };
-    Ok((msg, rseq))
+    if tseq != hdr.seq {
+      self.broken = true;
+      return Err(QueryErr::Seq);
+    }
+    if &hdr.tag == b"!!!" && !self.rbuf.is_empty() {
+      return Err(QueryErr::Bot(String::from_utf8_lossy(&self.rbuf).into_owned()));
+    }
+    Ok(self.decode_rbuf(hdr.tag)?)
   }
 
-  pub fn query(&mut self, query: &Msg<MsgX>) -> Result<Msg<MsgX>, QueryErr> {
-    let tseq = self.send(query)?;
-    let (reply, rseq) = self.recv()?;
-    if tseq != rseq {
-      return Err(QueryErr::Seq);
+  // Receives the next query in a session, handling the handshake and
+  // replaying cached replies; returns `None` if the frame was answered.
+  // A new query is marked as running until the returned `SessionRun` is
+  // dropped.
+  fn recv_session(&mut self) -> Result<Option<(Msg<MsgX>, FrameHdr, Option<SessionRun>)>, ReplyErr> {
+    let hdr = self.recv_frame()?.0;
+    let resumed = self.sess.as_ref().map(|s| s.state.is_some()).unwrap_or(false);
+    if hdr.tag == SESSION_RESUME_TAG && hdr.seq == 0 && !resumed && self.rseq == 0 {
+      let (id, last_ack) = decode_session_hdr(&self.rbuf)?;
+      let (id, last_seq, state) = self.sess.as_ref().unwrap().store.resume(id, last_ack);
+      self.sess.as_mut().unwrap().state = Some(state);
+      self.rseq = last_ack;
+      self.tseq = last_ack;
+      self.send_raw(0, SESSION_ACCEPT_TAG, &encode_session_hdr(id, last_seq))?;
+      return Ok(None);
+    }
+    if self.rseq >= hdr.seq {
+      return Err(RecvErr::Seq.into());
+    }
+    self.rseq = hdr.seq;
+    let msg = self.decode_rbuf(hdr.tag)?;
+    let state = match self.sess.as_ref().and_then(|s| s.state.clone()) {
+      None => return Ok(Some((msg, hdr, None))),
+      Some(state) => state
+    };
+    match SessionRun::begin(state.clone(), hdr.seq) {
+      Some(run) => Ok(Some((msg, hdr, Some(run)))),
+      None => {
+        // Already started, possibly on another connection: replay the
+        // reply once the handler is done, if still cached.
+        let (tag, payload) = state.wait_cached(hdr.seq).ok_or(ReplyErr::Seq)?;
+        self.send_raw(hdr.seq, tag, &payload)?;
+        self.tseq = hdr.seq;
+        Ok(None)
+      }
     }
-    Ok(reply)
   }
 
-  /*pub fn query_timeout(&mut self, query: &Msg<MsgX>, timeout: StdDuration) -> Result<Msg<MsgX>, QueryErr> {
-    let tseq = self.send(query)?;
-    let (reply, rseq) = self.recv_timeout(timeout)?;
-    if tseq != rseq {
-      return Err(QueryErr::Seq);
+  fn side_reply(&self, tx: &Arc<Mutex<Box<dyn ChanStream>>>) -> SideReply {
+    SideReply{
+      tx: tx.clone(),
+      cap: self.cap.clone(),
+      sess: self.sess.as_ref().and_then(|s| s.state.as_ref().map(|state| (s.store.clone(), state.clone()))),
+      frames_sent: self.stat.frames_sent.clone(),
+      bytes_sent: self.stat.bytes_sent.clone(),
     }
-    Ok(reply)
-  }*/
+  }
 
-  pub fn reply<P: Fn(&Msg<MsgX>) -> Msg<MsgX>>(&mut self, proc_: P) -> Result<bool, ReplyErr> {
-    let (query, rseq) = self.recv()?;
-    let reply: Msg<MsgX> = (proc_)(&query);
-    let tseq = self.send(&reply)?;
+  // The querying peer has already given up, so the handler is skipped;
+  // still answer, so that a pipelining peer waiting on its flow window is
+  // not left hanging.
+  fn reply_expired(&mut self, rseq: u64, tag: [u8; 3]) -> Result<bool, ReplyErr> {
+    let tseq = self.send_bot(&bot_details("deadline", tag, Vec::new()))?;
     if rseq != tseq {
       return Err(ReplyErr::Seq);
     }
     Ok(false)
   }
 
-  pub fn replying<P: Fn(&Msg<MsgX>) -> Msg<MsgX>>(&mut self, proc_: P) {
-    // FIXME: pre, post callbacks.
+  // Handles one query; returns true if the connection should be closed.
+  // The handler returns either a `Msg` to reply with or an `Outcome`.
+  pub fn reply<R: Into<Outcome<MsgX>>, P: Fn(&Msg<MsgX>) -> R>(&mut self, proc_: P) -> Result<bool, ReplyErr> {
+    self.reply_ctx(|_, query| (proc_)(query))
+  }
+
+  // Like `reply`, but the handler also gets the connection context.
+  pub fn reply_ctx<R: Into<Outcome<MsgX>>, P: FnMut(&mut ConnCtx, &Msg<MsgX>) -> R>(&mut self, mut proc_: P) -> Result<bool, ReplyErr> {
+    let (query, hdr, _run) = match self.sess {
+      None => {
+        let (query, hdr) = self.recv_with()?;
+        (query, hdr, None)
+      }
+      Some(_) => {
+        match self.recv_session()? {
+          None => return Ok(false),
+          Some(x) => x
+        }
+      }
+    };
+    let rseq = hdr.seq;
+    let meta = hdr.meta;
+    if let &Msg::HUP = &query {
+      return Ok(true);
+    }
+    if expired(meta.deadline) {
+      return self.reply_expired(rseq, hdr.tag);
+    }
+    if let Some(limit) = self.limit.as_mut() {
+      if let Some(key) = self.ctx.take_rate_key() {
+        limit.set_peer(key);
+      }
+      match limit.admit(meta.deadline) {
+        Some(ThrottleAction::Reject) => {
+          let tseq = self.send_bot(&bot_details("rate_limited", hdr.tag, Vec::new()))?;
+          if rseq != tseq {
+            return Err(ReplyErr::Seq);
+          }
+          return Ok(false);
+        }
+        Some(ThrottleAction::Disconnect) => {
+          return Ok(true);
+        }
+        Some(ThrottleAction::Delay) => {
+          if expired(meta.deadline) {
+            return self.reply_expired(rseq, hdr.tag);
+          }
+        }
+        None => {}
+      }
+    }
+    let t0 = Instant::now();
+    let start = SystemTime::now();
+    self.ctx.set_seq(rseq);
+    self.ctx.set_tag(hdr.tag);
+    let guard = self.budget.as_ref().map(|&(ref budget, ref tx)| {
+      budget.start(self.side_reply(tx), self.ctx.id(), rseq, hdr.tag)
+    });
+    let deadline = min_deadline(meta.deadline, guard.as_ref().map(|g| g.deadline()));
+    let ctx = &mut self.ctx;
+    // A panicking handler may leave the connection context or its own
+    // state inconsistent; that is its own business, so the connection
+    // carries on.
+    let res = with_deadline(deadline, || {
+      with_trace(meta.trace, || {
+        catch_unwind(AssertUnwindSafe(|| -> Outcome<MsgX> { (proc_)(ctx, &query).into() }))
+      })
+    });
+    let outcome = match res {
+      Ok(outcome) => Ok(outcome.resolve()),
+      Err(payload) => Err(panic_message(&*payload)),
+    };
+    if let Some(ctx) = meta.trace {
+      export_span(&SpanRecord{
+        ctx,
+        name: String::from_utf8_lossy(&hdr.tag).into_owned(),
+        start,
+        duration: t0.elapsed(),
+      });
+    }
+    if let Some(guard) = guard {
+      if !guard.finish() {
+        // The watchdog has replied with a timeout error.
+        return Ok(true);
+      }
+    }
+    let tag = String::from_utf8_lossy(&hdr.tag);
+    let (tseq, halt) = match outcome {
+      Ok(Outcome::Reply(reply)) => (self.send(&reply)?, false),
+      Ok(Outcome::ReplyClose(reply)) => (self.send(&reply)?, true),
+      Ok(Outcome::Close) => return Ok(true),
+      Ok(Outcome::Defer(_)) => panic!("bug: unresolved deferred outcome"),
+      Err(msg) => {
+        self.stat.panics.inc();
+        log(LogLevel::Error, "handler panicked", &[
+            ("conn", &self.ctx.id()),
+            ("seq", &rseq),
+            ("tag", &tag),
+            ("panic", &msg),
+        ]);
+        let details = bot_details("panic", hdr.tag, vec![("message", Json::String(msg))]);
+        (self.send_bot(&details)?, false)
+      }
+    };
+    if rseq != tseq {
+      return Err(ReplyErr::Seq);
+    }
+    self.stat.query_latency(hdr.tag).observe_duration(t0.elapsed());
+    Ok(halt)
+  }
+
+  pub fn replying<R: Into<Outcome<MsgX>>, P: Fn(&Msg<MsgX>) -> R>(&mut self, proc_: P) {
+    self.replying_ctx(|_, query| (proc_)(query))
+  }
+
+  pub fn replying_ctx<R: Into<Outcome<MsgX>>, P: FnMut(&mut ConnCtx, &Msg<MsgX>) -> R>(&mut self, proc_: P) {
+    self.serve(&mut FnService(proc_))
+  }
+
+  // Replies to queries until the connection ends, calling the service
+  // lifecycle hooks.
+  pub fn serve<S: Service<MsgX>>(&mut self, svc: &mut S) {
+    if !svc.on_connect(&mut self.ctx) {
+      svc.on_disconnect(&mut self.ctx);
+      return;
+    }
     loop {
-      match self.reply(&proc_) {
-        Err(_) => {
+      match self.reply_ctx(|ctx, query| svc.on_query(ctx, query)) {
+        Err(ReplyErr::Recv(RecvErr::IO)) => {
+          break;
+        }
+        Err(e) => {
+          log(LogLevel::Warn, "connection error", &[
+              ("conn", &self.ctx.id()),
+              ("seq", &self.ctx.seq()),
+              ("error", &format!("{:?}", e)),
+          ]);
+          svc.on_error(&mut self.ctx, &e);
           break;
         }
         Ok(halt) => if halt {
@@ -285,31 +948,107 @@ impl<MsgX: MsgCodex> Chan<MsgX> {
         }
       }
     }
+    svc.on_disconnect(&mut self.ctx);
   }
 }
 
 pub struct SpawnPool<MsgX=()> {
   bind: TcpListener,
+  cap:  Option<Arc<CaptureWriter>>,
+  sess: Option<Arc<SessionStore>>,
+  limit: Option<Arc<RateLimiter>>,
+  mw:   MiddlewareChain<MsgX>,
+  budget: Option<QueryBudget>,
   _mrk: PhantomData<fn (MsgX) -> MsgX>,
 }
 
-impl<MsgX> SpawnPool<MsgX> {
+impl<MsgX: WireCodex> SpawnPool<MsgX> {
   pub fn new(bind: TcpListener) -> SpawnPool<MsgX> {
-    SpawnPool{bind, _mrk: PhantomData}
+    register_tags_or_panic::<MsgX>();
+    SpawnPool{bind, cap: None, sess: None, limit: None, mw: MiddlewareChain::new(), budget: None, _mrk: PhantomData}
+  }
+
+  // Taps every connection accepted from now on.
+  pub fn set_capture(&mut self, cap: Option<Arc<CaptureWriter>>) {
+    self.cap = cap;
+  }
+
+  // Accepts session handshakes on every connection accepted from now on.
+  pub fn set_sessions(&mut self, store: Option<Arc<SessionStore>>) {
+    self.sess = store;
+  }
+
+  // Rate limits every connection accepted from now on, with per-peer
+  // limits keyed by the peer IP address until a handler rekeys them with
+  // `ConnCtx::set_rate_key`.
+  pub fn set_rate_limit(&mut self, limiter: Option<Arc<RateLimiter>>) {
+    self.limit = limiter;
+  }
+
+  // Limits the time each query may take on connections accepted from now
+  // on; overrunning queries get a timeout error and their connection is
+  // closed.
+  pub fn set_query_budget(&mut self, budget: Option<StdDuration>) {
+    self.budget = budget.map(QueryBudget::new);
+  }
+
+  pub fn query_budget(&self) -> Option<&QueryBudget> {
+    self.budget.as_ref()
+  }
+
+  // Wraps the handlers of connections accepted from now on; middleware
+  // runs in the order it was pushed, the first being the outermost.
+  pub fn push_middleware(&mut self, mw: Arc<dyn Middleware<MsgX>>) {
+    self.mw.push(mw);
   }
 }
 
-impl<MsgX: 'static + MsgCodex> SpawnPool<MsgX> {
+impl<MsgX: 'static + WireCodex> SpawnPool<MsgX> {
   pub fn replying(&self, proc_: Arc<dyn 'static + Send + Sync + Fn(&Msg<MsgX>) -> Msg<MsgX>>) {
+    self.replying_ctx(Arc::new(move |_: &mut ConnCtx, query: &Msg<MsgX>| (proc_)(query)))
+  }
+
+  pub fn replying_ctx(&self, proc_: Arc<dyn 'static + Send + Sync + Fn(&mut ConnCtx, &Msg<MsgX>) -> Msg<MsgX>>) {
+    self.serving(Arc::new(move |_: &ConnCtx| {
+      let proc_ = proc_.clone();
+      FnService(move |ctx: &mut ConnCtx, query: &Msg<MsgX>| (proc_)(ctx, query))
+    }))
+  }
+
+  // Serves each connection with its own `Service` from the factory.
+  pub fn serving<F: 'static + ServiceFactory<MsgX>>(&self, factory: Arc<F>) {
+    let mw = Arc::new(self.mw.clone());
+    let active = metrics().gauge("spawn_pool_active_connections", "Connections being served.", &[]);
+    let accepted = metrics().counter("spawn_pool_connections_total", "Connections accepted.", &[]);
     loop {
       match self.bind.accept() {
-        Err(_) => {
+        Err(e) => {
+          log(LogLevel::Warn, "accept failed", &[("error", &e)]);
         }
-        Ok((stream, _addr)) => {
-          let proc_ = proc_.clone();
+        Ok((stream, addr)) => {
+          let factory = factory.clone();
+          let mw = mw.clone();
+          let cap = self.cap.clone();
+          let sess = self.sess.clone();
+          let limit = self.limit.clone()
+            .map(|l| ConnLimiter::new(l, Some(PeerKey::Ip(addr.ip()))));
+          let budget = self.budget.clone();
+          let active = active.clone();
+          accepted.inc();
           let _ = spawn(move || {
+            let _active = active.track();
             let mut chan = Chan::<MsgX>::new(stream);
-            chan.replying(&*proc_);
+            chan.set_capture(cap);
+            chan.set_sessions(sess);
+            chan.set_rate_limit(limit);
+            if let Err(e) = chan.set_query_budget(budget) {
+              log(LogLevel::Warn, "serving without a query budget", &[
+                  ("conn", &chan.ctx().id()),
+                  ("error", &e),
+              ]);
+            }
+            let mut svc = Layered::new(mw, factory.new_service(chan.ctx()));
+            chan.serve(&mut svc);
           });
         }
       }
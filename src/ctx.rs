@@ -0,0 +1,115 @@
+use crate::ratelimit::{PeerKey};
+
+use std::any::{Any, TypeId};
+use std::collections::{HashMap};
+use std::net::{SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::time::{Instant, SystemTime};
+
+static CONN_ID_CTR: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Debug)]
+pub enum PeerAddr {
+  Tcp(SocketAddr),
+  // Without credentials: std does not expose SO_PEERCRED.
+  Unix,
+  Unknown,
+}
+
+// Per-connection state visible to handlers: who the peer is, when the
+// connection started, the seq and tag of the query being handled, and typed
+// connection-local storage (e.g. the authenticated user).
+pub struct ConnCtx {
+  id: u64,
+  peer: PeerAddr,
+  start: Instant,
+  start_time: SystemTime,
+  seq: u64,
+  tag: [u8; 3],
+  // A new per-peer rate limit key, not yet applied.
+  rate_key: Option<Option<PeerKey>>,
+  ext: HashMap<TypeId, Box<dyn Any + Send>>,
+}
+
+impl ConnCtx {
+  pub fn new(peer: PeerAddr) -> ConnCtx {
+    ConnCtx{
+      id: CONN_ID_CTR.fetch_add(1, AtomicOrdering::Relaxed),
+      peer,
+      start: Instant::now(),
+      start_time: SystemTime::now(),
+      seq: 0,
+      tag: *b"...",
+      rate_key: None,
+      ext: HashMap::new(),
+    }
+  }
+
+  // Unique within the process.
+  pub fn id(&self) -> u64 {
+    self.id
+  }
+
+  pub fn peer(&self) -> &PeerAddr {
+    &self.peer
+  }
+
+  pub fn set_peer(&mut self, peer: PeerAddr) {
+    self.peer = peer;
+  }
+
+  pub fn started_at(&self) -> Instant {
+    self.start
+  }
+
+  pub fn start_time(&self) -> SystemTime {
+    self.start_time
+  }
+
+  // The seq of the query currently being handled.
+  pub fn seq(&self) -> u64 {
+    self.seq
+  }
+
+  pub(crate) fn set_seq(&mut self, seq: u64) {
+    self.seq = seq;
+  }
+
+  // The wire tag of the query currently being handled.
+  pub fn tag(&self) -> [u8; 3] {
+    self.tag
+  }
+
+  pub(crate) fn set_tag(&mut self, tag: [u8; 3]) {
+    self.tag = tag;
+  }
+
+  // Rekeys the per-peer rate limit of the connection (see `ratelimit`)
+  // from the next query on, e.g. to `PeerKey::Cred` once the peer has
+  // authenticated; `None` leaves only the per-connection limit.
+  pub fn set_rate_key(&mut self, key: Option<PeerKey>) {
+    self.rate_key = Some(key);
+  }
+
+  pub(crate) fn take_rate_key(&mut self) -> Option<Option<PeerKey>> {
+    self.rate_key.take()
+  }
+
+  pub fn insert<T: Any + Send>(&mut self, val: T) -> Option<T> {
+    self.ext.insert(TypeId::of::<T>(), Box::new(val))
+      .and_then(|prev| prev.downcast().ok().map(|prev| *prev))
+  }
+
+  pub fn get<T: Any + Send>(&self) -> Option<&T> {
+    self.ext.get(&TypeId::of::<T>()).and_then(|v| v.downcast_ref())
+  }
+
+  pub fn get_mut<T: Any + Send>(&mut self) -> Option<&mut T> {
+    self.ext.get_mut(&TypeId::of::<T>()).and_then(|v| v.downcast_mut())
+  }
+
+  pub fn remove<T: Any + Send>(&mut self) -> Option<T> {
+    self.ext.remove(&TypeId::of::<T>())
+      .and_then(|prev| prev.downcast().ok().map(|prev| *prev))
+  }
+}
@@ -0,0 +1,49 @@
+use std::cell::{Cell};
+use std::time::{Duration as StdDuration, Instant};
+
+thread_local! {
+  static TL_DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+}
+
+// The deadline of the query currently being handled on this thread,
+// if the querying peer sent one.
+pub fn deadline() -> Option<Instant> {
+  TL_DEADLINE.with(|d| d.get())
+}
+
+// The time left before the current deadline; `Some(0)` once the
+// deadline has passed.
+pub fn remaining() -> Option<StdDuration> {
+  deadline().map(|d| d.saturating_duration_since(Instant::now()))
+}
+
+pub fn expired() -> bool {
+  match deadline() {
+    None => false,
+    Some(d) => d <= Instant::now()
+  }
+}
+
+// Runs `f` with the thread-local deadline set to `deadline`, restoring
+// the previous deadline afterwards. Nested `Chan::query` calls made
+// within `f` inherit the deadline.
+pub fn with_deadline<R, F: FnOnce() -> R>(deadline: Option<Instant>, f: F) -> R {
+  struct Restore(Option<Instant>);
+
+  impl Drop for Restore {
+    fn drop(&mut self) {
+      TL_DEADLINE.with(|d| d.set(self.0));
+    }
+  }
+
+  let prev = TL_DEADLINE.with(|d| d.replace(deadline));
+  let _restore = Restore(prev);
+  f()
+}
+
+pub fn min_deadline(lhs: Option<Instant>, rhs: Option<Instant>) -> Option<Instant> {
+  match (lhs, rhs) {
+    (None, d) | (d, None) => d,
+    (Some(l), Some(r)) => Some(l.min(r))
+  }
+}
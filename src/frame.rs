@@ -0,0 +1,323 @@
+use crate::chan::{SendErr, RecvErr};
+use crate::deadline::{deadline};
+use crate::msg::*;
+use crate::tag::{is_reserved_tag};
+use crate::trace::{self, TraceCtx};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian as LE};
+use rustc_serialize::{Encodable};
+use rustc_serialize::json::{Json, JsonEncoder};
+
+use std::collections::{BTreeMap};
+use std::fmt::{Result as FmtResult, Write as FmtWrite};
+use std::io::{Write, Cursor};
+use std::time::{Duration as StdDuration, Instant};
+
+// A frame is laid out as:
+//
+//     seq: u64, tag: [u8; 3], flags: u8, len: u32,
+//     ext: [u8; frame_ext_len(flags)], payload: [u8; len]
+//
+// with all integers little-endian.
+pub const FRAME_HDR_LEN: usize = 16;
+pub const FRAME_EXT_MAX_LEN: usize = 40;
+// FIXME: configure max message size.
+pub const FRAME_MAX_LEN: usize = 16 * (u16::max_value() as usize);
+
+// Bits of the frame flags byte, which is the byte following the 3-byte
+// tag in the frame header. Each set flag means that the corresponding
+// optional field follows the fixed header, in order of the flag bits.
+pub const FRAME_FLAG_DEADLINE: u8 = 0x01;
+pub const FRAME_FLAG_TRACE: u8 = 0x02;
+
+pub const FRAME_FLAGS_KNOWN: u8 = FRAME_FLAG_DEADLINE | FRAME_FLAG_TRACE;
+
+// Optional per-frame metadata carried in the frame header.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FrameMeta {
+  // On the wire, the deadline is sent as the remaining time in
+  // microseconds, so that peers do not need synchronized clocks.
+  pub deadline: Option<Instant>,
+  // On the wire, the trace context is sent as the 128-bit trace id
+  // followed by the span id and the parent span id (zero for none).
+  pub trace: Option<TraceCtx>,
+}
+
+impl FrameMeta {
+  // Metadata inherited from the query currently being handled on this
+  // thread, if any.
+  pub fn inherit() -> FrameMeta {
+    FrameMeta{
+      deadline: deadline(),
+      trace: trace::current().map(|t| t.child()),
+    }
+  }
+
+  pub fn flags(&self) -> u8 {
+    let mut flags = 0;
+    if self.deadline.is_some() {
+      flags |= FRAME_FLAG_DEADLINE;
+    }
+    if self.trace.is_some() {
+      flags |= FRAME_FLAG_TRACE;
+    }
+    flags
+  }
+}
+
+// The length of the optional header fields that follow the fixed
+// 16-byte frame header, given the frame flags.
+pub fn frame_ext_len(flags: u8) -> usize {
+  let mut len = 0;
+  if flags & FRAME_FLAG_DEADLINE != 0 {
+    len += 8;
+  }
+  if flags & FRAME_FLAG_TRACE != 0 {
+    len += 32;
+  }
+  len
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FrameHdr {
+  pub seq: u64,
+  pub tag: [u8; 3],
+  pub meta: FrameMeta,
+}
+
+// Lets the JSON encoders write directly into a byte buffer.
+struct ByteWriter<'a>(&'a mut Vec<u8>);
+
+impl<'a> FmtWrite for ByteWriter<'a> {
+  fn write_str(&mut self, s: &str) -> FmtResult {
+    self.0.extend_from_slice(s.as_bytes());
+    Ok(())
+  }
+}
+
+pub fn encode_msg<MsgX: WireCodex>(item: &Msg<MsgX>, buf: &mut Vec<u8>) -> Result<[u8; 3], SendErr> {
+  let mut w = ByteWriter(buf);
+  Ok(match item {
+    &Msg::Top => *b"...",
+    &Msg::HUP => *b"HUP",
+    &Msg::OKQ => *b"OK?",
+    &Msg::OKR => *b"OK.",
+    /*&Msg::NTP(ref j) => {
+      write!(buf, "{}", j)
+        .map_err(|_| SendErr::JsonWrite)?;
+      *b"NTP"
+    }*/
+    &Msg::H1Q(ref req) => {
+      let mut enc = JsonEncoder::new(&mut w);
+      req.encode(&mut enc)
+        .map_err(|_| SendErr::JsonWrite)?;
+      *b"H1?"
+    }
+    &Msg::H1P(ref rep) => {
+      let mut enc = JsonEncoder::new(&mut w);
+      rep.encode(&mut enc)
+        .map_err(|_| SendErr::JsonWrite)?;
+      *b"H1."
+    }
+    &Msg::JSO(ref j) => {
+      write!(w, "{}", j)
+        .map_err(|_| SendErr::JsonWrite)?;
+      *b"JSO"
+    }
+    &Msg::Ext(ref x) => {
+      let buf = &mut *w.0;
+      buf.reserve(x.wire_size_hint());
+      let tag = x.encode_bytes(buf).map_err(SendErr::Codec)?;
+      // A reserved tag would be decoded as something else.
+      if is_reserved_tag(&tag) {
+        return Err(SendErr::Tag);
+      }
+      tag
+    }
+    &Msg::Bot => *b"!!!",
+    _ => return Err(SendErr::Top)
+  })
+}
+
+// The payload of a `!!!` frame sent in place of a handler's reply, e.g.
+// when the handler panicked or overran its time budget:
+//
+//     {"error": "<kind>", "tag": "<query tag>", ...}
+pub fn bot_details(error: &str, tag: [u8; 3], fields: Vec<(&str, Json)>) -> String {
+  let mut obj = BTreeMap::new();
+  obj.insert("error".to_string(), Json::String(error.to_string()));
+  obj.insert("tag".to_string(), Json::String(String::from_utf8_lossy(&tag).into_owned()));
+  for (k, v) in fields.into_iter() {
+    obj.insert(k.to_string(), v);
+  }
+  Json::Object(obj).to_string()
+}
+
+pub fn decode_msg<MsgX: WireCodex>(tag: [u8; 3], buf: &[u8]) -> Result<Msg<MsgX>, RecvErr> {
+  Ok(match &tag {
+    b"..." => {
+      if buf.len() > 0 {
+        return Err(RecvErr::Trailing);
+      }
+      Msg::Top
+    }
+    b"HUP" => {
+      if buf.len() > 0 {
+        return Err(RecvErr::Trailing);
+      }
+      Msg::HUP
+    }
+    b"OK?" => {
+      if buf.len() > 0 {
+        return Err(RecvErr::Trailing);
+      }
+      Msg::OKQ
+    }
+    b"OK." => {
+      if buf.len() > 0 {
+        return Err(RecvErr::Trailing);
+      }
+      Msg::OKR
+    }
+    /*b"NTP" => {
+      let j = Json::from_reader(Cursor::new(buf))
+        .map_err(|_| RecvErr::JsonBuild)?;
+      // TODO TODO
+      Msg::NTP(j)
+    }*/
+    b"H1?" => {
+      let j = Json::from_reader(Cursor::new(buf))
+        .map_err(|_| RecvErr::JsonBuild)?;
+      let req = j.decode_into()
+        .map_err(|_| RecvErr::JsonDecode)?;
+      Msg::H1Q(req)
+    }
+    b"H1." => {
+      let j = Json::from_reader(Cursor::new(buf))
+        .map_err(|_| RecvErr::JsonBuild)?;
+      let rep = j.decode_into()
+        .map_err(|_| RecvErr::JsonDecode)?;
+      Msg::H1P(rep)
+    }
+    b"JSO" => {
+      let j = Json::from_reader(Cursor::new(buf))
+        .map_err(|_| RecvErr::JsonBuild)?;
+      // TODO TODO
+      Msg::JSO(j)
+    }
+    b"!!!" => {
+      // The payload, if any, is error details (see `bot_details`), which
+      // `Chan::query` returns as `QueryErr::Bot`.
+      Msg::Bot
+    }
+    _ => {
+      let x = MsgX::decode_bytes(tag, buf).map_err(RecvErr::Codec)?;
+      Msg::Ext(x)
+    }
+  })
+}
+
+// The fixed 16-byte part of a frame header.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameFixed {
+  pub seq: u64,
+  pub tag: [u8; 3],
+  pub flags: u8,
+  pub len: usize,
+}
+
+// Validates the flags and the payload length, so that the caller may
+// safely allocate `frame_ext_len(flags)` and `len` bytes.
+pub fn decode_frame_fixed(buf: &[u8; FRAME_HDR_LEN]) -> Result<FrameFixed, RecvErr> {
+  let mut r = Cursor::new(&buf[..]);
+  let seq = r.read_u64::<LE>().map_err(|_| RecvErr::Truncated)?;
+  let mask = r.read_u32::<LE>().map_err(|_| RecvErr::Truncated)?;
+  let len = r.read_u32::<LE>().map_err(|_| RecvErr::Truncated)? as usize;
+  let mask_bytes = mask.to_le_bytes();
+  let tag = [mask_bytes[0], mask_bytes[1], mask_bytes[2]];
+  let flags = mask_bytes[3];
+  if flags & !FRAME_FLAGS_KNOWN != 0 {
+    return Err(RecvErr::Flags);
+  }
+  if len >= FRAME_MAX_LEN {
+    return Err(RecvErr::Overflow);
+  }
+  Ok(FrameFixed{seq, tag, flags, len})
+}
+
+pub fn decode_frame_ext(flags: u8, buf: &[u8]) -> Result<FrameMeta, RecvErr> {
+  if buf.len() != frame_ext_len(flags) {
+    return Err(RecvErr::Truncated);
+  }
+  let mut r = Cursor::new(buf);
+  let mut meta = FrameMeta::default();
+  if flags & FRAME_FLAG_DEADLINE != 0 {
+    let rem_us = r.read_u64::<LE>().map_err(|_| RecvErr::Truncated)?;
+    let now = Instant::now();
+    meta.deadline = Some(now.checked_add(StdDuration::from_micros(rem_us))
+                        .unwrap_or(now + StdDuration::from_secs(u32::max_value() as u64)));
+  }
+  if flags & FRAME_FLAG_TRACE != 0 {
+    let trace_lo = r.read_u64::<LE>().map_err(|_| RecvErr::Truncated)?;
+    let trace_hi = r.read_u64::<LE>().map_err(|_| RecvErr::Truncated)?;
+    let span_id = r.read_u64::<LE>().map_err(|_| RecvErr::Truncated)?;
+    let parent_span_id = r.read_u64::<LE>().map_err(|_| RecvErr::Truncated)?;
+    meta.trace = Some(TraceCtx{
+      trace_id: ((trace_hi as u128) << 64) | (trace_lo as u128),
+      span_id,
+      parent_span_id: if parent_span_id == 0 { None } else { Some(parent_span_id) },
+    });
+  }
+  Ok(meta)
+}
+
+pub fn encode_frame_hdr<W: Write>(w: &mut W, seq: u64, tag: [u8; 3], meta: &FrameMeta, len: usize) -> Result<(), SendErr> {
+  if len >= FRAME_MAX_LEN {
+    return Err(SendErr::Overflow);
+  }
+  let mask = u32::from_le_bytes([tag[0], tag[1], tag[2], meta.flags()]);
+  w.write_u64::<LE>(seq).map_err(|_| SendErr::IO)?;
+  w.write_u32::<LE>(mask).map_err(|_| SendErr::IO)?;
+  w.write_u32::<LE>(len as u32).map_err(|_| SendErr::IO)?;
+  if let Some(d) = meta.deadline {
+    let rem = d.saturating_duration_since(Instant::now());
+    let rem_us = rem.as_micros().min(u64::max_value() as u128) as u64;
+    w.write_u64::<LE>(rem_us).map_err(|_| SendErr::IO)?;
+  }
+  if let Some(t) = meta.trace {
+    w.write_u64::<LE>(t.trace_id as u64).map_err(|_| SendErr::IO)?;
+    w.write_u64::<LE>((t.trace_id >> 64) as u64).map_err(|_| SendErr::IO)?;
+    w.write_u64::<LE>(t.span_id).map_err(|_| SendErr::IO)?;
+    w.write_u64::<LE>(t.parent_span_id.unwrap_or(0)).map_err(|_| SendErr::IO)?;
+  }
+  Ok(())
+}
+
+// Encodes a whole frame (header and payload), appending it to `out`.
+pub fn encode_frame<MsgX: WireCodex>(item: &Msg<MsgX>, seq: u64, meta: &FrameMeta, out: &mut Vec<u8>) -> Result<(), SendErr> {
+  let mut buf = Vec::new();
+  let tag = encode_msg(item, &mut buf)?;
+  encode_frame_hdr(out, seq, tag, meta, buf.len())?;
+  out.extend_from_slice(&buf);
+  Ok(())
+}
+
+// Decodes a whole frame (header and payload) from the front of `buf`,
+// also returning the number of bytes consumed. This never allocates
+// more than a small multiple of `buf.len()`.
+pub fn decode_frame<MsgX: WireCodex>(buf: &[u8]) -> Result<(Msg<MsgX>, FrameHdr, usize), RecvErr> {
+  if buf.len() < FRAME_HDR_LEN {
+    return Err(RecvErr::Truncated);
+  }
+  let mut fixed_buf = [0; FRAME_HDR_LEN];
+  fixed_buf.copy_from_slice(&buf[ .. FRAME_HDR_LEN]);
+  let fixed = decode_frame_fixed(&fixed_buf)?;
+  let ext_end = FRAME_HDR_LEN + frame_ext_len(fixed.flags);
+  let end = ext_end + fixed.len;
+  if buf.len() < end {
+    return Err(RecvErr::Truncated);
+  }
+  let meta = decode_frame_ext(fixed.flags, &buf[FRAME_HDR_LEN .. ext_end])?;
+  let msg = decode_msg(fixed.tag, &buf[ext_end .. end])?;
+  Ok((msg, FrameHdr{seq: fixed.seq, tag: fixed.tag, meta}, end))
+}
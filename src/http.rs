@@ -1,3 +1,5 @@
+use crate::logging::{LogLevel, log, log_enabled};
+
 pub use http1::{Mime, Charset as HttpCharset, Encoding as HttpEncoding, Status as HttpStatus};
 use rustc_serialize::base64;
 use rustc_serialize::json::{Json};
@@ -300,11 +302,15 @@ impl HttpResponse {
     match self.payload.as_ref() {
       None => {}
       Some(&HttpPayload::Utf8(mime, _encoding, ref s)) => {
-        //println!("DEBUG:  service_base: HttpResponse::to_raw: mime={:?}", mime);
+        if log_enabled(LogLevel::Trace) {
+          log(LogLevel::Trace, "HttpResponse::to_raw", &[("mime", &format!("{:?}", mime))]);
+        }
         let buf: Box<[u8]> = s.clone().into_bytes().into();
         rep.push_header(http1::HeaderName::ContentLength, format!("{}", buf.len()));
         if let Some(m) = mime.and_then(|m| m.to_str()) {
-          //println!("DEBUG:  service_base: HttpResponse::to_raw:   to str={:?}", m);
+          if log_enabled(LogLevel::Trace) {
+            log(LogLevel::Trace, "HttpResponse::to_raw: content type", &[("mime", &m)]);
+          }
           rep.push_header(http1::HeaderName::ContentType, format!("{}; charset=utf-8", m));
         }
         rep.payload = Some(buf);
@@ -0,0 +1,462 @@
+use crate::chan::*;
+use crate::msg::*;
+use crate::schema::{Schema, SchemaErr};
+
+use rustc_serialize::{Decodable, Encodable};
+use rustc_serialize::json::{Json, JsonEncoder};
+
+use std::collections::{BTreeMap};
+use std::io::{Cursor};
+use std::sync::{Arc};
+
+// A JSON-RPC 2.0 layer over `Msg::JSO`: each query carries a request
+// object (or a batch array of them), and each reply carries the
+// corresponding response object (or array).
+//
+// Since every query on a `Chan` gets a reply, a query consisting only of
+// notifications is answered with `Msg::OKR` instead of a JSON response.
+
+pub const JSONRPC_VERSION: &'static str = "2.0";
+
+// The standard error codes. Codes -32000 to -32099 are reserved for
+// implementation-defined server errors.
+pub const RPC_PARSE_ERROR: i64 = -32700;
+pub const RPC_INVALID_REQUEST: i64 = -32600;
+pub const RPC_METHOD_NOT_FOUND: i64 = -32601;
+pub const RPC_INVALID_PARAMS: i64 = -32602;
+pub const RPC_INTERNAL_ERROR: i64 = -32603;
+
+pub fn to_json<T: Encodable>(x: &T) -> Result<Json, ()> {
+  let mut buf = String::new();
+  {
+    let mut enc = JsonEncoder::new(&mut buf);
+    x.encode(&mut enc).map_err(|_| ())?;
+  }
+  Json::from_str(&buf).map_err(|_| ())
+}
+
+pub fn from_json<T: Decodable>(j: &Json) -> Result<T, ()> {
+  j.clone().decode_into().map_err(|_| ())
+}
+
+#[derive(Clone, Debug)]
+pub struct RpcError {
+  pub code: i64,
+  pub message: String,
+  pub data: Option<Json>,
+}
+
+impl RpcError {
+  pub fn new<S: Into<String>>(code: i64, message: S) -> RpcError {
+    RpcError{code, message: message.into(), data: None}
+  }
+
+  pub fn with_data(mut self, data: Json) -> RpcError {
+    self.data = Some(data);
+    self
+  }
+
+  pub fn parse_error() -> RpcError {
+    RpcError::new(RPC_PARSE_ERROR, "Parse error")
+  }
+
+  pub fn invalid_request() -> RpcError {
+    RpcError::new(RPC_INVALID_REQUEST, "Invalid Request")
+  }
+
+  pub fn method_not_found() -> RpcError {
+    RpcError::new(RPC_METHOD_NOT_FOUND, "Method not found")
+  }
+
+  pub fn invalid_params() -> RpcError {
+    RpcError::new(RPC_INVALID_PARAMS, "Invalid params")
+  }
+
+  // An "Invalid params" error carrying the schema violations as data.
+  pub fn from_schema(e: &SchemaErr) -> RpcError {
+    RpcError::invalid_params().with_data(e.to_json())
+  }
+
+  pub fn internal_error() -> RpcError {
+    RpcError::new(RPC_INTERNAL_ERROR, "Internal error")
+  }
+
+  pub fn to_json(&self) -> Json {
+    let mut obj = BTreeMap::new();
+    obj.insert("code".to_string(), Json::I64(self.code));
+    obj.insert("message".to_string(), Json::String(self.message.clone()));
+    if let Some(ref data) = self.data {
+      obj.insert("data".to_string(), data.clone());
+    }
+    Json::Object(obj)
+  }
+
+  pub fn from_json(j: &Json) -> Option<RpcError> {
+    let obj = j.as_object()?;
+    let code = obj.get("code")?.as_i64()?;
+    let message = obj.get("message")?.as_string()?.to_string();
+    let data = obj.get("data").cloned();
+    Some(RpcError{code, message, data})
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct RpcRequest {
+  pub method: String,
+  // Either an array or an object, if present.
+  pub params: Option<Json>,
+  // `None` for a notification.
+  pub id: Option<Json>,
+}
+
+impl RpcRequest {
+  pub fn new<S: Into<String>>(method: S, params: Option<Json>, id: Json) -> RpcRequest {
+    RpcRequest{method: method.into(), params, id: Some(id)}
+  }
+
+  pub fn notification<S: Into<String>>(method: S, params: Option<Json>) -> RpcRequest {
+    RpcRequest{method: method.into(), params, id: None}
+  }
+
+  pub fn is_notification(&self) -> bool {
+    self.id.is_none()
+  }
+
+  // Decodes the params, treating absent params as `null`.
+  pub fn params<T: Decodable>(&self) -> Result<T, RpcError> {
+    from_json(self.params.as_ref().unwrap_or(&Json::Null))
+      .map_err(|_| RpcError::invalid_params())
+  }
+
+  pub fn to_json(&self) -> Json {
+    let mut obj = BTreeMap::new();
+    obj.insert("jsonrpc".to_string(), Json::String(JSONRPC_VERSION.to_string()));
+    obj.insert("method".to_string(), Json::String(self.method.clone()));
+    if let Some(ref params) = self.params {
+      obj.insert("params".to_string(), params.clone());
+    }
+    if let Some(ref id) = self.id {
+      obj.insert("id".to_string(), id.clone());
+    }
+    Json::Object(obj)
+  }
+
+  // On error, also returns the request id, if it could be determined.
+  pub fn from_json(j: &Json) -> Result<RpcRequest, (RpcError, Json)> {
+    let obj = match j.as_object() {
+      None => return Err((RpcError::invalid_request(), Json::Null)),
+      Some(obj) => obj
+    };
+    let id = match obj.get("id") {
+      None => None,
+      Some(id) if valid_id(id) => Some(id.clone()),
+      Some(_) => return Err((RpcError::invalid_request(), Json::Null))
+    };
+    let err_id = id.clone().unwrap_or(Json::Null);
+    if obj.get("jsonrpc").and_then(|v| v.as_string()) != Some(JSONRPC_VERSION) {
+      return Err((RpcError::invalid_request(), err_id));
+    }
+    let method = match obj.get("method").and_then(|v| v.as_string()) {
+      None => return Err((RpcError::invalid_request(), err_id)),
+      Some(m) => m.to_string()
+    };
+    let params = match obj.get("params") {
+      None => None,
+      Some(p) if p.is_array() || p.is_object() => Some(p.clone()),
+      Some(_) => return Err((RpcError::invalid_request(), err_id))
+    };
+    Ok(RpcRequest{method, params, id})
+  }
+}
+
+fn valid_id(id: &Json) -> bool {
+  match id {
+    &Json::I64(_) | &Json::U64(_) | &Json::String(_) | &Json::Null => true,
+    _ => false
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct RpcResponse {
+  pub id: Json,
+  pub result: Result<Json, RpcError>,
+}
+
+impl RpcResponse {
+  pub fn decode_result<T: Decodable>(&self) -> Result<T, RpcCallErr> {
+    match self.result {
+      Err(ref e) => Err(RpcCallErr::Rpc(e.clone())),
+      Ok(ref j) => from_json(j).map_err(|_| RpcCallErr::Decode)
+    }
+  }
+
+  pub fn to_json(&self) -> Json {
+    let mut obj = BTreeMap::new();
+    obj.insert("jsonrpc".to_string(), Json::String(JSONRPC_VERSION.to_string()));
+    match self.result {
+      Ok(ref j) => {
+        obj.insert("result".to_string(), j.clone());
+      }
+      Err(ref e) => {
+        obj.insert("error".to_string(), e.to_json());
+      }
+    }
+    obj.insert("id".to_string(), self.id.clone());
+    Json::Object(obj)
+  }
+
+  pub fn from_json(j: &Json) -> Option<RpcResponse> {
+    let obj = j.as_object()?;
+    if obj.get("jsonrpc")?.as_string()? != JSONRPC_VERSION {
+      return None;
+    }
+    let id = obj.get("id")?.clone();
+    let result = match (obj.get("result"), obj.get("error")) {
+      (Some(r), None) => Ok(r.clone()),
+      (None, Some(e)) => Err(RpcError::from_json(e)?),
+      _ => return None
+    };
+    Some(RpcResponse{id, result})
+  }
+}
+
+pub type RpcMethod = Box<dyn 'static + Send + Sync + Fn(&RpcRequest) -> Result<Json, RpcError>>;
+
+pub struct RpcDispatcher {
+  methods: BTreeMap<String, RpcMethod>,
+}
+
+impl RpcDispatcher {
+  pub fn new() -> RpcDispatcher {
+    RpcDispatcher{methods: BTreeMap::new()}
+  }
+
+  pub fn insert<S: Into<String>>(&mut self, method: S, fire: RpcMethod) {
+    let method = method.into();
+    if method.starts_with("rpc.") {
+      panic!("bug: RpcDispatcher::insert: reserved method name: {:?}", method);
+    }
+    self.methods.insert(method, fire);
+  }
+
+  // Registers a method with typed params and result; params that fail to
+  // decode are answered with an "Invalid params" error.
+  pub fn insert_typed<S, P, R, F>(&mut self, method: S, fire: F)
+  where S: Into<String>,
+        P: Decodable,
+        R: Encodable,
+        F: 'static + Send + Sync + Fn(P) -> Result<R, RpcError>,
+  {
+    self.insert(method, Box::new(move |req: &RpcRequest| {
+      let params = req.params()?;
+      let result = (fire)(params)?;
+      to_json(&result).map_err(|_| RpcError::internal_error())
+    }))
+  }
+
+  // Registers a method whose params (`null` if absent) are validated
+  // against the schema before it runs.
+  pub fn insert_validated<S: Into<String>>(&mut self, method: S, schema: Schema, fire: RpcMethod) {
+    self.insert(method, Box::new(move |req: &RpcRequest| {
+      schema.validate(req.params.as_ref().unwrap_or(&Json::Null))
+        .map_err(|e| RpcError::from_schema(&e))?;
+      (fire)(req)
+    }))
+  }
+
+  pub fn remove(&mut self, method: &str) {
+    self.methods.remove(method);
+  }
+
+  fn dispatch_one(&self, j: &Json) -> Option<Json> {
+    let req = match RpcRequest::from_json(j) {
+      Err((e, id)) => return Some(RpcResponse{id, result: Err(e)}.to_json()),
+      Ok(req) => req
+    };
+    let result = match self.methods.get(&req.method) {
+      None => Err(RpcError::method_not_found()),
+      Some(fire) => (fire)(&req)
+    };
+    let id = req.id?;
+    Some(RpcResponse{id, result}.to_json())
+  }
+
+  // Handles a request or a batch of requests, returning the response,
+  // if any; notifications get no response.
+  pub fn handle(&self, j: &Json) -> Option<Json> {
+    match j.as_array() {
+      None => self.dispatch_one(j),
+      Some(reqs) if reqs.is_empty() => {
+        Some(RpcResponse{id: Json::Null, result: Err(RpcError::invalid_request())}.to_json())
+      }
+      Some(reqs) => {
+        let reps: Vec<_> = reqs.iter().filter_map(|req| self.dispatch_one(req)).collect();
+        if reps.is_empty() {
+          None
+        } else {
+          Some(Json::Array(reps))
+        }
+      }
+    }
+  }
+
+  // Like `handle`, but parses the request first; text that is not JSON
+  // is answered with a "Parse error".
+  pub fn handle_bytes(&self, buf: &[u8]) -> Option<Json> {
+    match Json::from_reader(Cursor::new(buf)) {
+      Err(_) => Some(RpcResponse{id: Json::Null, result: Err(RpcError::parse_error())}.to_json()),
+      Ok(j) => self.handle(&j)
+    }
+  }
+
+  // Handles one query on a connection that only carries JSON-RPC;
+  // returns true if the connection should be closed. The handler from
+  // `into_handler` only sees payloads that parsed (a `Chan` fails the
+  // connection on a `JSO` payload that is not JSON), whereas this answers
+  // them with a "Parse error".
+  pub fn reply<MsgX: WireCodex>(&self, chan: &mut Chan<MsgX>) -> Result<bool, ReplyErr> {
+    let (rseq, rep) = {
+      let (hdr, buf) = chan.recv_raw()?;
+      let rep = match &hdr.tag {
+        b"HUP" => return Ok(true),
+        b"JSO" => {
+          match self.handle_bytes(buf) {
+            None => Msg::OKR,
+            Some(rep) => Msg::JSO(rep)
+          }
+        }
+        _ => Msg::Bot
+      };
+      (hdr.seq, rep)
+    };
+    let tseq = chan.send(&rep)?;
+    if rseq != tseq {
+      return Err(ReplyErr::Seq);
+    }
+    Ok(false)
+  }
+
+  pub fn handle_msg<MsgX>(&self, msg: &Msg<MsgX>) -> Msg<MsgX> {
+    match msg {
+      &Msg::JSO(ref j) => {
+        match self.handle(j) {
+          None => Msg::OKR,
+          Some(rep) => Msg::JSO(rep)
+        }
+      }
+      _ => Msg::Bot
+    }
+  }
+
+  // A handler for e.g. `SpawnPool::replying`.
+  pub fn into_handler<MsgX: 'static>(self) -> Arc<dyn 'static + Send + Sync + Fn(&Msg<MsgX>) -> Msg<MsgX>> {
+    Arc::new(move |msg: &Msg<MsgX>| self.handle_msg(msg))
+  }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RpcCallErr {
+  Encode,
+  Query(QueryErr),
+  // The reply is not a well-formed response to the request.
+  Reply,
+  Rpc(RpcError),
+  Decode,
+}
+
+impl From<QueryErr> for RpcCallErr {
+  fn from(e: QueryErr) -> RpcCallErr {
+    RpcCallErr::Query(e)
+  }
+}
+
+pub struct RpcClient<MsgX=()> {
+  chan: Chan<MsgX>,
+  // Positive integers are parsed as `Json::U64`, so ids are `u64` so that
+  // they compare equal to the echoed ids.
+  next_id: u64,
+}
+
+impl<MsgX: WireCodex> RpcClient<MsgX> {
+  pub fn new(chan: Chan<MsgX>) -> RpcClient<MsgX> {
+    RpcClient{chan, next_id: 1}
+  }
+
+  pub fn chan_mut(&mut self) -> &mut Chan<MsgX> {
+    &mut self.chan
+  }
+
+  pub fn into_chan(self) -> Chan<MsgX> {
+    self.chan
+  }
+
+  // Builds a request with a fresh id, e.g. for `batch`.
+  pub fn request<S: Into<String>, P: Encodable>(&mut self, method: S, params: &P) -> Result<RpcRequest, RpcCallErr> {
+    let params = to_json(params).map_err(|_| RpcCallErr::Encode)?;
+    let id = self.next_id;
+    self.next_id += 1;
+    Ok(RpcRequest::new(method, Some(params), Json::U64(id)))
+  }
+
+  pub fn call<S, P, R>(&mut self, method: S, params: &P) -> Result<R, RpcCallErr>
+  where S: Into<String>, P: Encodable, R: Decodable {
+    let req = self.request(method, params)?;
+    let rep = match self.chan.query(&Msg::JSO(req.to_json()))? {
+      Msg::JSO(j) => RpcResponse::from_json(&j).ok_or(RpcCallErr::Reply)?,
+      _ => return Err(RpcCallErr::Reply)
+    };
+    if Some(&rep.id) != req.id.as_ref() {
+      return Err(RpcCallErr::Reply);
+    }
+    rep.decode_result()
+  }
+
+  pub fn notify<S: Into<String>, P: Encodable>(&mut self, method: S, params: &P) -> Result<(), RpcCallErr> {
+    let params = to_json(params).map_err(|_| RpcCallErr::Encode)?;
+    let req = RpcRequest::notification(method, Some(params));
+    match self.chan.query(&Msg::JSO(req.to_json()))? {
+      Msg::OKR => Ok(()),
+      _ => Err(RpcCallErr::Reply)
+    }
+  }
+
+  // Sends a batch, returning the responses in the order of the
+  // (non-notification) requests.
+  //
+  // Responses with a `null` id are matched by position instead: they go,
+  // in order, to the requests left without a response of their own. These
+  // are requests with a `null` id, and requests that the server could not
+  // make sense of, which are answered with a `null` id. Any left over
+  // belong to malformed notifications and are dropped.
+  pub fn batch(&mut self, reqs: &[RpcRequest]) -> Result<Vec<RpcResponse>, RpcCallErr> {
+    let batch = Json::Array(reqs.iter().map(|req| req.to_json()).collect());
+    let mut reps = match self.chan.query(&Msg::JSO(batch))? {
+      Msg::OKR => Vec::new(),
+      Msg::JSO(Json::Array(reps)) => {
+        let mut parsed = Vec::with_capacity(reps.len());
+        for rep in reps.iter() {
+          parsed.push(RpcResponse::from_json(rep).ok_or(RpcCallErr::Reply)?);
+        }
+        parsed
+      }
+      _ => return Err(RpcCallErr::Reply)
+    };
+    let mut ordered = Vec::with_capacity(reps.len());
+    for id in reqs.iter().filter_map(|req| req.id.as_ref()) {
+      match reps.iter().position(|rep| !rep.id.is_null() && &rep.id == id) {
+        None => ordered.push(None),
+        Some(i) => ordered.push(Some(reps.remove(i)))
+      }
+    }
+    let mut unclaimed = reps.into_iter().filter(|rep| rep.id.is_null());
+    let mut matched = Vec::with_capacity(ordered.len());
+    for rep in ordered.into_iter() {
+      match rep.or_else(|| unclaimed.next()) {
+        None => return Err(RpcCallErr::Reply),
+        Some(rep) => matched.push(rep)
+      }
+    }
+    Ok(matched)
+  }
+}
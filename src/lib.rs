@@ -5,15 +5,40 @@ extern crate constant_time_eq;
 extern crate http1;
 extern crate once_cell;
 extern crate rustc_serialize;
+#[cfg(feature = "derive")]
+extern crate service_base_derive;
 extern crate signal_hook;
 extern crate smol_str;
 extern crate unix2;
 
+pub mod capture;
 pub mod chan;
+pub mod ctx;
 pub mod daemon;
+pub mod deadline;
+pub mod frame;
 pub mod http;
+pub mod jsonrpc;
+pub mod logging;
+pub mod metrics;
+pub mod middleware;
 pub mod msg;
 pub mod prelude;
+pub mod ratelimit;
+pub mod retry;
 pub mod route;
+pub mod schema;
+pub mod service;
+pub mod session;
 pub mod signal;
 pub mod state;
+pub mod tag;
+pub mod testkit;
+pub mod trace;
+pub mod typed;
+pub mod watchdog;
+
+#[cfg(feature = "derive")]
+pub use service_base_derive::{MsgCodex};
+#[doc(hidden)]
+pub use rustc_serialize as __rustc_serialize;
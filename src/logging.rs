@@ -0,0 +1,295 @@
+use crate::ctx::{ConnCtx};
+use crate::middleware::{Middleware};
+use crate::msg::*;
+use crate::service::{Outcome};
+use crate::signal::{signals};
+use crate::trace::{current as current_trace};
+
+use once_cell::sync::{Lazy};
+use rustc_serialize::json::{Json};
+
+use std::collections::{BTreeMap};
+use std::fmt::{Display, Write as FmtWrite};
+use std::fs::{OpenOptions};
+use std::io::{Error as IoError, Write, stderr};
+use std::path::{PathBuf};
+use std::sync::{Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering as AtomicOrdering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A small structured logger: each record is a level, a message, and
+// key-value fields, plus the trace context of the query being handled,
+// written as one line of logfmt or JSON.
+//
+//     log(LogLevel::Warn, "accept failed", &[("error", &e)]);
+
+pub static ONCE_LOGGER: Lazy<Logger> = Lazy::new(|| Logger::new());
+
+pub fn logger() -> &'static Logger {
+  &*ONCE_LOGGER
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[repr(u8)]
+pub enum LogLevel {
+  Error = 1,
+  Warn,
+  Info,
+  Debug,
+  Trace,
+}
+
+impl LogLevel {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      LogLevel::Error => "error",
+      LogLevel::Warn => "warn",
+      LogLevel::Info => "info",
+      LogLevel::Debug => "debug",
+      LogLevel::Trace => "trace",
+    }
+  }
+
+  pub fn parse(s: &str) -> Option<LogLevel> {
+    Some(match s {
+      "error" => LogLevel::Error,
+      "warn" => LogLevel::Warn,
+      "info" => LogLevel::Info,
+      "debug" => LogLevel::Debug,
+      "trace" => LogLevel::Trace,
+      _ => return None
+    })
+  }
+
+  fn from_u8(x: u8) -> LogLevel {
+    match x {
+      0 | 1 => LogLevel::Error,
+      2 => LogLevel::Warn,
+      3 => LogLevel::Info,
+      4 => LogLevel::Debug,
+      _ => LogLevel::Trace,
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogFormat {
+  Logfmt,
+  JsonLines,
+}
+
+#[derive(Clone, Debug)]
+pub enum LogOutput {
+  Stderr,
+  // Appended to.
+  File(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+  pub level: LogLevel,
+  pub format: LogFormat,
+  pub output: LogOutput,
+  // Whether SIGUSR1 and SIGUSR2 raise and lower the level (see
+  // `SignalsConfigOnce`, which must also enable them).
+  pub level_signals: bool,
+}
+
+impl Default for LogConfig {
+  fn default() -> LogConfig {
+    LogConfig{
+      level: LogLevel::Info,
+      format: LogFormat::Logfmt,
+      output: LogOutput::Stderr,
+      level_signals: false,
+    }
+  }
+}
+
+struct LogSink {
+  format: LogFormat,
+  w: Box<dyn Write + Send>,
+}
+
+pub struct Logger {
+  level: AtomicU8,
+  level_signals: AtomicBool,
+  sink: Mutex<LogSink>,
+}
+
+impl Logger {
+  fn new() -> Logger {
+    let cfg = LogConfig::default();
+    Logger{
+      level: AtomicU8::new(cfg.level as u8),
+      level_signals: AtomicBool::new(cfg.level_signals),
+      sink: Mutex::new(LogSink{
+        format: cfg.format,
+        w: Box::new(stderr()),
+      }),
+    }
+  }
+
+  pub fn configure(&self, cfg: &LogConfig) -> Result<(), IoError> {
+    let w: Box<dyn Write + Send> = match &cfg.output {
+      &LogOutput::Stderr => Box::new(stderr()),
+      &LogOutput::File(ref path) => {
+        Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+      }
+    };
+    let mut sink = self.sink.lock().unwrap();
+    *sink = LogSink{format: cfg.format, w};
+    self.set_level(cfg.level);
+    self.level_signals.store(cfg.level_signals, AtomicOrdering::Relaxed);
+    Ok(())
+  }
+
+  pub fn level(&self) -> LogLevel {
+    LogLevel::from_u8(self.level.load(AtomicOrdering::Relaxed))
+  }
+
+  pub fn set_level(&self, level: LogLevel) {
+    self.level.store(level as u8, AtomicOrdering::Relaxed);
+  }
+
+  pub fn enabled(&self, level: LogLevel) -> bool {
+    if self.level_signals.load(AtomicOrdering::Relaxed) {
+      self.poll_level_signals();
+    }
+    level <= self.level()
+  }
+
+  // Applies pending SIGUSR1 (more verbose) and SIGUSR2 (less verbose).
+  fn poll_level_signals(&self) {
+    let sigs = signals();
+    if sigs.take_usr1() {
+      let level = self.level() as u8;
+      self.set_level(LogLevel::from_u8(level.saturating_add(1)));
+    }
+    if sigs.take_usr2() {
+      let level = self.level() as u8;
+      self.set_level(LogLevel::from_u8(level.saturating_sub(1)));
+    }
+  }
+
+  pub fn log(&self, level: LogLevel, msg: &str, fields: &[(&str, &dyn Display)]) {
+    if !self.enabled(level) {
+      return;
+    }
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH)
+      .map(|d| d.as_secs_f64()).unwrap_or(0.0);
+    let mut kvs: Vec<(&str, String)> = Vec::with_capacity(fields.len() + 2);
+    for &(k, v) in fields.iter() {
+      kvs.push((k, v.to_string()));
+    }
+    if let Some(t) = current_trace() {
+      kvs.push(("trace_id", format!("{:032x}", t.trace_id)));
+      kvs.push(("span_id", format!("{:016x}", t.span_id)));
+    }
+    let mut sink = self.sink.lock().unwrap();
+    let mut line = String::new();
+    match sink.format {
+      LogFormat::Logfmt => {
+        write!(line, "ts={:.6} level={} msg={}", ts, level.as_str(), logfmt_val(msg)).unwrap();
+        for &(k, ref v) in kvs.iter() {
+          write!(line, " {}={}", k, logfmt_val(v)).unwrap();
+        }
+      }
+      LogFormat::JsonLines => {
+        write!(line, "{{\"ts\":{:.6},\"level\":\"{}\",\"msg\":{}", ts, level.as_str(), Json::String(msg.to_string())).unwrap();
+        for &(k, ref v) in kvs.iter() {
+          write!(line, ",{}:{}", Json::String(k.to_string()), Json::String(v.clone())).unwrap();
+        }
+        line.push('}');
+      }
+    }
+    line.push('\n');
+    let _ = sink.w.write_all(line.as_bytes());
+    let _ = sink.w.flush();
+  }
+}
+
+fn logfmt_val(s: &str) -> String {
+  let bare = !s.is_empty() && s.chars().all(|c| c > ' ' && c != '"' && c != '=' && c != '\\' && c != '\x7f');
+  if bare {
+    return s.to_string();
+  }
+  let mut out = String::with_capacity(s.len() + 2);
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+pub fn configure_logging(cfg: &LogConfig) -> Result<(), IoError> {
+  logger().configure(cfg)
+}
+
+pub fn set_log_level(level: LogLevel) {
+  logger().set_level(level)
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+  logger().enabled(level)
+}
+
+pub fn log(level: LogLevel, msg: &str, fields: &[(&str, &dyn Display)]) {
+  logger().log(level, msg, fields)
+}
+
+pub const LOG_LEVEL_QUERY_KEY: &'static str = "log_level";
+
+// Answers the control query
+//
+//     JSO {"log_level": "<level>" | null}
+//
+// by setting the level (unless null), replying with the current level
+// as `JSO {"log_level": "<level>"}`; an unknown level gets `Bot`.
+//
+// Any client that can send this query can change the log level of the
+// whole server, e.g. to `trace`, which may be costly and log sensitive
+// data. It is not installed by default; install it behind an auth
+// middleware (e.g. after `SharedSecretAuth` in the chain).
+pub struct LogLevelQuery;
+
+impl LogLevelQuery {
+  pub fn query<MsgX>(level: Option<LogLevel>) -> Msg<MsgX> {
+    let mut obj = BTreeMap::new();
+    obj.insert(LOG_LEVEL_QUERY_KEY.to_string(), match level {
+      None => Json::Null,
+      Some(level) => Json::String(level.as_str().to_string())
+    });
+    Msg::JSO(Json::Object(obj))
+  }
+}
+
+impl<MsgX> Middleware<MsgX> for LogLevelQuery {
+  fn call(&self, ctx: &mut ConnCtx, query: &Msg<MsgX>, next: &mut dyn FnMut(&mut ConnCtx, &Msg<MsgX>) -> Outcome<MsgX>) -> Outcome<MsgX> {
+    let arg = match query {
+      &Msg::JSO(Json::Object(ref obj)) if obj.len() == 1 => obj.get(LOG_LEVEL_QUERY_KEY),
+      _ => None
+    };
+    match arg {
+      None => (next)(ctx, query),
+      Some(&Json::Null) => Outcome::Reply(LogLevelQuery::query(Some(logger().level()))),
+      Some(&Json::String(ref s)) => match LogLevel::parse(s) {
+        None => Outcome::Reply(Msg::Bot),
+        Some(level) => {
+          set_log_level(level);
+          log(LogLevel::Info, "log level changed", &[("level", &level.as_str()), ("conn", &ctx.id())]);
+          Outcome::Reply(LogLevelQuery::query(Some(level)))
+        }
+      },
+      Some(_) => Outcome::Reply(Msg::Bot)
+    }
+  }
+}
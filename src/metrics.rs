@@ -0,0 +1,329 @@
+use crate::ctx::{ConnCtx};
+use crate::http::{HttpRequest, HttpResponse};
+use crate::middleware::{Middleware};
+use crate::msg::*;
+use crate::route::{Pat, RouteArgs, RouteFire};
+use crate::service::{Outcome};
+
+use once_cell::sync::{Lazy};
+use rustc_serialize::json::{Json};
+
+use std::collections::{BTreeMap};
+use std::fmt::{Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering as AtomicOrdering};
+use std::time::{Duration as StdDuration};
+
+// The registry that `Chan`, `SpawnPool` and `Router` report into.
+pub static ONCE_METRICS: Lazy<MetricsRegistry> = Lazy::new(|| MetricsRegistry::new());
+
+pub fn metrics() -> &'static MetricsRegistry {
+  &*ONCE_METRICS
+}
+
+// Histogram bucket bounds for latencies, in seconds.
+pub const LATENCY_BUCKETS: &'static [f64] = &[
+  0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Clone)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+  pub fn inc(&self) {
+    self.add(1);
+  }
+
+  pub fn add(&self, n: u64) {
+    self.0.fetch_add(n, AtomicOrdering::Relaxed);
+  }
+
+  pub fn get(&self) -> u64 {
+    self.0.load(AtomicOrdering::Relaxed)
+  }
+}
+
+#[derive(Clone)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+  pub fn inc(&self) {
+    self.add(1);
+  }
+
+  pub fn dec(&self) {
+    self.add(-1);
+  }
+
+  pub fn add(&self, n: i64) {
+    self.0.fetch_add(n, AtomicOrdering::Relaxed);
+  }
+
+  pub fn set(&self, x: i64) {
+    self.0.store(x, AtomicOrdering::Relaxed);
+  }
+
+  pub fn get(&self) -> i64 {
+    self.0.load(AtomicOrdering::Relaxed)
+  }
+
+  // Increments the gauge until the guard is dropped, e.g. to count
+  // active connections.
+  pub fn track(&self) -> GaugeGuard {
+    self.inc();
+    GaugeGuard(self.clone())
+  }
+}
+
+pub struct GaugeGuard(Gauge);
+
+impl Drop for GaugeGuard {
+  fn drop(&mut self) {
+    self.0.dec();
+  }
+}
+
+struct HistogramState {
+  bounds: Vec<f64>,
+  // Per bucket, not cumulative; the last is the `+Inf` bucket.
+  counts: Vec<u64>,
+  sum: f64,
+  count: u64,
+}
+
+#[derive(Clone)]
+pub struct Histogram(Arc<Mutex<HistogramState>>);
+
+impl Histogram {
+  fn new(bounds: &[f64]) -> Histogram {
+    Histogram(Arc::new(Mutex::new(HistogramState{
+      bounds: bounds.to_owned(),
+      counts: vec![0; bounds.len() + 1],
+      sum: 0.0,
+      count: 0,
+    })))
+  }
+
+  pub fn observe(&self, x: f64) {
+    let mut h = self.0.lock().unwrap();
+    let idx = h.bounds.iter().position(|&b| x <= b).unwrap_or(h.bounds.len());
+    h.counts[idx] += 1;
+    h.sum += x;
+    h.count += 1;
+  }
+
+  pub fn observe_duration(&self, d: StdDuration) {
+    self.observe(d.as_secs_f64());
+  }
+
+  pub fn count(&self) -> u64 {
+    self.0.lock().unwrap().count
+  }
+
+  pub fn sum(&self) -> f64 {
+    self.0.lock().unwrap().sum
+  }
+}
+
+#[derive(Clone)]
+enum Metric {
+  Counter(Counter),
+  Gauge(Gauge),
+  Histogram(Histogram),
+}
+
+impl Metric {
+  fn kind(&self) -> &'static str {
+    match self {
+      &Metric::Counter(_) => "counter",
+      &Metric::Gauge(_) => "gauge",
+      &Metric::Histogram(_) => "histogram",
+    }
+  }
+}
+
+type Labels = Vec<(String, String)>;
+
+struct Family {
+  help: String,
+  series: BTreeMap<Labels, Metric>,
+}
+
+// Metrics by name and labels. Looking up a metric creates it, so report
+// sites need no setup; hot paths should keep the returned handle.
+pub struct MetricsRegistry {
+  families: Mutex<BTreeMap<String, Family>>,
+}
+
+impl MetricsRegistry {
+  pub fn new() -> MetricsRegistry {
+    MetricsRegistry{families: Mutex::new(BTreeMap::new())}
+  }
+
+  fn get_or_insert<F: FnOnce() -> Metric>(&self, name: &str, help: &str, labels: &[(&str, &str)], kind: &'static str, f: F) -> Metric {
+    let mut labels: Labels = labels.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+    labels.sort();
+    let res = {
+      let mut families = self.families.lock().unwrap();
+      let family = families.entry(name.to_string()).or_insert_with(|| Family{
+        help: help.to_string(),
+        series: BTreeMap::new(),
+      });
+      match family.series.values().next().map(|m| m.kind()) {
+        Some(other) if other != kind => Err(other),
+        _ => Ok(family.series.entry(labels).or_insert_with(f).clone())
+      }
+    };
+    // Not panicking with the lock held, which would poison the registry.
+    match res {
+      Err(other) => panic!("bug: MetricsRegistry: metric {:?} is a {}, not a {}", name, other, kind),
+      Ok(metric) => metric
+    }
+  }
+
+  pub fn counter(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Counter {
+    match self.get_or_insert(name, help, labels, "counter", || Metric::Counter(Counter(Arc::new(AtomicU64::new(0))))) {
+      Metric::Counter(c) => c,
+      m => panic!("bug: MetricsRegistry: metric {:?} is a {}, not a counter", name, m.kind())
+    }
+  }
+
+  pub fn gauge(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Gauge {
+    match self.get_or_insert(name, help, labels, "gauge", || Metric::Gauge(Gauge(Arc::new(AtomicI64::new(0))))) {
+      Metric::Gauge(g) => g,
+      m => panic!("bug: MetricsRegistry: metric {:?} is a {}, not a gauge", name, m.kind())
+    }
+  }
+
+  // A histogram with the `LATENCY_BUCKETS` bounds.
+  pub fn histogram(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Histogram {
+    self.histogram_with(name, help, labels, LATENCY_BUCKETS)
+  }
+
+  // The bounds only apply when the histogram is created.
+  pub fn histogram_with(&self, name: &str, help: &str, labels: &[(&str, &str)], bounds: &[f64]) -> Histogram {
+    match self.get_or_insert(name, help, labels, "histogram", || Metric::Histogram(Histogram::new(bounds))) {
+      Metric::Histogram(h) => h,
+      m => panic!("bug: MetricsRegistry: metric {:?} is a {}, not a histogram", name, m.kind())
+    }
+  }
+
+  // Renders the metrics in the Prometheus text exposition format.
+  pub fn render_prometheus(&self) -> String {
+    let mut out = String::new();
+    let families = self.families.lock().unwrap();
+    for (name, family) in families.iter() {
+      let kind = match family.series.values().next() {
+        None => continue,
+        Some(m) => m.kind()
+      };
+      writeln!(out, "# HELP {} {}", name, escape_help(&family.help)).unwrap();
+      writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+      for (labels, metric) in family.series.iter() {
+        match metric {
+          &Metric::Counter(ref c) => {
+            writeln!(out, "{}{} {}", name, fmt_labels(labels, None), c.get()).unwrap();
+          }
+          &Metric::Gauge(ref g) => {
+            writeln!(out, "{}{} {}", name, fmt_labels(labels, None), g.get()).unwrap();
+          }
+          &Metric::Histogram(ref h) => {
+            let h = h.0.lock().unwrap();
+            let mut acc = 0;
+            for (i, &n) in h.counts.iter().enumerate() {
+              acc += n;
+              let le = match h.bounds.get(i) {
+                None => "+Inf".to_string(),
+                Some(b) => fmt_f64(*b)
+              };
+              writeln!(out, "{}_bucket{} {}", name, fmt_labels(labels, Some(&le)), acc).unwrap();
+            }
+            writeln!(out, "{}_sum{} {}", name, fmt_labels(labels, None), fmt_f64(h.sum)).unwrap();
+            writeln!(out, "{}_count{} {}", name, fmt_labels(labels, None), h.count).unwrap();
+          }
+        }
+      }
+    }
+    out
+  }
+}
+
+impl Default for MetricsRegistry {
+  fn default() -> MetricsRegistry {
+    MetricsRegistry::new()
+  }
+}
+
+fn fmt_f64(x: f64) -> String {
+  if x.is_infinite() {
+    return if x > 0.0 { "+Inf".to_string() } else { "-Inf".to_string() };
+  }
+  format!("{}", x)
+}
+
+fn escape_help(s: &str) -> String {
+  s.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn escape_label(s: &str) -> String {
+  s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn fmt_labels(labels: &Labels, le: Option<&str>) -> String {
+  if labels.is_empty() && le.is_none() {
+    return String::new();
+  }
+  let mut out = String::from("{");
+  for (i, &(ref k, ref v)) in labels.iter().enumerate() {
+    if i > 0 {
+      out.push(',');
+    }
+    write!(out, "{}=\"{}\"", k, escape_label(v)).unwrap();
+  }
+  if let Some(le) = le {
+    if !labels.is_empty() {
+      out.push(',');
+    }
+    write!(out, "le=\"{}\"", le).unwrap();
+  }
+  out.push('}');
+  out
+}
+
+// A `RouteFire` that serves the global registry, e.g.
+//
+//     router.insert_get("metrics", metrics_route());
+pub fn metrics_route() -> RouteFire {
+  Box::new(|_: &[Pat], _: &RouteArgs, _: &HttpRequest| {
+    Some(HttpResponse::ok().with_payload_str(metrics().render_prometheus()))
+  })
+}
+
+pub const METRICS_QUERY_KEY: &'static str = "metrics";
+
+// Answers the control query
+//
+//     JSO {"metrics": null}
+//
+// with the global registry in the Prometheus text format, as a JSON
+// string.
+pub struct MetricsQuery;
+
+impl MetricsQuery {
+  pub fn query<MsgX>() -> Msg<MsgX> {
+    let mut obj = BTreeMap::new();
+    obj.insert(METRICS_QUERY_KEY.to_string(), Json::Null);
+    Msg::JSO(Json::Object(obj))
+  }
+}
+
+impl<MsgX> Middleware<MsgX> for MetricsQuery {
+  fn call(&self, ctx: &mut ConnCtx, query: &Msg<MsgX>, next: &mut dyn FnMut(&mut ConnCtx, &Msg<MsgX>) -> Outcome<MsgX>) -> Outcome<MsgX> {
+    match query {
+      &Msg::JSO(Json::Object(ref obj)) if obj.len() == 1 && obj.contains_key(METRICS_QUERY_KEY) => {
+        Outcome::Reply(Msg::JSO(Json::String(metrics().render_prometheus())))
+      }
+      _ => (next)(ctx, query)
+    }
+  }
+}
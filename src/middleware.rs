@@ -0,0 +1,299 @@
+use crate::chan::*;
+use crate::ctx::{ConnCtx, PeerAddr};
+use crate::logging::{LogLevel, log, log_enabled};
+use crate::msg::*;
+use crate::service::{Outcome, Service};
+
+use constant_time_eq::{constant_time_eq};
+use rustc_serialize::json::{Json};
+
+use std::collections::{BTreeMap};
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::time::{Duration as StdDuration, Instant};
+
+// An interceptor around a handler: it sees each query before the handler
+// (and may answer it without calling `next`), and the outcome after.
+// Middleware is shared by all connections; per-connection state belongs
+// in the `ConnCtx`.
+pub trait Middleware<MsgX=()>: Send + Sync {
+  fn call(&self, ctx: &mut ConnCtx, query: &Msg<MsgX>, next: &mut dyn FnMut(&mut ConnCtx, &Msg<MsgX>) -> Outcome<MsgX>) -> Outcome<MsgX>;
+}
+
+// Middleware in call order: the first is the outermost.
+pub struct MiddlewareChain<MsgX=()> {
+  layers: Vec<Arc<dyn Middleware<MsgX>>>,
+}
+
+impl<MsgX> MiddlewareChain<MsgX> {
+  pub fn new() -> MiddlewareChain<MsgX> {
+    MiddlewareChain{layers: Vec::new()}
+  }
+
+  pub fn push(&mut self, mw: Arc<dyn Middleware<MsgX>>) {
+    self.layers.push(mw);
+  }
+
+  pub fn len(&self) -> usize {
+    self.layers.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.layers.is_empty()
+  }
+
+  pub fn call(&self, ctx: &mut ConnCtx, query: &Msg<MsgX>, handler: &mut dyn FnMut(&mut ConnCtx, &Msg<MsgX>) -> Outcome<MsgX>) -> Outcome<MsgX> {
+    call_layers(&self.layers, ctx, query, handler)
+  }
+}
+
+impl<MsgX> Default for MiddlewareChain<MsgX> {
+  fn default() -> MiddlewareChain<MsgX> {
+    MiddlewareChain::new()
+  }
+}
+
+impl<MsgX> Clone for MiddlewareChain<MsgX> {
+  fn clone(&self) -> MiddlewareChain<MsgX> {
+    MiddlewareChain{layers: self.layers.clone()}
+  }
+}
+
+fn call_layers<MsgX>(layers: &[Arc<dyn Middleware<MsgX>>], ctx: &mut ConnCtx, query: &Msg<MsgX>, handler: &mut dyn FnMut(&mut ConnCtx, &Msg<MsgX>) -> Outcome<MsgX>) -> Outcome<MsgX> {
+  match layers.split_first() {
+    None => (handler)(ctx, query),
+    Some((mw, rest)) => {
+      mw.call(ctx, query, &mut |ctx, query| call_layers(rest, ctx, query, handler))
+    }
+  }
+}
+
+// A `Service` with a middleware chain around its `on_query`.
+pub struct Layered<MsgX, S> {
+  chain: Arc<MiddlewareChain<MsgX>>,
+  svc: S,
+}
+
+impl<MsgX, S: Service<MsgX>> Layered<MsgX, S> {
+  pub fn new(chain: Arc<MiddlewareChain<MsgX>>, svc: S) -> Layered<MsgX, S> {
+    Layered{chain, svc}
+  }
+
+  pub fn into_inner(self) -> S {
+    self.svc
+  }
+}
+
+impl<MsgX, S: Service<MsgX>> Service<MsgX> for Layered<MsgX, S> {
+  fn on_connect(&mut self, ctx: &mut ConnCtx) -> bool {
+    self.svc.on_connect(ctx)
+  }
+
+  fn on_query(&mut self, ctx: &mut ConnCtx, query: &Msg<MsgX>) -> Outcome<MsgX> {
+    let svc = &mut self.svc;
+    self.chain.call(ctx, query, &mut |ctx, query| svc.on_query(ctx, query))
+  }
+
+  fn on_error(&mut self, ctx: &mut ConnCtx, err: &ReplyErr) {
+    self.svc.on_error(ctx, err)
+  }
+
+  fn on_disconnect(&mut self, ctx: &mut ConnCtx) {
+    self.svc.on_disconnect(ctx)
+  }
+}
+
+fn tag_str(tag: &[u8; 3]) -> String {
+  String::from_utf8_lossy(tag).into_owned()
+}
+
+fn msg_name<MsgX>(msg: &Msg<MsgX>) -> &'static str {
+  match msg {
+    &Msg::Top => "Top",
+    &Msg::HUP => "HUP",
+    &Msg::OKQ => "OKQ",
+    &Msg::OKR => "OKR",
+    &Msg::H1Q(_) => "H1Q",
+    &Msg::H1P(_) => "H1P",
+    &Msg::JSO(_) => "JSO",
+    &Msg::Ext(_) => "Ext",
+    &Msg::Bot => "Bot",
+  }
+}
+
+// Logs a record per query (see `logging`) at the given level:
+//
+//     msg=query conn=<id> peer=<addr> seq=<seq> tag=<tag> outcome=<reply> us=<latency>
+//
+// A deferred outcome is waited for, so that it can be logged.
+pub struct AccessLog {
+  level: LogLevel,
+}
+
+impl AccessLog {
+  pub fn new(level: LogLevel) -> AccessLog {
+    AccessLog{level}
+  }
+}
+
+impl Default for AccessLog {
+  fn default() -> AccessLog {
+    AccessLog::new(LogLevel::Info)
+  }
+}
+
+impl<MsgX> Middleware<MsgX> for AccessLog {
+  fn call(&self, ctx: &mut ConnCtx, query: &Msg<MsgX>, next: &mut dyn FnMut(&mut ConnCtx, &Msg<MsgX>) -> Outcome<MsgX>) -> Outcome<MsgX> {
+    let t0 = Instant::now();
+    let outcome = (next)(ctx, query).resolve();
+    if !log_enabled(self.level) {
+      return outcome;
+    }
+    let dt = t0.elapsed();
+    let peer = match ctx.peer() {
+      &PeerAddr::Tcp(addr) => addr.to_string(),
+      &PeerAddr::Unix => "unix".to_string(),
+      &PeerAddr::Unknown => "-".to_string(),
+    };
+    let result = match &outcome {
+      &Outcome::Reply(ref reply) => msg_name(reply).to_string(),
+      &Outcome::ReplyClose(ref reply) => format!("{}+close", msg_name(reply)),
+      &Outcome::Close => "close".to_string(),
+      &Outcome::Defer(_) => panic!("bug: unresolved deferred outcome"),
+    };
+    log(self.level, "query", &[
+        ("conn", &ctx.id()),
+        ("peer", &peer),
+        ("seq", &ctx.seq()),
+        ("tag", &tag_str(&ctx.tag())),
+        ("outcome", &result),
+        ("us", &dt.as_micros()),
+    ]);
+    outcome
+  }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LatencyStats {
+  pub count: u64,
+  pub total: StdDuration,
+  pub max: StdDuration,
+}
+
+// Measures the time spent in the rest of the chain, including waiting
+// for deferred outcomes.
+pub struct Latency {
+  count: AtomicU64,
+  total_ns: AtomicU64,
+  max_ns: AtomicU64,
+}
+
+impl Latency {
+  pub fn new() -> Latency {
+    Latency{
+      count: AtomicU64::new(0),
+      total_ns: AtomicU64::new(0),
+      max_ns: AtomicU64::new(0),
+    }
+  }
+
+  pub fn stats(&self) -> LatencyStats {
+    LatencyStats{
+      count: self.count.load(AtomicOrdering::Relaxed),
+      total: StdDuration::from_nanos(self.total_ns.load(AtomicOrdering::Relaxed)),
+      max: StdDuration::from_nanos(self.max_ns.load(AtomicOrdering::Relaxed)),
+    }
+  }
+}
+
+impl Default for Latency {
+  fn default() -> Latency {
+    Latency::new()
+  }
+}
+
+impl<MsgX> Middleware<MsgX> for Latency {
+  fn call(&self, ctx: &mut ConnCtx, query: &Msg<MsgX>, next: &mut dyn FnMut(&mut ConnCtx, &Msg<MsgX>) -> Outcome<MsgX>) -> Outcome<MsgX> {
+    let t0 = Instant::now();
+    let outcome = (next)(ctx, query).resolve();
+    let ns = t0.elapsed().as_nanos().min(u64::max_value() as u128) as u64;
+    self.count.fetch_add(1, AtomicOrdering::Relaxed);
+    self.total_ns.fetch_add(ns, AtomicOrdering::Relaxed);
+    self.max_ns.fetch_max(ns, AtomicOrdering::Relaxed);
+    outcome
+  }
+}
+
+pub const AUTH_KEY: &'static str = "auth";
+
+// Marks an authenticated connection in its `ConnCtx`.
+#[derive(Clone, Copy, Debug)]
+pub struct Authenticated;
+
+// Requires the first query of a connection to be the auth query
+//
+//     JSO {"auth": "<secret>"}
+//
+// which is answered with `OKR`. Other queries on an unauthenticated
+// connection are answered with `Bot`; a wrong secret closes the
+// connection.
+pub struct SharedSecretAuth {
+  secret: Vec<u8>,
+}
+
+impl SharedSecretAuth {
+  pub fn new<S: AsRef<[u8]>>(secret: S) -> SharedSecretAuth {
+    SharedSecretAuth{secret: secret.as_ref().to_owned()}
+  }
+
+  pub fn auth_query<MsgX>(secret: &str) -> Msg<MsgX> {
+    let mut obj = BTreeMap::new();
+    obj.insert(AUTH_KEY.to_string(), Json::String(secret.to_string()));
+    Msg::JSO(Json::Object(obj))
+  }
+}
+
+impl<MsgX> Middleware<MsgX> for SharedSecretAuth {
+  fn call(&self, ctx: &mut ConnCtx, query: &Msg<MsgX>, next: &mut dyn FnMut(&mut ConnCtx, &Msg<MsgX>) -> Outcome<MsgX>) -> Outcome<MsgX> {
+    if ctx.get::<Authenticated>().is_some() {
+      return (next)(ctx, query);
+    }
+    let secret = match query {
+      &Msg::JSO(ref j) => j.find(AUTH_KEY).and_then(|s| s.as_string()),
+      _ => None
+    };
+    match secret {
+      None => Outcome::Reply(Msg::Bot),
+      Some(secret) => if constant_time_eq(secret.as_bytes(), &self.secret) {
+        ctx.insert(Authenticated);
+        Outcome::Reply(Msg::OKR)
+      } else {
+        Outcome::ReplyClose(Msg::Bot)
+      }
+    }
+  }
+}
+
+// Answers queries whose tag is not in the list with `Bot`.
+pub struct TagAllowList {
+  tags: Vec<[u8; 3]>,
+}
+
+impl TagAllowList {
+  pub fn new(tags: &[[u8; 3]]) -> TagAllowList {
+    TagAllowList{tags: tags.to_owned()}
+  }
+
+  pub fn allows(&self, tag: &[u8; 3]) -> bool {
+    self.tags.iter().any(|t| t == tag)
+  }
+}
+
+impl<MsgX> Middleware<MsgX> for TagAllowList {
+  fn call(&self, ctx: &mut ConnCtx, query: &Msg<MsgX>, next: &mut dyn FnMut(&mut ConnCtx, &Msg<MsgX>) -> Outcome<MsgX>) -> Outcome<MsgX> {
+    if !self.allows(&ctx.tag()) {
+      return Outcome::Reply(Msg::Bot);
+    }
+    (next)(ctx, query)
+  }
+}
@@ -3,6 +3,7 @@ use crate::http::*;
 use rustc_serialize::json::{Json};
 
 use std::convert::{TryFrom};
+use std::str::{from_utf8};
 
 #[derive(Debug)]
 #[non_exhaustive]
@@ -27,7 +28,77 @@ pub enum Msg<X=()> {
   Bot,
 }
 
+include!("reserved_tags.rs");
+
+// The wire tags of the built-in `Msg` variants, with their names.
+pub const BUILTIN_TAGS: &'static [([u8; 3], &'static str)] = RESERVED_BUILTIN_TAGS;
+
+pub fn builtin_tag_name(tag: &[u8; 3]) -> Option<&'static str> {
+  BUILTIN_TAGS.iter().find(|&&(t, _)| &t == tag).map(|&(_, name)| name)
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CodecErr {
+  // The value has no wire encoding.
+  Unsupported,
+  // The tag is not one of the type's wire tags.
+  Tag([u8; 3]),
+  Utf8,
+  Truncated,
+  Trailing,
+  Malformed(String),
+  // An error without details, from a `MsgCodex` impl.
+  Opaque,
+}
+
+// The codec of an extension message type. The payload is arbitrary
+// bytes; see `tag` for the tags that extension types may use.
+pub trait WireCodex: Sized {
+  // The wire tags of this type, checked against the reserved namespace
+  // when a `Chan` or `SpawnPool` is constructed.
+  fn wire_tags() -> Vec<[u8; 3]> {
+    Vec::new()
+  }
+
+  // The expected encoded payload length, used to reserve the send
+  // buffer; need not be exact.
+  fn wire_size_hint(&self) -> usize {
+    0
+  }
+
+  // Appends the payload to `buf`, returning the tag.
+  fn encode_bytes(&self, buf: &mut Vec<u8>) -> Result<[u8; 3], CodecErr>;
+  fn decode_bytes(tag: [u8; 3], buf: &[u8]) -> Result<Self, CodecErr>;
+}
+
+// Decoding that borrows from the payload, e.g. from the receive buffer
+// of a `Chan` (see `Chan::recv_raw`).
+pub trait WireDecodeRef<'a>: Sized {
+  fn decode_ref(tag: [u8; 3], buf: &'a [u8]) -> Result<Self, CodecErr>;
+}
+
+impl<'a> WireDecodeRef<'a> for &'a [u8] {
+  fn decode_ref(_tag: [u8; 3], buf: &'a [u8]) -> Result<&'a [u8], CodecErr> {
+    Ok(buf)
+  }
+}
+
+impl<'a> WireDecodeRef<'a> for &'a str {
+  fn decode_ref(_tag: [u8; 3], buf: &'a [u8]) -> Result<&'a str, CodecErr> {
+    from_utf8(buf).map_err(|_| CodecErr::Utf8)
+  }
+}
+
+// The original, text-only extension codec; every `MsgCodex` is also a
+// `WireCodex`.
 pub trait MsgCodex {
+  // The wire tags of this type, checked against the reserved namespace
+  // when a `Chan` or `SpawnPool` is constructed.
+  fn wire_tags() -> Vec<[u8; 3]> where Self: Sized {
+    Vec::new()
+  }
+
   fn encode_wire(&self, buf: &mut String) -> Result<[u8; 3], ()>;
   fn decode_wire(tag: [u8; 3], buf: &[u8]) -> Result<Self, ()> where Self: Sized;
 }
@@ -41,3 +112,68 @@ impl MsgCodex for () {
     Err(())
   }
 }
+
+impl<X: MsgCodex> WireCodex for X {
+  fn wire_tags() -> Vec<[u8; 3]> {
+    <X as MsgCodex>::wire_tags()
+  }
+
+  fn encode_bytes(&self, buf: &mut Vec<u8>) -> Result<[u8; 3], CodecErr> {
+    let mut s = String::new();
+    let tag = self.encode_wire(&mut s).map_err(|_| CodecErr::Opaque)?;
+    buf.extend_from_slice(s.as_bytes());
+    Ok(tag)
+  }
+
+  fn decode_bytes(tag: [u8; 3], buf: &[u8]) -> Result<X, CodecErr> {
+    X::decode_wire(tag, buf).map_err(|_| CodecErr::Opaque)
+  }
+}
+
+// Composes two extension types into one, e.g. `Msg<Either<Kv, Job>>`;
+// nest to compose more, e.g. `Either<Kv, Either<Job, Log>>`.
+//
+// Decoding dispatches on the tags declared by each side's `wire_tags`;
+// a tag declared by neither side is offered to `A`, then to `B`. Overlapping
+// declared tags are rejected when the composed type is registered (see
+// `tag::register_tags`).
+#[derive(Clone, Debug)]
+pub enum Either<A, B> {
+  Left(A),
+  Right(B),
+}
+
+impl<A: WireCodex, B: WireCodex> WireCodex for Either<A, B> {
+  fn wire_tags() -> Vec<[u8; 3]> {
+    let mut tags = A::wire_tags();
+    tags.extend(B::wire_tags());
+    tags
+  }
+
+  fn wire_size_hint(&self) -> usize {
+    match self {
+      &Either::Left(ref a) => a.wire_size_hint(),
+      &Either::Right(ref b) => b.wire_size_hint(),
+    }
+  }
+
+  fn encode_bytes(&self, buf: &mut Vec<u8>) -> Result<[u8; 3], CodecErr> {
+    match self {
+      &Either::Left(ref a) => a.encode_bytes(buf),
+      &Either::Right(ref b) => b.encode_bytes(buf),
+    }
+  }
+
+  fn decode_bytes(tag: [u8; 3], buf: &[u8]) -> Result<Either<A, B>, CodecErr> {
+    if A::wire_tags().contains(&tag) {
+      return A::decode_bytes(tag, buf).map(Either::Left);
+    }
+    if B::wire_tags().contains(&tag) {
+      return B::decode_bytes(tag, buf).map(Either::Right);
+    }
+    match A::decode_bytes(tag, buf) {
+      Ok(a) => Ok(Either::Left(a)),
+      Err(_) => B::decode_bytes(tag, buf).map(Either::Right)
+    }
+  }
+}
@@ -1,2 +1,4 @@
 pub use crate::http::*;
 pub use crate::msg::*;
+#[cfg(feature = "derive")]
+pub use service_base_derive::{MsgCodex};
@@ -0,0 +1,257 @@
+use smol_str::{SmolStr};
+
+use std::cmp::{max, min};
+use std::collections::{HashMap};
+use std::net::{IpAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::thread::{sleep};
+use std::time::{Duration as StdDuration, Instant};
+
+// Token-bucket parameters: a bucket holds up to `burst` tokens, refills
+// at `refill_per_sec` tokens per second, and each query takes a token.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+  pub burst: u32,
+  pub refill_per_sec: f64,
+}
+
+impl RateLimit {
+  // With no burst the bucket never holds a whole token, and without
+  // refill it never gets one back, so either would stall `Delay` forever.
+  fn check(&self) {
+    if self.burst == 0 {
+      panic!("bug: RateLimit: zero burst");
+    }
+    if self.refill_per_sec.is_nan() || self.refill_per_sec <= 0.0 {
+      panic!("bug: RateLimit: non-positive refill rate: {}", self.refill_per_sec);
+    }
+  }
+}
+
+pub struct TokenBucket {
+  limit: RateLimit,
+  tokens: f64,
+  last: Instant,
+}
+
+impl TokenBucket {
+  pub fn new(limit: RateLimit) -> TokenBucket {
+    limit.check();
+    TokenBucket{limit, tokens: limit.burst as f64, last: Instant::now()}
+  }
+
+  fn tokens_at(&self, now: Instant) -> f64 {
+    let dt = now.saturating_duration_since(self.last).as_secs_f64();
+    (self.tokens + dt * self.limit.refill_per_sec).min(self.limit.burst as f64)
+  }
+
+  fn refill(&mut self, now: Instant) {
+    self.tokens = self.tokens_at(now);
+    self.last = now;
+  }
+
+  fn is_full_at(&self, now: Instant) -> bool {
+    self.tokens_at(now) >= self.limit.burst as f64
+  }
+
+  // The time until a token is available, zero if one is available now.
+  fn wait(&self) -> StdDuration {
+    if self.tokens >= 1.0 {
+      return StdDuration::from_secs(0);
+    }
+    StdDuration::from_secs_f64((1.0 - self.tokens) / self.limit.refill_per_sec)
+  }
+
+  pub fn try_take(&mut self) -> Result<(), StdDuration> {
+    self.refill(Instant::now());
+    let wait = self.wait();
+    if wait > StdDuration::from_secs(0) {
+      return Err(wait);
+    }
+    self.tokens -= 1.0;
+    Ok(())
+  }
+}
+
+// What to do with a query over the limit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ThrottleAction {
+  // Wait for a token, then handle the query.
+  Delay,
+  // Reply with a `rate_limited` Bot without running the handler.
+  Reject,
+  // Close the connection without replying.
+  Disconnect,
+}
+
+// The peer that a per-peer limit applies to.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum PeerKey {
+  Ip(IpAddr),
+  // E.g. an authenticated user.
+  Cred(SmolStr),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitConfig {
+  pub per_conn: Option<RateLimit>,
+  pub per_peer: Option<RateLimit>,
+  pub action: ThrottleAction,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ThrottleStats {
+  pub delayed: u64,
+  pub rejected: u64,
+  pub disconnected: u64,
+}
+
+// The most peers with a bucket at a time.
+pub const MAX_PEERS: usize = 1024;
+
+// Shared by the connections of a `SpawnPool`; each connection also has
+// its own `ConnLimiter`.
+pub struct RateLimiter {
+  cfg: RateLimitConfig,
+  peers: Mutex<HashMap<PeerKey, TokenBucket>>,
+  delayed: AtomicU64,
+  rejected: AtomicU64,
+  disconnected: AtomicU64,
+}
+
+impl RateLimiter {
+  pub fn new(cfg: RateLimitConfig) -> RateLimiter {
+    if let Some(ref limit) = cfg.per_conn {
+      limit.check();
+    }
+    if let Some(ref limit) = cfg.per_peer {
+      limit.check();
+    }
+    RateLimiter{
+      cfg,
+      peers: Mutex::new(HashMap::new()),
+      delayed: AtomicU64::new(0),
+      rejected: AtomicU64::new(0),
+      disconnected: AtomicU64::new(0),
+    }
+  }
+
+  pub fn config(&self) -> &RateLimitConfig {
+    &self.cfg
+  }
+
+  // The number of peers with a bucket, at most `MAX_PEERS`.
+  pub fn peer_count(&self) -> usize {
+    self.peers.lock().unwrap().len()
+  }
+
+  pub fn stats(&self) -> ThrottleStats {
+    ThrottleStats{
+      delayed: self.delayed.load(AtomicOrdering::Relaxed),
+      rejected: self.rejected.load(AtomicOrdering::Relaxed),
+      disconnected: self.disconnected.load(AtomicOrdering::Relaxed),
+    }
+  }
+
+  pub(crate) fn count(&self, action: ThrottleAction) {
+    let ctr = match action {
+      ThrottleAction::Delay => &self.delayed,
+      ThrottleAction::Reject => &self.rejected,
+      ThrottleAction::Disconnect => &self.disconnected,
+    };
+    ctr.fetch_add(1, AtomicOrdering::Relaxed);
+  }
+
+  // Takes a token from both the connection and the peer buckets, or
+  // neither, returning the time until both have one.
+  fn try_take(&self, mut conn: Option<&mut TokenBucket>, peer: Option<&PeerKey>) -> Result<(), StdDuration> {
+    let now = Instant::now();
+    let mut peers = self.peers.lock().unwrap();
+    let mut peer_bucket = match (self.cfg.per_peer, peer) {
+      (Some(limit), Some(peer)) => {
+        if !peers.contains_key(peer) && peers.len() >= MAX_PEERS {
+          // Full buckets hold no state worth keeping.
+          peers.retain(|_, b| !b.is_full_at(now));
+          if peers.len() >= MAX_PEERS {
+            // Otherwise, evict the least recently used.
+            let lru = peers.iter().min_by_key(|&(_, b)| b.last).map(|(k, _)| k.clone()).unwrap();
+            peers.remove(&lru);
+          }
+        }
+        Some(peers.entry(peer.clone()).or_insert_with(|| TokenBucket::new(limit)))
+      }
+      _ => None
+    };
+    let mut wait = StdDuration::from_secs(0);
+    if let Some(b) = conn.as_mut() {
+      b.refill(now);
+      wait = max(wait, b.wait());
+    }
+    if let Some(b) = peer_bucket.as_mut() {
+      b.refill(now);
+      wait = max(wait, b.wait());
+    }
+    if wait > StdDuration::from_secs(0) {
+      return Err(wait);
+    }
+    if let Some(b) = conn {
+      b.tokens -= 1.0;
+    }
+    if let Some(b) = peer_bucket {
+      b.tokens -= 1.0;
+    }
+    Ok(())
+  }
+}
+
+// The per-connection side of a `RateLimiter`.
+pub struct ConnLimiter {
+  limiter: Arc<RateLimiter>,
+  conn: Option<TokenBucket>,
+  peer: Option<PeerKey>,
+}
+
+impl ConnLimiter {
+  pub fn new(limiter: Arc<RateLimiter>, peer: Option<PeerKey>) -> ConnLimiter {
+    let conn = limiter.cfg.per_conn.map(TokenBucket::new);
+    ConnLimiter{limiter, conn, peer}
+  }
+
+  pub fn set_peer(&mut self, peer: Option<PeerKey>) {
+    self.peer = peer;
+  }
+
+  // Admits a query, returning the action taken if it was over the
+  // limit. `Delay` waits no longer than the deadline of the query, so
+  // after `Delay`, the query is admitted unless its deadline has passed.
+  pub fn admit(&mut self, deadline: Option<Instant>) -> Option<ThrottleAction> {
+    let action = self.limiter.cfg.action;
+    let mut throttled = false;
+    loop {
+      match self.limiter.try_take(self.conn.as_mut(), self.peer.as_ref()) {
+        Ok(_) => break,
+        Err(wait) => {
+          if !throttled {
+            self.limiter.count(action);
+            throttled = true;
+          }
+          if action != ThrottleAction::Delay {
+            return Some(action);
+          }
+          match deadline {
+            None => sleep(wait),
+            Some(deadline) => {
+              let now = Instant::now();
+              if now >= deadline {
+                return Some(ThrottleAction::Delay);
+              }
+              sleep(min(wait, deadline - now));
+            }
+          }
+        }
+      }
+    }
+    if throttled { Some(ThrottleAction::Delay) } else { None }
+  }
+}
@@ -0,0 +1,25 @@
+// The reserved wire tags, with their `Msg` variant names. This file is
+// not a module: it is `include!`d by `msg` (for `msg::BUILTIN_TAGS` and
+// `tag::FUTURE_TAGS`) and by `service_base_derive`, which cannot depend
+// on this crate, so that both share one list.
+
+// The tags of the built-in `Msg` variants.
+pub(crate) const RESERVED_BUILTIN_TAGS: &'static [([u8; 3], &'static str)] = &[
+  (*b"...", "Top"),
+  (*b"HUP", "HUP"),
+  (*b"OK?", "OKQ"),
+  (*b"OK.", "OKR"),
+  (*b"H1?", "H1Q"),
+  (*b"H1.", "H1P"),
+  (*b"JSO", "JSO"),
+  (*b"!!!", "Bot"),
+];
+
+// The tags set aside for planned `Msg` variants.
+pub(crate) const RESERVED_FUTURE_TAGS: &'static [([u8; 3], &'static str)] = &[
+  (*b"XC?", "XCQ"),
+  (*b"XC.", "XCR"),
+  (*b"PV?", "PVQ"),
+  (*b"PV.", "PVR"),
+  (*b"NTP", "NTP"),
+];
@@ -0,0 +1,358 @@
+use crate::chan::*;
+use crate::msg::*;
+
+use std::cmp::{min};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep};
+use std::time::{Duration as StdDuration, Instant};
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+  // Total number of attempts, including the first one.
+  pub max_attempts: u32,
+  pub backoff_init: StdDuration,
+  pub backoff_max: StdDuration,
+  pub backoff_mul: u32,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> RetryPolicy {
+    RetryPolicy{
+      max_attempts: 3,
+      backoff_init: StdDuration::from_millis(50),
+      backoff_max: StdDuration::from_secs(2),
+      backoff_mul: 2,
+    }
+  }
+}
+
+impl RetryPolicy {
+  pub fn no_retry() -> RetryPolicy {
+    RetryPolicy{
+      max_attempts: 1,
+      .. RetryPolicy::default()
+    }
+  }
+
+  // The delay to sleep after the failed attempt `attempt` (counting
+  // from 1) and before the next one.
+  pub fn backoff(&self, attempt: u32) -> StdDuration {
+    let mut delay = self.backoff_init;
+    for _ in 1 .. attempt {
+      delay = match delay.checked_mul(self.backoff_mul) {
+        None => return self.backoff_max,
+        Some(d) => d
+      };
+      if delay >= self.backoff_max {
+        break;
+      }
+    }
+    min(delay, self.backoff_max)
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum BreakerState {
+  Closed,
+  Open,
+  HalfOpen,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BreakerConfig {
+  // Consecutive failures before the breaker opens.
+  pub failure_threshold: u32,
+  // How long the breaker stays open before half-opening to probe.
+  pub cooldown: StdDuration,
+  // The deadline of the probe; a probe that has not reported back by
+  // then counts as failed, so that a hung backend cannot hold the probe
+  // slot forever.
+  pub probe_timeout: StdDuration,
+}
+
+impl Default for BreakerConfig {
+  fn default() -> BreakerConfig {
+    BreakerConfig{
+      failure_threshold: 5,
+      cooldown: StdDuration::from_secs(10),
+      probe_timeout: StdDuration::from_secs(5),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BreakerStats {
+  pub state: BreakerState,
+  pub consecutive_failures: u32,
+  pub successes: u64,
+  pub failures: u64,
+  pub rejected: u64,
+  pub trips: u64,
+}
+
+struct BreakerInner {
+  state: BreakerState,
+  opened_at: Option<Instant>,
+  probing: bool,
+  probe_at: Option<Instant>,
+  consecutive_failures: u32,
+  successes: u64,
+  failures: u64,
+  rejected: u64,
+  trips: u64,
+}
+
+impl BreakerInner {
+  fn refresh(&mut self, cfg: &BreakerConfig) {
+    if self.state == BreakerState::Open {
+      match self.opened_at {
+        Some(t) if t.elapsed() < cfg.cooldown => {}
+        _ => {
+          self.state = BreakerState::HalfOpen;
+          self.probing = false;
+        }
+      }
+    }
+    if self.state == BreakerState::HalfOpen && self.probing {
+      match self.probe_at {
+        Some(t) if t.elapsed() < cfg.probe_timeout => {}
+        _ => self.trip()
+      }
+    }
+  }
+
+  fn trip(&mut self) {
+    self.state = BreakerState::Open;
+    self.opened_at = Some(Instant::now());
+    self.probing = false;
+    self.trips += 1;
+  }
+}
+
+// A circuit breaker meant to be shared (via `Arc`) between all clients
+// of the same backend.
+pub struct CircuitBreaker {
+  cfg: BreakerConfig,
+  inner: Mutex<BreakerInner>,
+}
+
+impl Default for CircuitBreaker {
+  fn default() -> CircuitBreaker {
+    CircuitBreaker::new(BreakerConfig::default())
+  }
+}
+
+impl CircuitBreaker {
+  pub fn new(cfg: BreakerConfig) -> CircuitBreaker {
+    CircuitBreaker{
+      cfg,
+      inner: Mutex::new(BreakerInner{
+        state: BreakerState::Closed,
+        opened_at: None,
+        probing: false,
+        probe_at: None,
+        consecutive_failures: 0,
+        successes: 0,
+        failures: 0,
+        rejected: 0,
+        trips: 0,
+      }),
+    }
+  }
+
+  pub fn config(&self) -> &BreakerConfig {
+    &self.cfg
+  }
+
+  pub fn state(&self) -> BreakerState {
+    let mut inner = self.inner.lock().unwrap();
+    inner.refresh(&self.cfg);
+    inner.state
+  }
+
+  pub fn stats(&self) -> BreakerStats {
+    let mut inner = self.inner.lock().unwrap();
+    inner.refresh(&self.cfg);
+    BreakerStats{
+      state: inner.state,
+      consecutive_failures: inner.consecutive_failures,
+      successes: inner.successes,
+      failures: inner.failures,
+      rejected: inner.rejected,
+      trips: inner.trips,
+    }
+  }
+
+  // Returns `true` if a query may be attempted. While half-open, only
+  // a single probe is let through at a time.
+  pub fn acquire(&self) -> bool {
+    self.admit().is_some()
+  }
+
+  // Like `acquire`, but says whether the query is the half-open probe,
+  // which should be sent with the `probe_timeout` deadline.
+  fn admit(&self) -> Option<bool> {
+    let mut inner = self.inner.lock().unwrap();
+    inner.refresh(&self.cfg);
+    match inner.state {
+      BreakerState::Closed => Some(false),
+      BreakerState::HalfOpen if !inner.probing => {
+        inner.probing = true;
+        inner.probe_at = Some(Instant::now());
+        Some(true)
+      }
+      _ => {
+        inner.rejected += 1;
+        None
+      }
+    }
+  }
+
+  pub fn on_success(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.successes += 1;
+    inner.consecutive_failures = 0;
+    inner.state = BreakerState::Closed;
+    inner.opened_at = None;
+    inner.probing = false;
+  }
+
+  pub fn on_failure(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.failures += 1;
+    inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+    match inner.state {
+      BreakerState::HalfOpen => {
+        inner.trip();
+      }
+      BreakerState::Closed => {
+        if inner.consecutive_failures >= self.cfg.failure_threshold {
+          inner.trip();
+        }
+      }
+      BreakerState::Open => {}
+    }
+  }
+
+  pub fn reset(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.state = BreakerState::Closed;
+    inner.opened_at = None;
+    inner.probing = false;
+    inner.consecutive_failures = 0;
+  }
+}
+
+// A reconnecting client: a failed query drops the underlying `Chan`,
+// and the next attempt dials a fresh connection.
+pub struct RetryChan<MsgX=()> {
+  addr: SocketAddr,
+  chan: Option<Chan<MsgX>>,
+  policy: RetryPolicy,
+  breaker: Arc<CircuitBreaker>,
+}
+
+impl<MsgX> RetryChan<MsgX> {
+  pub fn new<A: ToSocketAddrs>(addr: A, policy: RetryPolicy, breaker: Arc<CircuitBreaker>) -> Result<RetryChan<MsgX>, IoError> {
+    let addr = match addr.to_socket_addrs()?.next() {
+      None => return Err(IoError::new(IoErrorKind::InvalidInput, "no socket address")),
+      Some(a) => a
+    };
+    Ok(RetryChan{addr, chan: None, policy, breaker})
+  }
+
+  pub fn addr(&self) -> SocketAddr {
+    self.addr
+  }
+
+  pub fn policy(&self) -> &RetryPolicy {
+    &self.policy
+  }
+
+  pub fn breaker(&self) -> &Arc<CircuitBreaker> {
+    &self.breaker
+  }
+}
+
+impl<MsgX: WireCodex> RetryChan<MsgX> {
+  // Queries at most once; the query is not assumed to be safe to
+  // repeat.
+  pub fn query(&mut self, query: &Msg<MsgX>) -> Result<Msg<MsgX>, QueryErr> {
+    self.query_once(query)
+  }
+
+  // Queries with retries per the `RetryPolicy`. Only use this for
+  // queries that are safe to execute more than once.
+  pub fn query_idempotent(&mut self, query: &Msg<MsgX>) -> Result<Msg<MsgX>, QueryErr> {
+    let max_attempts = self.policy.max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+      match self.query_once(query) {
+        Ok(reply) => return Ok(reply),
+        Err(QueryErr::Open) => return Err(QueryErr::Open),
+        Err(QueryErr::Deadline) => return Err(QueryErr::Deadline),
+        // An answer from the handler; asking again gets the same one.
+        Err(QueryErr::Bot(details)) => return Err(QueryErr::Bot(details)),
+        Err(e) => {
+          if attempt >= max_attempts {
+            return Err(e);
+          }
+        }
+      }
+      sleep(self.policy.backoff(attempt));
+      attempt += 1;
+    }
+  }
+
+  fn query_once(&mut self, query: &Msg<MsgX>) -> Result<Msg<MsgX>, QueryErr> {
+    let probe = match self.breaker.admit() {
+      None => return Err(QueryErr::Open),
+      Some(probe) => probe
+    };
+    // The probe, connect included, must finish within the probe timeout,
+    // so that a black-holed backend cannot keep the probe slot.
+    let t0 = Instant::now();
+    if self.chan.is_none() {
+      let res = if probe {
+        TcpStream::connect_timeout(&self.addr, self.breaker.cfg.probe_timeout).map(Chan::<MsgX>::new)
+      } else {
+        Chan::connect(self.addr)
+      };
+      match res {
+        Err(_) => {
+          self.breaker.on_failure();
+          return Err(QueryErr::Connect);
+        }
+        Ok(chan) => {
+          self.chan = Some(chan);
+        }
+      }
+    }
+    let chan = self.chan.as_mut().unwrap();
+    let res = if probe {
+      chan.query_timeout(query, self.breaker.cfg.probe_timeout.saturating_sub(t0.elapsed()))
+    } else {
+      chan.query(query)
+    };
+    match res {
+      // The backend is up and answered; the handler failing is not a
+      // backend failure.
+      Err(QueryErr::Bot(details)) => {
+        self.breaker.on_success();
+        Err(QueryErr::Bot(details))
+      }
+      Err(e) => {
+        self.chan = None;
+        self.breaker.on_failure();
+        Err(e)
+      }
+      Ok(reply) => {
+        self.breaker.on_success();
+        Ok(reply)
+      }
+    }
+  }
+}
@@ -1,4 +1,6 @@
 use crate::http::{HttpRequest, HttpResponse};
+use crate::logging::{LogLevel, log, log_enabled};
+use crate::metrics::{Histogram, metrics};
 
 use constant_time_eq::{constant_time_eq};
 pub use http1::Method::{GET, POST, PUT};
@@ -8,6 +10,7 @@ use smol_str::{SmolStr};
 
 use std::collections::{BTreeMap};
 use std::convert::{TryFrom, TryInto};
+use std::time::{Instant};
 
 #[derive(Clone, Copy, Debug)]
 #[repr(u8)]
@@ -294,6 +297,30 @@ impl RoutePath {
     RoutePath{parts}
   }
 
+  // The rule as a path pattern, e.g. `/user/{id}`, for logs and metric
+  // labels; binary parts are elided, as they may be secrets.
+  pub fn pattern(&self) -> String {
+    let mut s = String::new();
+    for p in self.parts.iter() {
+      s.push('/');
+      match p {
+        &Pat::Lit(v) => s.push_str(v),
+        &Pat::Str(ref v) => s.push_str(v),
+        &Pat::U64(x) => s.push_str(&x.to_string()),
+        &Pat::Base64(_) => s.push('*'),
+        &Pat::Sub(k, _) => {
+          s.push('{');
+          s.push_str(k);
+          s.push('}');
+        }
+      }
+    }
+    if s.is_empty() {
+      s.push('/');
+    }
+    s
+  }
+
   pub fn match_<S: AsRef<str>>(&self, parts: &[S], args: &mut RouteArgs) -> Option<()> {
     let rule_len = self.parts.len();
     if rule_len > parts.len() {
@@ -355,11 +382,56 @@ impl RoutePath {
   }
 }
 
+impl RoutePath {
+  // Whether the part at `i` is a parameter, or a binary part; either may
+  // be a secret.
+  fn is_param(&self, i: usize) -> bool {
+    matches!(self.parts.get(i), Some(&Pat::Sub(..)) | Some(&Pat::Base64(_)))
+  }
+
+  // Whether `parts` matches the literal parts of the rule, leaving out
+  // its parameters (and binary parts).
+  fn match_literals<S: AsRef<str>>(&self, parts: &[S]) -> bool {
+    self.parts.iter().zip(parts.iter()).all(|(p, v)| {
+      let v = v.as_ref();
+      match p {
+        &Pat::Lit(v0) => v0 == v,
+        &Pat::Str(ref v0) => v0 == v,
+        &Pat::U64(v0) => v.parse() == Ok(v0),
+        &Pat::Base64(_) | &Pat::Sub(..) => true,
+      }
+    })
+  }
+}
+
+// The length of a request path, without trailing empty segments.
+fn path_len<S: AsRef<str>>(parts: &[S]) -> usize {
+  parts.iter().rposition(|v| !v.as_ref().is_empty()).map(|i| i + 1).unwrap_or(0)
+}
+
+fn method_name(method: Method) -> &'static str {
+  if method == GET {
+    "GET"
+  } else if method == POST {
+    "POST"
+  } else if method == PUT {
+    "PUT"
+  } else {
+    "other"
+  }
+}
+
 pub type RouteArgs = BTreeMap<&'static str, Val>;
 pub type RouteFire = Box<dyn 'static + Send + Sync + Fn(&[Pat], &RouteArgs, &HttpRequest) -> Option<HttpResponse>>;
 
+struct RouteEntry {
+  fire: RouteFire,
+  // The handle is kept, as looking it up takes the registry lock.
+  latency: Histogram,
+}
+
 pub struct Router {
-  rules: BTreeMap<usize, BTreeMap<(RoutePort, Method, RoutePath), RouteFire>>,
+  rules: BTreeMap<usize, BTreeMap<(RoutePort, Method, RoutePath), RouteEntry>>,
 }
 
 impl Router {
@@ -381,14 +453,17 @@ impl Router {
     let rule = rule.into();
     let rule_len = rule.parts.len();
     //let fire = fire.into();
+    let latency = metrics().histogram("router_route_duration_seconds", "Time in route handlers, by route.",
+        &[("method", method_name(method)), ("route", &rule.pattern())]);
+    let entry = RouteEntry{fire, latency};
     match self.rules.get_mut(&rule_len) {
       None => {
         let mut rules = BTreeMap::new();
-        rules.insert((port, method, rule), fire);
+        rules.insert((port, method, rule), entry);
         self.rules.insert(rule_len, rules);
       }
       Some(rules) => {
-        rules.insert((port, method, rule), fire);
+        rules.insert((port, method, rule), entry);
       }
     }
   }
@@ -413,19 +488,39 @@ impl Router {
     }
   }
 
+  // A request path for logs, e.g. of a route miss. The segments that the
+  // rules of the same length declare as parameters (or binary parts) are
+  // elided, as they may be secrets; a rule only counts if the path
+  // matches its literal parts. Other segments are kept as they are.
+  pub fn elide_path<S: AsRef<str>>(&self, parts: &[S]) -> String {
+    let rules: Vec<&RoutePath> = match self.rules.get(&path_len(parts)) {
+      None => Vec::new(),
+      Some(rules) => rules.keys()
+        .map(|&(_, _, ref rule)| rule)
+        .filter(|rule| rule.match_literals(parts))
+        .collect()
+    };
+    let mut s = String::new();
+    for (i, p) in parts.iter().enumerate() {
+      s.push('/');
+      if rules.iter().any(|rule| rule.is_param(i)) {
+        s.push('*');
+      } else {
+        s.push_str(p.as_ref());
+      }
+    }
+    if s.is_empty() {
+      s.push('/');
+    }
+    s
+  }
+
   pub fn match_(&self, q_port: u16, req: &HttpRequest) -> Result<Option<HttpResponse>, MatchErr> {
     // TODO TODO: payload.
     // FIXME: optional trailing-'/' stripping.
-    let mut q_pathlen = req.path.len();
-    for i in (0 .. req.path.len()).rev() {
-      let v = &req.path[i];
-      if v.len() > 0 {
-        break;
-      }
-      q_pathlen -= 1;
-    }
+    let q_pathlen = path_len(&req.path);
     if let Some(rules) = self.rules.get(&q_pathlen) {
-      for (&(port, method, ref rule), fire) in rules.iter() {
+      for (&(port, method, ref rule), entry) in rules.iter() {
         match port.match_(q_port) {
           Ok(_) => {}
           Err(MatchErr::RedirectHttps443) => {
@@ -440,11 +535,19 @@ impl Router {
         }
         let mut args = BTreeMap::new();
         if rule.match_(&req.path, &mut args).is_some() {
-          let rep = (fire)(&rule.parts, &args, req);
+          let t0 = Instant::now();
+          let rep = (entry.fire)(&rule.parts, &args, req);
+          entry.latency.observe_duration(t0.elapsed());
           return Ok(rep);
         }
       }
     }
+    if log_enabled(LogLevel::Debug) {
+      log(LogLevel::Debug, "route miss", &[
+          ("method", &method_name(req.method)),
+          ("path", &self.elide_path(&req.path)),
+      ]);
+    }
     Ok(None)
   }
 }
@@ -0,0 +1,391 @@
+use crate::msg::*;
+
+use rustc_serialize::json::{Json};
+
+use std::collections::{BTreeMap};
+
+// A subset of JSON Schema: types, required keys, properties (optionally
+// closed), array items, enums, numeric ranges, string lengths, and
+// array sizes.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SchemaType {
+  Null,
+  Boolean,
+  Integer,
+  Number,
+  String,
+  Array,
+  Object,
+}
+
+impl SchemaType {
+  pub fn from_str(s: &str) -> Option<SchemaType> {
+    Some(match s {
+      "null" => SchemaType::Null,
+      "boolean" => SchemaType::Boolean,
+      "integer" => SchemaType::Integer,
+      "number" => SchemaType::Number,
+      "string" => SchemaType::String,
+      "array" => SchemaType::Array,
+      "object" => SchemaType::Object,
+      _ => return None
+    })
+  }
+
+  pub fn as_str(self) -> &'static str {
+    match self {
+      SchemaType::Null => "null",
+      SchemaType::Boolean => "boolean",
+      SchemaType::Integer => "integer",
+      SchemaType::Number => "number",
+      SchemaType::String => "string",
+      SchemaType::Array => "array",
+      SchemaType::Object => "object",
+    }
+  }
+
+  pub fn match_(self, j: &Json) -> bool {
+    match (self, j) {
+      (SchemaType::Null, &Json::Null) => true,
+      (SchemaType::Boolean, &Json::Boolean(_)) => true,
+      (SchemaType::Integer, &Json::I64(_)) |
+      (SchemaType::Integer, &Json::U64(_)) => true,
+      (SchemaType::Integer, &Json::F64(x)) => x.fract() == 0.0,
+      (SchemaType::Number, &Json::I64(_)) |
+      (SchemaType::Number, &Json::U64(_)) |
+      (SchemaType::Number, &Json::F64(_)) => true,
+      (SchemaType::String, &Json::String(_)) => true,
+      (SchemaType::Array, &Json::Array(_)) => true,
+      (SchemaType::Object, &Json::Object(_)) => true,
+      _ => false
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct Schema {
+  // Any type if empty.
+  pub types: Vec<SchemaType>,
+  pub required: Vec<String>,
+  pub properties: BTreeMap<String, Schema>,
+  pub additional_properties: bool,
+  pub items: Option<Box<Schema>>,
+  pub enum_: Option<Vec<Json>>,
+  pub minimum: Option<f64>,
+  pub maximum: Option<f64>,
+  // The length of a string, in chars.
+  pub min_length: Option<usize>,
+  pub max_length: Option<usize>,
+  // The number of items in an array.
+  pub min_items: Option<usize>,
+  pub max_items: Option<usize>,
+}
+
+impl Default for Schema {
+  fn default() -> Schema {
+    Schema::any()
+  }
+}
+
+impl Schema {
+  pub fn any() -> Schema {
+    Schema{
+      types: Vec::new(),
+      required: Vec::new(),
+      properties: BTreeMap::new(),
+      additional_properties: true,
+      items: None,
+      enum_: None,
+      minimum: None,
+      maximum: None,
+      min_length: None,
+      max_length: None,
+      min_items: None,
+      max_items: None,
+    }
+  }
+
+  pub fn new(ty: SchemaType) -> Schema {
+    let mut schema = Schema::any();
+    schema.types.push(ty);
+    schema
+  }
+
+  pub fn or_type(mut self, ty: SchemaType) -> Schema {
+    self.types.push(ty);
+    self
+  }
+
+  pub fn required(mut self, key: &str) -> Schema {
+    self.required.push(key.to_string());
+    self
+  }
+
+  pub fn property(mut self, key: &str, schema: Schema) -> Schema {
+    self.properties.insert(key.to_string(), schema);
+    self
+  }
+
+  // Rejects object keys not listed in `properties`.
+  pub fn closed(mut self) -> Schema {
+    self.additional_properties = false;
+    self
+  }
+
+  pub fn items(mut self, schema: Schema) -> Schema {
+    self.items = Some(Box::new(schema));
+    self
+  }
+
+  pub fn enum_values(mut self, vals: Vec<Json>) -> Schema {
+    self.enum_ = Some(vals);
+    self
+  }
+
+  pub fn range(mut self, minimum: Option<f64>, maximum: Option<f64>) -> Schema {
+    self.minimum = minimum;
+    self.maximum = maximum;
+    self
+  }
+
+  pub fn length(mut self, min_length: Option<usize>, max_length: Option<usize>) -> Schema {
+    self.min_length = min_length;
+    self.max_length = max_length;
+    self
+  }
+
+  pub fn item_count(mut self, min_items: Option<usize>, max_items: Option<usize>) -> Schema {
+    self.min_items = min_items;
+    self.max_items = max_items;
+    self
+  }
+
+  // Parses a schema written as JSON Schema, using the keywords: `type`,
+  // `required`, `properties`, `additionalProperties` (a boolean), `items`,
+  // `enum`, `minimum`, `maximum`, `minLength`, `maxLength`, `minItems`,
+  // and `maxItems`. Other keywords are rejected rather than ignored.
+  pub fn from_json(j: &Json) -> Result<Schema, String> {
+    let obj = j.as_object().ok_or_else(|| "schema must be an object".to_string())?;
+    let mut schema = Schema::any();
+    for (k, v) in obj.iter() {
+      match k.as_str() {
+        "type" => {
+          let tys: Vec<&Json> = match v {
+            &Json::Array(ref tys) => tys.iter().collect(),
+            _ => vec![v]
+          };
+          for ty in tys.into_iter() {
+            let ty = ty.as_string().and_then(SchemaType::from_str)
+              .ok_or_else(|| format!("invalid type: {}", ty))?;
+            schema.types.push(ty);
+          }
+        }
+        "required" => {
+          let keys = v.as_array().ok_or_else(|| "required must be an array".to_string())?;
+          for key in keys.iter() {
+            let key = key.as_string().ok_or_else(|| "required must be an array of strings".to_string())?;
+            schema.required.push(key.to_string());
+          }
+        }
+        "properties" => {
+          let props = v.as_object().ok_or_else(|| "properties must be an object".to_string())?;
+          for (key, sub) in props.iter() {
+            schema.properties.insert(key.clone(), Schema::from_json(sub)?);
+          }
+        }
+        "additionalProperties" => {
+          schema.additional_properties = v.as_boolean()
+            .ok_or_else(|| "additionalProperties must be a boolean".to_string())?;
+        }
+        "items" => {
+          schema.items = Some(Box::new(Schema::from_json(v)?));
+        }
+        "enum" => {
+          let vals = v.as_array().ok_or_else(|| "enum must be an array".to_string())?;
+          schema.enum_ = Some(vals.clone());
+        }
+        "minimum" => {
+          schema.minimum = Some(v.as_f64().ok_or_else(|| "minimum must be a number".to_string())?);
+        }
+        "maximum" => {
+          schema.maximum = Some(v.as_f64().ok_or_else(|| "maximum must be a number".to_string())?);
+        }
+        "minLength" => schema.min_length = Some(count(k, v)?),
+        "maxLength" => schema.max_length = Some(count(k, v)?),
+        "minItems" => schema.min_items = Some(count(k, v)?),
+        "maxItems" => schema.max_items = Some(count(k, v)?),
+        _ => return Err(format!("unsupported keyword: {}", k))
+      }
+    }
+    Ok(schema)
+  }
+
+  pub fn validate(&self, j: &Json) -> Result<(), SchemaErr> {
+    let mut err = SchemaErr{violations: Vec::new()};
+    let mut path = "$".to_string();
+    self.validate_at(j, &mut path, &mut err.violations);
+    if err.violations.is_empty() {
+      Ok(())
+    } else {
+      Err(err)
+    }
+  }
+
+  fn validate_at(&self, j: &Json, path: &mut String, out: &mut Vec<SchemaViolation>) {
+    let mut violation = |path: &str, message: String| {
+      out.push(SchemaViolation{path: path.to_string(), message});
+    };
+    if !self.types.is_empty() && !self.types.iter().any(|ty| ty.match_(j)) {
+      let tys: Vec<_> = self.types.iter().map(|ty| ty.as_str()).collect();
+      violation(path, format!("expected {}", tys.join(" or ")));
+      // The remaining checks would only add noise.
+      return;
+    }
+    if let Some(ref vals) = self.enum_ {
+      if !vals.iter().any(|v| json_eq(v, j)) {
+        let vals: Vec<_> = vals.iter().map(|v| v.to_string()).collect();
+        violation(path, format!("expected one of {}", vals.join(", ")));
+      }
+    }
+    if let Some(x) = j.as_f64() {
+      if let Some(min) = self.minimum {
+        if x < min {
+          violation(path, format!("expected at least {}", min));
+        }
+      }
+      if let Some(max) = self.maximum {
+        if x > max {
+          violation(path, format!("expected at most {}", max));
+        }
+      }
+    }
+    if let &Json::String(ref s) = j {
+      let len = s.chars().count();
+      if let Some(min) = self.min_length {
+        if len < min {
+          violation(path, format!("expected a length of at least {}", min));
+        }
+      }
+      if let Some(max) = self.max_length {
+        if len > max {
+          violation(path, format!("expected a length of at most {}", max));
+        }
+      }
+    }
+    if let &Json::Array(ref a) = j {
+      if let Some(min) = self.min_items {
+        if a.len() < min {
+          violation(path, format!("expected at least {} items", min));
+        }
+      }
+      if let Some(max) = self.max_items {
+        if a.len() > max {
+          violation(path, format!("expected at most {} items", max));
+        }
+      }
+    }
+    match j {
+      &Json::Object(ref obj) => {
+        for key in self.required.iter() {
+          if !obj.contains_key(key) {
+            violation(path, format!("missing required key {:?}", key));
+          }
+        }
+        for (key, v) in obj.iter() {
+          let prev_len = path.len();
+          push_key(path, key);
+          match self.properties.get(key) {
+            Some(sub) => sub.validate_at(v, path, out),
+            None if !self.additional_properties => {
+              out.push(SchemaViolation{path: path.clone(), message: "unexpected key".to_string()});
+            }
+            None => {}
+          }
+          path.truncate(prev_len);
+        }
+      }
+      &Json::Array(ref items) => {
+        if let Some(ref sub) = self.items {
+          for (i, v) in items.iter().enumerate() {
+            let prev_len = path.len();
+            path.push_str(&format!("[{}]", i));
+            sub.validate_at(v, path, out);
+            path.truncate(prev_len);
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+}
+
+fn count(k: &str, v: &Json) -> Result<usize, String> {
+  v.as_u64().map(|n| n as usize).ok_or_else(|| format!("{} must be a non-negative integer", k))
+}
+
+fn push_key(path: &mut String, key: &str) {
+  let plain = !key.is_empty() &&
+      key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') &&
+      !key.as_bytes()[0].is_ascii_digit();
+  if plain {
+    path.push('.');
+    path.push_str(key);
+  } else {
+    path.push_str(&format!("[{:?}]", key));
+  }
+}
+
+// Numbers compare by value, e.g. `1` (parsed as `U64`) equals `1.0`.
+fn json_eq(a: &Json, b: &Json) -> bool {
+  match (a, b) {
+    (&Json::I64(x), &Json::U64(y)) |
+    (&Json::U64(y), &Json::I64(x)) => x >= 0 && x as u64 == y,
+    (&Json::F64(_), _) | (_, &Json::F64(_)) if a.is_number() && b.is_number() => {
+      a.as_f64() == b.as_f64()
+    }
+    _ => a == b
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct SchemaViolation {
+  // E.g. `$.items[2].name`.
+  pub path: String,
+  pub message: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct SchemaErr {
+  pub violations: Vec<SchemaViolation>,
+}
+
+impl SchemaErr {
+  // E.g. `[{"path": "$.name", "message": "expected string"}]`.
+  pub fn to_json(&self) -> Json {
+    Json::Array(self.violations.iter().map(|v| {
+      let mut obj = BTreeMap::new();
+      obj.insert("path".to_string(), Json::String(v.path.clone()));
+      obj.insert("message".to_string(), Json::String(v.message.clone()));
+      Json::Object(obj)
+    }).collect())
+  }
+}
+
+// Wraps a handler so that `Msg::JSO` queries are validated against the
+// schema before the handler runs. An invalid query is answered with
+// `{"error": "schema", "violations": [...]}` (see `SchemaErr::to_json`).
+pub fn validated<MsgX, P>(schema: Schema, proc_: P) -> impl Fn(&Msg<MsgX>) -> Msg<MsgX>
+where P: Fn(&Msg<MsgX>) -> Msg<MsgX> {
+  move |query: &Msg<MsgX>| {
+    if let &Msg::JSO(ref j) = query {
+      if let Err(e) = schema.validate(j) {
+        let mut obj = BTreeMap::new();
+        obj.insert("error".to_string(), Json::String("schema".to_string()));
+        obj.insert("violations".to_string(), e.to_json());
+        return Msg::JSO(Json::Object(obj));
+      }
+    }
+    (proc_)(query)
+  }
+}
@@ -0,0 +1,115 @@
+use crate::chan::*;
+use crate::ctx::{ConnCtx};
+use crate::msg::*;
+
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+
+// A connection handler with lifecycle hooks. Each connection gets its
+// own `Service` from a `ServiceFactory`, so the service may keep mutable
+// per-connection state.
+pub trait Service<MsgX=()> {
+  // Returns false to close the connection right away.
+  fn on_connect(&mut self, _ctx: &mut ConnCtx) -> bool {
+    true
+  }
+
+  fn on_query(&mut self, ctx: &mut ConnCtx, query: &Msg<MsgX>) -> Outcome<MsgX>;
+
+  // Called on the error that ends the connection, e.g. a malformed
+  // frame; not called on I/O errors, as when the peer closes.
+  fn on_error(&mut self, _ctx: &mut ConnCtx, _err: &ReplyErr) {
+  }
+
+  fn on_disconnect(&mut self, _ctx: &mut ConnCtx) {
+  }
+}
+
+pub trait ServiceFactory<MsgX=()>: Send + Sync {
+  type Service: Service<MsgX>;
+
+  fn new_service(&self, ctx: &ConnCtx) -> Self::Service;
+}
+
+impl<MsgX, S, F> ServiceFactory<MsgX> for F
+where S: Service<MsgX>,
+      F: Send + Sync + Fn(&ConnCtx) -> S,
+{
+  type Service = S;
+
+  fn new_service(&self, ctx: &ConnCtx) -> S {
+    (self)(ctx)
+  }
+}
+
+// A `Service` with only an `on_query` hook.
+pub struct FnService<P>(pub P);
+
+impl<MsgX, R, P> Service<MsgX> for FnService<P>
+where R: Into<Outcome<MsgX>>,
+      P: FnMut(&mut ConnCtx, &Msg<MsgX>) -> R,
+{
+  fn on_query(&mut self, ctx: &mut ConnCtx, query: &Msg<MsgX>) -> Outcome<MsgX> {
+    (self.0)(ctx, query).into()
+  }
+}
+
+// What a handler does with a query. A handler returning a plain `Msg`
+// replies and continues.
+pub enum Outcome<MsgX=()> {
+  Reply(Msg<MsgX>),
+  // Replies, then closes the connection.
+  ReplyClose(Msg<MsgX>),
+  // Closes the connection without replying.
+  Close,
+  // The outcome is completed later through a `Replier`, e.g. from another
+  // thread; the connection waits for it before reading the next query.
+  Defer(Deferred<MsgX>),
+}
+
+impl<MsgX> From<Msg<MsgX>> for Outcome<MsgX> {
+  fn from(reply: Msg<MsgX>) -> Outcome<MsgX> {
+    Outcome::Reply(reply)
+  }
+}
+
+impl<MsgX> Outcome<MsgX> {
+  // Returns `Outcome::Defer` for the handler to return, and the
+  // `Replier` to complete it with.
+  pub fn defer() -> (Outcome<MsgX>, Replier<MsgX>) {
+    let (tx, rx) = sync_channel(1);
+    (Outcome::Defer(Deferred(rx)), Replier(tx))
+  }
+
+  // Waits for a deferred outcome; a `Replier` dropped without completing
+  // closes the connection.
+  pub(crate) fn resolve(self) -> Outcome<MsgX> {
+    let mut outcome = self;
+    while let Outcome::Defer(Deferred(rx)) = outcome {
+      outcome = rx.recv().unwrap_or(Outcome::Close);
+    }
+    outcome
+  }
+}
+
+pub struct Deferred<MsgX=()>(Receiver<Outcome<MsgX>>);
+
+pub struct Replier<MsgX=()>(SyncSender<Outcome<MsgX>>);
+
+impl<MsgX> Replier<MsgX> {
+  pub fn complete(self, outcome: Outcome<MsgX>) {
+    // The connection may have gone away in the meantime.
+    let _ = self.0.send(outcome);
+  }
+
+  pub fn reply(self, reply: Msg<MsgX>) {
+    self.complete(Outcome::Reply(reply))
+  }
+
+  pub fn reply_close(self, reply: Msg<MsgX>) {
+    self.complete(Outcome::ReplyClose(reply))
+  }
+
+  pub fn close(self) {
+    self.complete(Outcome::Close)
+  }
+}
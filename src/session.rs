@@ -0,0 +1,370 @@
+use crate::chan::*;
+use crate::msg::*;
+use crate::retry::{RetryPolicy};
+use crate::trace::{gen_id64};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian as LE};
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{sleep};
+use std::time::{Duration as StdDuration, Instant};
+
+// Session resumption gives at-most-once execution of queries across
+// reconnects. The handshake is the first frame of a connection, with
+// seq 0:
+//
+//     client: tag `~S?`, payload: session_id: u128, last_ack: u64
+//     server: tag `~S.`, payload: session_id: u128, last_seq: u64
+//
+// where a zero session id asks for a new session, `last_ack` is the seq
+// of the last reply the client received, and `last_seq` is the seq of
+// the last query the server started in the session. Both ends then
+// continue the seqs from `last_ack`. The server keeps the last few
+// replies of each session, and answers a query that it has already
+// started with the cached reply instead of running the handler again;
+// if the handler is still running (e.g. on the connection that dropped),
+// the answer waits for it. Replies are cached before they are written,
+// so a dropped connection never loses the reply of a query that ran.
+//
+// A server that has lost the session (e.g. it restarted, or the session
+// idled out) answers with a different session id.
+
+pub const SESSION_RESUME_TAG: [u8; 3] = *b"~S?";
+pub const SESSION_ACCEPT_TAG: [u8; 3] = *b"~S.";
+
+pub type SessionId = u128;
+
+pub(crate) fn encode_session_hdr(id: SessionId, seq: u64) -> Vec<u8> {
+  let mut buf = Vec::with_capacity(24);
+  buf.write_u64::<LE>(id as u64).unwrap();
+  buf.write_u64::<LE>((id >> 64) as u64).unwrap();
+  buf.write_u64::<LE>(seq).unwrap();
+  buf
+}
+
+pub(crate) fn decode_session_hdr(buf: &[u8]) -> Result<(SessionId, u64), RecvErr> {
+  if buf.len() != 24 {
+    return Err(RecvErr::Truncated);
+  }
+  let mut r = Cursor::new(buf);
+  let lo = r.read_u64::<LE>().map_err(|_| RecvErr::Truncated)?;
+  let hi = r.read_u64::<LE>().map_err(|_| RecvErr::Truncated)?;
+  let seq = r.read_u64::<LE>().map_err(|_| RecvErr::Truncated)?;
+  Ok((((hi as u128) << 64) | (lo as u128), seq))
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SessionConfig {
+  // The number of replies cached per session; a synchronous client
+  // needs only the last one.
+  pub reply_cache_len: usize,
+  // Sessions without a connection for this long are dropped.
+  pub idle_timeout: StdDuration,
+  pub max_sessions: usize,
+}
+
+impl Default for SessionConfig {
+  fn default() -> SessionConfig {
+    SessionConfig{
+      reply_cache_len: 16,
+      idle_timeout: StdDuration::from_secs(600),
+      max_sessions: 10_000,
+    }
+  }
+}
+
+pub(crate) struct CachedReply {
+  pub seq: u64,
+  pub tag: [u8; 3],
+  pub payload: Vec<u8>,
+}
+
+pub(crate) struct SessionState {
+  pub last_seq: u64,
+  // The seq of the query whose handler is running, if any.
+  pub running: Option<u64>,
+  pub replies: VecDeque<CachedReply>,
+  last_used: Instant,
+}
+
+impl SessionState {
+  pub fn cached(&self, seq: u64) -> Option<&CachedReply> {
+    self.replies.iter().find(|r| r.seq == seq)
+  }
+}
+
+pub(crate) struct Session {
+  state: Mutex<SessionState>,
+  // Notified when a running query finishes.
+  done: Condvar,
+}
+
+impl Session {
+  pub fn lock(&self) -> MutexGuard<SessionState> {
+    self.state.lock().unwrap()
+  }
+
+  // Marks `seq` as running, before its handler is called; returns false
+  // if the query has already been started.
+  fn begin(&self, seq: u64) -> bool {
+    let mut s = self.lock();
+    if seq <= s.last_seq {
+      return false;
+    }
+    s.last_seq = seq;
+    s.running = Some(seq);
+    true
+  }
+
+  // Marks `seq` as no longer running, whether or not it was answered.
+  fn finish(&self, seq: u64) {
+    let mut s = self.lock();
+    if s.running == Some(seq) {
+      s.running = None;
+      self.done.notify_all();
+    }
+  }
+
+  // Waits until `seq` is not running, then returns its cached reply.
+  pub fn wait_cached(&self, seq: u64) -> Option<([u8; 3], Vec<u8>)> {
+    let mut s = self.lock();
+    while s.running == Some(seq) {
+      s = self.done.wait(s).unwrap();
+    }
+    s.cached(seq).map(|r| (r.tag, r.payload.clone()))
+  }
+}
+
+// Clears the running mark of a query when its handling ends, however it
+// ends.
+pub(crate) struct SessionRun {
+  sess: Arc<Session>,
+  seq: u64,
+}
+
+impl SessionRun {
+  pub fn begin(sess: Arc<Session>, seq: u64) -> Option<SessionRun> {
+    if !sess.begin(seq) {
+      return None;
+    }
+    Some(SessionRun{sess, seq})
+  }
+}
+
+impl Drop for SessionRun {
+  fn drop(&mut self) {
+    self.sess.finish(self.seq);
+  }
+}
+
+// The server-side sessions, shared by the connections of a `SpawnPool`.
+pub struct SessionStore {
+  cfg: SessionConfig,
+  sessions: Mutex<HashMap<SessionId, Arc<Session>>>,
+}
+
+impl SessionStore {
+  pub fn new(cfg: SessionConfig) -> SessionStore {
+    SessionStore{cfg, sessions: Mutex::new(HashMap::new())}
+  }
+
+  pub fn config(&self) -> &SessionConfig {
+    &self.cfg
+  }
+
+  pub fn len(&self) -> usize {
+    self.sessions.lock().unwrap().len()
+  }
+
+  // Resumes the session `id`, or starts a new one if `id` is zero or
+  // unknown. Returns the session id and the seq of the last started
+  // query.
+  pub(crate) fn resume(&self, id: SessionId, last_ack: u64) -> (SessionId, u64, Arc<Session>) {
+    let now = Instant::now();
+    let mut sessions = self.sessions.lock().unwrap();
+    let idle_timeout = self.cfg.idle_timeout;
+    sessions.retain(|_, state| {
+      // A session in use by a connection is never idle.
+      Arc::strong_count(state) > 1 ||
+      now.duration_since(state.lock().last_used) < idle_timeout
+    });
+    if id != 0 {
+      if let Some(state) = sessions.get(&id) {
+        let last_seq = {
+          let mut s = state.lock();
+          s.last_used = now;
+          // The client has seen these replies.
+          s.replies.retain(|r| r.seq > last_ack);
+          s.last_seq
+        };
+        return (id, last_seq, state.clone());
+      }
+    }
+    if sessions.len() >= self.cfg.max_sessions {
+      let oldest = sessions.iter()
+        .filter(|&(_, state)| Arc::strong_count(state) <= 1)
+        .min_by_key(|&(_, state)| state.lock().last_used)
+        .map(|(&id, _)| id);
+      if let Some(oldest) = oldest {
+        sessions.remove(&oldest);
+      }
+    }
+    let mut new_id = 0;
+    while new_id == 0 || sessions.contains_key(&new_id) {
+      new_id = ((gen_id64() as u128) << 64) | (gen_id64() as u128);
+    }
+    let state = Arc::new(Session{
+      state: Mutex::new(SessionState{
+        last_seq: last_ack,
+        running: None,
+        replies: VecDeque::new(),
+        last_used: now,
+      }),
+      done: Condvar::new(),
+    });
+    sessions.insert(new_id, state.clone());
+    (new_id, last_ack, state)
+  }
+
+  // Caches the reply to `seq`, which must be recorded before it is
+  // written, and finishes the query.
+  pub(crate) fn record(&self, sess: &Session, seq: u64, tag: [u8; 3], payload: &[u8]) {
+    let mut s = sess.lock();
+    if seq < s.last_seq || s.cached(seq).is_some() {
+      return;
+    }
+    s.last_seq = seq;
+    s.last_used = Instant::now();
+    s.replies.push_back(CachedReply{seq, tag, payload: payload.to_owned()});
+    while s.replies.len() > self.cfg.reply_cache_len {
+      s.replies.pop_front();
+    }
+    if s.running == Some(seq) {
+      s.running = None;
+      sess.done.notify_all();
+    }
+  }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SessionErr {
+  Query(QueryErr),
+  // The server does not support sessions.
+  Unsupported,
+  // The server lost the session while a query was outstanding, so
+  // whether the query was processed is unknown.
+  Lost,
+}
+
+impl From<QueryErr> for SessionErr {
+  fn from(e: QueryErr) -> SessionErr {
+    SessionErr::Query(e)
+  }
+}
+
+// A reconnecting client with session resumption: a query interrupted by
+// a dropped connection is re-sent on a fresh connection with its
+// original seq, and the server either runs it (if it never got it) or
+// replays its cached reply.
+pub struct SessionChan<MsgX=()> {
+  addr: SocketAddr,
+  chan: Option<Chan<MsgX>>,
+  policy: RetryPolicy,
+  session_id: SessionId,
+  last_ack: u64,
+}
+
+impl<MsgX> SessionChan<MsgX> {
+  pub fn new<A: ToSocketAddrs>(addr: A, policy: RetryPolicy) -> Result<SessionChan<MsgX>, IoError> {
+    let addr = match addr.to_socket_addrs()?.next() {
+      None => return Err(IoError::new(IoErrorKind::InvalidInput, "no socket address")),
+      Some(a) => a
+    };
+    Ok(SessionChan{addr, chan: None, policy, session_id: 0, last_ack: 0})
+  }
+
+  pub fn session_id(&self) -> Option<SessionId> {
+    if self.session_id == 0 { None } else { Some(self.session_id) }
+  }
+}
+
+impl<MsgX: WireCodex> SessionChan<MsgX> {
+  fn open(&mut self, pending: bool) -> Result<(), SessionErr> {
+    let mut chan = Chan::<MsgX>::connect(self.addr).map_err(|_| QueryErr::Connect)?;
+    let hdr = encode_session_hdr(self.session_id, self.last_ack);
+    chan.send_raw(0, SESSION_RESUME_TAG, &hdr).map_err(QueryErr::Send)?;
+    let (id, _last_seq) = match chan.recv_frame() {
+      // The server closes the connection on an unknown tag.
+      Err(_) => return Err(SessionErr::Unsupported),
+      Ok((hdr, buf)) => {
+        if hdr.seq != 0 || hdr.tag != SESSION_ACCEPT_TAG {
+          return Err(SessionErr::Unsupported);
+        }
+        decode_session_hdr(buf).map_err(QueryErr::Recv)?
+      }
+    };
+    let lost = self.session_id != 0 && id != self.session_id;
+    self.session_id = id;
+    chan.reset_seqs(self.last_ack, self.last_ack);
+    self.chan = Some(chan);
+    if lost && pending {
+      return Err(SessionErr::Lost);
+    }
+    Ok(())
+  }
+
+  pub fn query(&mut self, query: &Msg<MsgX>) -> Result<Msg<MsgX>, SessionErr> {
+    let max_attempts = self.policy.max_attempts.max(1);
+    let mut attempt = 1;
+    // The seq of the query, once it may have reached the server.
+    let mut sent = None;
+    loop {
+      let res = match self.chan.as_mut() {
+        None => self.open(sent.is_some()).map(|_| None),
+        Some(chan) => {
+          sent = Some(chan.seqs().1 + 1);
+          match chan.query(query) {
+            Err(QueryErr::Bot(details)) => {
+              // A definitive answer, cached by the server like any other.
+              self.last_ack = chan.seqs().0;
+              return Err(SessionErr::Query(QueryErr::Bot(details)));
+            }
+            Err(e) => Err(SessionErr::Query(e)),
+            Ok(reply) => Ok(Some((reply, chan.seqs().0)))
+          }
+        }
+      };
+      let e = match res {
+        Ok(None) => continue,
+        Ok(Some((reply, rseq))) => {
+          self.last_ack = rseq;
+          return Ok(reply);
+        }
+        Err(SessionErr::Lost) => {
+          return Err(SessionErr::Lost);
+        }
+        Err(e) => e
+      };
+      self.chan = None;
+      // After a deadline, waiting any longer is up to the caller.
+      let retry = match &e {
+        &SessionErr::Query(QueryErr::Deadline) => false,
+        _ => attempt < max_attempts
+      };
+      if !retry {
+        // The abandoned query keeps its seq: skip past it, so that the
+        // next query is not answered with its cached reply.
+        if let Some(seq) = sent {
+          self.last_ack = self.last_ack.max(seq);
+        }
+        return Err(e);
+      }
+      sleep(self.policy.backoff(attempt));
+      attempt += 1;
+    }
+  }
+}
@@ -1,5 +1,5 @@
 use once_cell::sync::{Lazy};
-use signal_hook::consts::{SIGWINCH, SIGCONT, SIGHUP, SIGINT, SIGTERM, SIGQUIT};
+use signal_hook::consts::{SIGWINCH, SIGCONT, SIGHUP, SIGINT, SIGTERM, SIGQUIT, SIGUSR1, SIGUSR2};
 use signal_hook::flag::{register as register_signal};
 
 use std::sync::{Arc};
@@ -18,6 +18,8 @@ pub struct SignalsState {
   pub int_: Arc<AtomicBool>,
   pub term: Arc<AtomicBool>,
   pub quit: Arc<AtomicBool>,
+  pub usr1: Arc<AtomicBool>,
+  pub usr2: Arc<AtomicBool>,
 }
 
 impl SignalsState {
@@ -29,6 +31,8 @@ impl SignalsState {
       int_: Arc::new(AtomicBool::new(false)),
       term: Arc::new(AtomicBool::new(false)),
       quit: Arc::new(AtomicBool::new(false)),
+      usr1: Arc::new(AtomicBool::new(false)),
+      usr2: Arc::new(AtomicBool::new(false)),
     }
   }
 
@@ -67,6 +71,15 @@ impl SignalsState {
   pub fn get_quit(&self) -> bool {
     self.quit.load(AtomicOrdering::Relaxed)
   }
+
+  // Returns and unsets the flag.
+  pub fn take_usr1(&self) -> bool {
+    self.usr1.load(AtomicOrdering::Relaxed) && self.usr1.swap(false, AtomicOrdering::SeqCst)
+  }
+
+  pub fn take_usr2(&self) -> bool {
+    self.usr2.load(AtomicOrdering::Relaxed) && self.usr2.swap(false, AtomicOrdering::SeqCst)
+  }
 }
 
 #[derive(Debug, Default)]
@@ -77,6 +90,8 @@ pub struct SignalsConfigOnce {
   pub int_: bool,
   pub term: bool,
   pub quit: bool,
+  pub usr1: bool,
+  pub usr2: bool,
 }
 
 impl SignalsConfigOnce {
@@ -99,5 +114,11 @@ impl SignalsConfigOnce {
     if self.quit {
       register_signal(SIGTERM, Arc::clone(&ONCE_SIGNALS.quit)).unwrap();
     }
+    if self.usr1 {
+      register_signal(SIGUSR1, Arc::clone(&ONCE_SIGNALS.usr1)).unwrap();
+    }
+    if self.usr2 {
+      register_signal(SIGUSR2, Arc::clone(&ONCE_SIGNALS.usr2)).unwrap();
+    }
   }
 }
@@ -0,0 +1,120 @@
+use crate::msg::*;
+
+use once_cell::sync::{Lazy};
+
+use std::any::{type_name};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Mutex};
+
+// The reserved tag namespace. Extension types may not use:
+//
+// - the tags of the built-in `Msg` variants (`BUILTIN_TAGS`);
+// - the tags set aside for planned `Msg` variants (`FUTURE_TAGS`);
+// - any tag with a byte outside of printable ASCII (0x21 ..= 0x7e);
+// - any tag starting with an ASCII punctuation character (like `...`
+//   and `!!!`), which are kept for protocol-level frames.
+//
+// Tags that do not fall into the above (e.g. `FOO`, `Q1?`) are free for
+// extension types.
+pub const FUTURE_TAGS: &'static [([u8; 3], &'static str)] = RESERVED_FUTURE_TAGS;
+
+pub static ONCE_TAG_REGISTRY: Lazy<Mutex<TagRegistry>> = Lazy::new(|| Mutex::new(TagRegistry::default()));
+
+pub fn is_reserved_tag(tag: &[u8; 3]) -> bool {
+  if builtin_tag_name(tag).is_some() {
+    return true;
+  }
+  if FUTURE_TAGS.iter().any(|&(t, _)| &t == tag) {
+    return true;
+  }
+  if tag.iter().any(|&x| x < 0x21 || x > 0x7e) {
+    return true;
+  }
+  tag[0].is_ascii_punctuation()
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TagErr {
+  // The extension type declared a reserved tag.
+  Reserved([u8; 3], &'static str),
+  // The extension type declared the same tag more than once.
+  Duplicate([u8; 3], &'static str),
+}
+
+#[derive(Clone, Debug)]
+pub struct TagInfo {
+  pub tag: [u8; 3],
+  // The `Msg` variant name for a built-in tag, or the name of the
+  // extension type that declared the tag.
+  pub owner: &'static str,
+  pub builtin: bool,
+}
+
+// The tags declared by extension types, keyed by type name. Distinct
+// extension types may reuse each other's tags, since a `Chan` speaks a
+// single `MsgX`; only collisions with the reserved namespace, and within
+// a single type, are errors.
+#[derive(Default)]
+pub struct TagRegistry {
+  types: BTreeMap<&'static str, Vec<[u8; 3]>>,
+}
+
+impl TagRegistry {
+  pub fn register<X: WireCodex>(&mut self) -> Result<(), TagErr> {
+    let owner = type_name::<X>();
+    if self.types.contains_key(owner) {
+      return Ok(());
+    }
+    let tags = X::wire_tags();
+    check_tags(owner, &tags)?;
+    self.types.insert(owner, tags);
+    Ok(())
+  }
+
+  pub fn known_tags(&self) -> Vec<TagInfo> {
+    let mut known = Vec::new();
+    for &(tag, name) in BUILTIN_TAGS.iter() {
+      known.push(TagInfo{tag, owner: name, builtin: true});
+    }
+    for (&owner, tags) in self.types.iter() {
+      for &tag in tags.iter() {
+        known.push(TagInfo{tag, owner, builtin: false});
+      }
+    }
+    known
+  }
+}
+
+fn check_tags(owner: &'static str, tags: &[[u8; 3]]) -> Result<(), TagErr> {
+  let mut seen = BTreeSet::new();
+  for &tag in tags.iter() {
+    if is_reserved_tag(&tag) {
+      return Err(TagErr::Reserved(tag, owner));
+    }
+    if !seen.insert(tag) {
+      return Err(TagErr::Duplicate(tag, owner));
+    }
+  }
+  Ok(())
+}
+
+// Validates and records the tags declared by `X::wire_tags`. This is
+// idempotent, and is called by `Chan` and `SpawnPool` on construction.
+pub fn register_tags<X: WireCodex>() -> Result<(), TagErr> {
+  ONCE_TAG_REGISTRY.lock().unwrap().register::<X>()
+}
+
+// Like `register_tags`, but panics on invalid tags; the tags of a type
+// are fixed at compile time, so this is a bug in the extension type.
+pub fn register_tags_or_panic<X: WireCodex>() {
+  if let Err(e) = register_tags::<X>() {
+    panic!("bug: invalid extension wire tags: {:?}", e);
+  }
+}
+
+// Lists the built-in tags, followed by the tags of every registered
+// extension type.
+pub fn known_tags() -> Vec<TagInfo> {
+  ONCE_TAG_REGISTRY.lock().unwrap().known_tags()
+}
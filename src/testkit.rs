@@ -0,0 +1,274 @@
+use crate::chan::*;
+use crate::frame::*;
+use crate::msg::*;
+
+use std::cmp::{min};
+use std::collections::{VecDeque};
+use std::fmt::{Debug};
+use std::io::{Read, Write, Error as IoError, ErrorKind as IoErrorKind};
+use std::panic::{resume_unwind};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{JoinHandle, spawn};
+use std::time::{Duration as StdDuration, Instant};
+
+struct Pipe {
+  buf: VecDeque<u8>,
+  closed: bool,
+}
+
+struct PipeShared {
+  pipe: Mutex<Pipe>,
+  cv: Condvar,
+}
+
+impl PipeShared {
+  fn new() -> Arc<PipeShared> {
+    Arc::new(PipeShared{
+      pipe: Mutex::new(Pipe{buf: VecDeque::new(), closed: false}),
+      cv: Condvar::new(),
+    })
+  }
+
+  fn close(&self) {
+    let mut pipe = self.pipe.lock().unwrap();
+    pipe.closed = true;
+    self.cv.notify_all();
+  }
+}
+
+struct MemEnd {
+  rx: Arc<PipeShared>,
+  tx: Arc<PipeShared>,
+}
+
+impl Drop for MemEnd {
+  fn drop(&mut self) {
+    self.rx.close();
+    self.tx.close();
+  }
+}
+
+// One end of an in-memory, unbounded byte stream. Like a `TcpStream`,
+// clones share the same end, which is closed once all clones are
+// dropped.
+pub struct MemStream {
+  end: Arc<MemEnd>,
+  timeout: Arc<Mutex<Option<StdDuration>>>,
+}
+
+pub fn mem_stream_pair() -> (MemStream, MemStream) {
+  let lo = PipeShared::new();
+  let hi = PipeShared::new();
+  let a = MemStream{
+    end: Arc::new(MemEnd{rx: lo.clone(), tx: hi.clone()}),
+    timeout: Arc::new(Mutex::new(None)),
+  };
+  let b = MemStream{
+    end: Arc::new(MemEnd{rx: hi, tx: lo}),
+    timeout: Arc::new(Mutex::new(None)),
+  };
+  (a, b)
+}
+
+impl Read for MemStream {
+  fn read(&mut self, out: &mut [u8]) -> Result<usize, IoError> {
+    if out.is_empty() {
+      return Ok(0);
+    }
+    let timeout = *self.timeout.lock().unwrap();
+    let t0 = Instant::now();
+    let shared = &self.end.rx;
+    let mut pipe = shared.pipe.lock().unwrap();
+    loop {
+      if !pipe.buf.is_empty() {
+        let n = min(out.len(), pipe.buf.len());
+        for (dst, x) in out.iter_mut().zip(pipe.buf.drain(.. n)) {
+          *dst = x;
+        }
+        return Ok(n);
+      }
+      if pipe.closed {
+        return Ok(0);
+      }
+      match timeout {
+        None => {
+          pipe = shared.cv.wait(pipe).unwrap();
+        }
+        Some(t) => {
+          let elapsed = t0.elapsed();
+          if elapsed >= t {
+            return Err(IoError::new(IoErrorKind::WouldBlock, "read timed out"));
+          }
+          pipe = shared.cv.wait_timeout(pipe, t - elapsed).unwrap().0;
+        }
+      }
+    }
+  }
+}
+
+impl Write for MemStream {
+  fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+    let shared = &self.end.tx;
+    let mut pipe = shared.pipe.lock().unwrap();
+    if pipe.closed {
+      return Err(IoError::new(IoErrorKind::BrokenPipe, "peer closed"));
+    }
+    pipe.buf.extend(buf.iter());
+    shared.cv.notify_all();
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> Result<(), IoError> {
+    Ok(())
+  }
+}
+
+impl ChanStream for MemStream {
+  fn try_clone_stream(&self) -> Result<Box<dyn ChanStream>, IoError> {
+    Ok(Box::new(MemStream{
+      end: self.end.clone(),
+      timeout: self.timeout.clone(),
+    }))
+  }
+
+  fn set_read_timeout(&self, timeout: Option<StdDuration>) -> Result<(), IoError> {
+    *self.timeout.lock().unwrap() = timeout;
+    Ok(())
+  }
+
+  // Like shutting down a socket: readers on both ends see the end of
+  // the stream once the buffered bytes are read, and writes fail.
+  fn shutdown(&self) -> Result<(), IoError> {
+    self.end.rx.close();
+    self.end.tx.close();
+    Ok(())
+  }
+}
+
+// A connected pair of in-memory `Chan`s.
+pub fn chan_pair<MsgX: WireCodex>() -> (Chan<MsgX>, Chan<MsgX>) {
+  let (a, b) = mem_stream_pair();
+  (Chan::from_stream(Box::new(a)), Chan::from_stream(Box::new(b)))
+}
+
+// Runs a handler against a sequence of queries, going through the wire
+// encoding in both directions, and collects the replies.
+pub fn run_handler<MsgX: WireCodex, P: Fn(&Msg<MsgX>) -> Msg<MsgX>>(proc_: P, queries: &[Msg<MsgX>]) -> Result<Vec<Msg<MsgX>>, ReplyErr> {
+  let (mut client, mut server) = chan_pair::<MsgX>();
+  let mut tseqs = Vec::with_capacity(queries.len());
+  for query in queries.iter() {
+    tseqs.push(client.send(query)?);
+  }
+  for _ in 0 .. queries.len() {
+    server.reply(&proc_)?;
+  }
+  let mut replies = Vec::with_capacity(queries.len());
+  for &tseq in tseqs.iter() {
+    let (reply, rseq) = client.recv()?;
+    if tseq != rseq {
+      return Err(ReplyErr::Seq);
+    }
+    replies.push(reply);
+  }
+  Ok(replies)
+}
+
+fn encode_or_panic<MsgX: WireCodex + Debug>(msg: &Msg<MsgX>) -> ([u8; 3], Vec<u8>) {
+  let mut buf = Vec::new();
+  match encode_msg(msg, &mut buf) {
+    Err(e) => panic!("MockService: failed to encode {:?}: {:?}", msg, e),
+    Ok(tag) => (tag, buf)
+  }
+}
+
+struct MockStep<MsgX> {
+  desc: String,
+  match_: Box<dyn Send + Fn(&Msg<MsgX>) -> bool>,
+  reply: Box<dyn Send + FnOnce(&Msg<MsgX>) -> Msg<MsgX>>,
+}
+
+// A scripted service: each received query must match the next expected
+// query, in order, and is answered with the corresponding canned reply.
+// Any unexpected or missing query panics the mock.
+pub struct MockService<MsgX=()> {
+  steps: Vec<MockStep<MsgX>>,
+}
+
+impl<MsgX: 'static + Send + Debug + WireCodex> MockService<MsgX> {
+  pub fn new() -> MockService<MsgX> {
+    MockService{steps: Vec::new()}
+  }
+
+  // Expects a query equal (on the wire) to `query`.
+  pub fn expect(self, query: Msg<MsgX>, reply: Msg<MsgX>) -> MockService<MsgX> {
+    let desc = format!("{:?}", query);
+    let expected = encode_or_panic(&query);
+    self.expect_with(desc, move |q| encode_or_panic(q) == expected, move |_| reply)
+  }
+
+  // Expects any query with the wire tag `tag`.
+  pub fn expect_tag(self, tag: [u8; 3], reply: Msg<MsgX>) -> MockService<MsgX> {
+    let desc = format!("a query with tag {:?}", String::from_utf8_lossy(&tag));
+    self.expect_with(desc, move |q| encode_or_panic(q).0 == tag, move |_| reply)
+  }
+
+  pub fn expect_with<S, M, R>(mut self, desc: S, match_: M, reply: R) -> MockService<MsgX>
+  where S: Into<String>,
+        M: 'static + Send + Fn(&Msg<MsgX>) -> bool,
+        R: 'static + Send + FnOnce(&Msg<MsgX>) -> Msg<MsgX>,
+  {
+    self.steps.push(MockStep{
+      desc: desc.into(),
+      match_: Box::new(match_),
+      reply: Box::new(reply),
+    });
+    self
+  }
+
+  // Runs the mock on its own thread, returning the client end. Drop the
+  // client `Chan` before calling `MockHandle::finish`.
+  pub fn spawn(self) -> (Chan<MsgX>, MockHandle) {
+    let (client, mut server) = chan_pair::<MsgX>();
+    let join = spawn(move || {
+      let mut steps = self.steps.into_iter().enumerate();
+      loop {
+        let query = match server.recv() {
+          Err(_) => break,
+          Ok((query, _)) => query
+        };
+        match steps.next() {
+          None => {
+            panic!("MockService: unexpected query: {:?}", query);
+          }
+          Some((i, step)) => {
+            if !(step.match_)(&query) {
+              panic!("MockService: step {}: expected {}, got: {:?}", i, step.desc, query);
+            }
+            let reply = (step.reply)(&query);
+            if server.send(&reply).is_err() {
+              break;
+            }
+          }
+        }
+      }
+      let missing: Vec<_> = steps.map(|(_, step)| step.desc).collect();
+      if !missing.is_empty() {
+        panic!("MockService: expected queries never received: {:?}", missing);
+      }
+    });
+    (client, MockHandle{join})
+  }
+}
+
+pub struct MockHandle {
+  join: JoinHandle<()>,
+}
+
+impl MockHandle {
+  // Waits for the mock to finish, re-raising any assertion failure.
+  pub fn finish(self) {
+    if let Err(e) = self.join.join() {
+      resume_unwind(e);
+    }
+  }
+}
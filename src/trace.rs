@@ -0,0 +1,162 @@
+use once_cell::sync::{Lazy};
+use rustc_serialize::json::{Json};
+
+use std::cell::{Cell};
+use std::collections::hash_map::{RandomState};
+use std::fmt::{Display, Formatter, Result as FmtResult, Write as FmtWrite};
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Error as IoError, Write, BufWriter};
+use std::path::{Path};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::time::{Duration as StdDuration, SystemTime, UNIX_EPOCH};
+
+pub static ONCE_SPAN_EXPORTER: Lazy<RwLock<Option<Arc<dyn SpanExporter>>>> = Lazy::new(|| RwLock::new(None));
+
+static ID_CTR: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+  static TL_TRACE: Cell<Option<TraceCtx>> = Cell::new(None);
+}
+
+pub(crate) fn gen_id64() -> u64 {
+  loop {
+    let mut h = RandomState::new().build_hasher();
+    h.write_u64(ID_CTR.fetch_add(1, AtomicOrdering::Relaxed));
+    let x = h.finish();
+    if x != 0 {
+      return x;
+    }
+  }
+}
+
+// Trace and span ids are never zero; a zero parent span id on the wire
+// means that the span is a root span.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TraceCtx {
+  pub trace_id: u128,
+  pub span_id: u64,
+  pub parent_span_id: Option<u64>,
+}
+
+impl Display for TraceCtx {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "trace_id={:032x} span_id={:016x}", self.trace_id, self.span_id)?;
+    if let Some(p) = self.parent_span_id {
+      write!(f, " parent_span_id={:016x}", p)?;
+    }
+    Ok(())
+  }
+}
+
+impl TraceCtx {
+  pub fn root() -> TraceCtx {
+    let trace_id = ((gen_id64() as u128) << 64) | (gen_id64() as u128);
+    TraceCtx{
+      trace_id,
+      span_id: gen_id64(),
+      parent_span_id: None,
+    }
+  }
+
+  pub fn child(&self) -> TraceCtx {
+    TraceCtx{
+      trace_id: self.trace_id,
+      span_id: gen_id64(),
+      parent_span_id: Some(self.span_id),
+    }
+  }
+}
+
+// The trace context of the query currently being handled on this
+// thread, if the querying peer sent one.
+pub fn current() -> Option<TraceCtx> {
+  TL_TRACE.with(|t| t.get())
+}
+
+// Runs `f` with the thread-local trace context set to `trace`, restoring
+// the previous context afterwards. Nested `Chan::query` calls made
+// within `f` send a child span of `trace`.
+pub fn with_trace<R, F: FnOnce() -> R>(trace: Option<TraceCtx>, f: F) -> R {
+  struct Restore(Option<TraceCtx>);
+
+  impl Drop for Restore {
+    fn drop(&mut self) {
+      TL_TRACE.with(|t| t.set(self.0));
+    }
+  }
+
+  let prev = TL_TRACE.with(|t| t.replace(trace));
+  let _restore = Restore(prev);
+  f()
+}
+
+// Starts a new trace (if none is current) for the duration of `f`.
+pub fn with_root_trace<R, F: FnOnce() -> R>(f: F) -> R {
+  match current() {
+    Some(_) => f(),
+    None => with_trace(Some(TraceCtx::root()), f)
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct SpanRecord {
+  pub ctx: TraceCtx,
+  pub name: String,
+  pub start: SystemTime,
+  pub duration: StdDuration,
+}
+
+impl SpanRecord {
+  pub fn to_json_line(&self) -> String {
+    let start_us = self.start.duration_since(UNIX_EPOCH)
+      .map(|d| d.as_micros()).unwrap_or(0);
+    let mut s = String::new();
+    write!(&mut s, "{{\"trace_id\":\"{:032x}\",\"span_id\":\"{:016x}\",",
+        self.ctx.trace_id, self.ctx.span_id).unwrap();
+    match self.ctx.parent_span_id {
+      None => s.push_str("\"parent_span_id\":null,"),
+      Some(p) => write!(&mut s, "\"parent_span_id\":\"{:016x}\",", p).unwrap()
+    }
+    write!(&mut s, "\"name\":{},\"start_us\":{},\"duration_us\":{}}}",
+        Json::String(self.name.clone()), start_us, self.duration.as_micros()).unwrap();
+    s
+  }
+}
+
+pub trait SpanExporter: Send + Sync {
+  fn export(&self, span: &SpanRecord);
+}
+
+// Appends span records as JSON lines to a local file.
+pub struct FileSpanExporter {
+  file: Mutex<BufWriter<File>>,
+}
+
+impl FileSpanExporter {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<FileSpanExporter, IoError> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(FileSpanExporter{file: Mutex::new(BufWriter::new(file))})
+  }
+}
+
+impl SpanExporter for FileSpanExporter {
+  fn export(&self, span: &SpanRecord) {
+    let line = span.to_json_line();
+    let mut file = self.file.lock().unwrap();
+    let _ = writeln!(&mut *file, "{}", line);
+    let _ = file.flush();
+  }
+}
+
+pub fn set_span_exporter(exporter: Option<Arc<dyn SpanExporter>>) {
+  *ONCE_SPAN_EXPORTER.write().unwrap() = exporter;
+}
+
+pub fn export_span(span: &SpanRecord) {
+  let exporter = ONCE_SPAN_EXPORTER.read().unwrap().clone();
+  if let Some(exporter) = exporter {
+    exporter.export(span);
+  }
+}
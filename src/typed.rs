@@ -0,0 +1,88 @@
+use crate::msg::*;
+
+use rustc_serialize::{Decodable, Encodable};
+use rustc_serialize::json::{Json, JsonEncoder};
+
+use std::collections::{BTreeMap};
+
+// A typed envelope over `Msg::JSO`, for exchanging plain Rust structs
+// through their `RustcEncodable`/`RustcDecodable` impls:
+//
+//     {"type": "<type name>", "value": <the encoded value>}
+//
+// The type name is part of the protocol, so it is always explicit: pass
+// it to `typed_as` and `decode_as`, or declare it once per type with
+// `TypedMsg` and use `typed` and `decode`. (`std::any::type_name` is not
+// an option, as it may change between compiler versions.)
+//
+//     impl TypedMsg for Ping {
+//       const NAME: &'static str = "ping";
+//     }
+
+pub const TYPED_TYPE_KEY: &'static str = "type";
+pub const TYPED_VALUE_KEY: &'static str = "value";
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TypedErr {
+  Encode(String),
+  // The message is not a typed envelope.
+  NotTyped,
+  Mismatch{expected: String, found: String},
+  Decode{type_name: String, error: String},
+}
+
+// A type with a stable envelope name.
+pub trait TypedMsg {
+  const NAME: &'static str;
+}
+
+impl<X> Msg<X> {
+  pub fn typed_as<T: Encodable>(name: &str, value: &T) -> Result<Msg<X>, TypedErr> {
+    let mut buf = String::new();
+    {
+      let mut enc = JsonEncoder::new(&mut buf);
+      value.encode(&mut enc).map_err(|e| TypedErr::Encode(format!("{:?}", e)))?;
+    }
+    let value = Json::from_str(&buf).map_err(|e| TypedErr::Encode(format!("{:?}", e)))?;
+    let mut obj = BTreeMap::new();
+    obj.insert(TYPED_TYPE_KEY.to_string(), Json::String(name.to_string()));
+    obj.insert(TYPED_VALUE_KEY.to_string(), value);
+    Ok(Msg::JSO(Json::Object(obj)))
+  }
+
+  // The type name of a typed envelope.
+  pub fn type_name(&self) -> Option<&str> {
+    match self {
+      &Msg::JSO(ref j) => j.find(TYPED_TYPE_KEY)?.as_string(),
+      _ => None
+    }
+  }
+
+  pub fn decode_as<T: Decodable>(&self, name: &str) -> Result<T, TypedErr> {
+    let obj = match self {
+      &Msg::JSO(Json::Object(ref obj)) => obj,
+      _ => return Err(TypedErr::NotTyped)
+    };
+    let found = match obj.get(TYPED_TYPE_KEY).and_then(|t| t.as_string()) {
+      None => return Err(TypedErr::NotTyped),
+      Some(t) => t
+    };
+    if found != name {
+      return Err(TypedErr::Mismatch{expected: name.to_string(), found: found.to_string()});
+    }
+    let value = obj.get(TYPED_VALUE_KEY).cloned().ok_or(TypedErr::NotTyped)?;
+    value.decode_into().map_err(|e| TypedErr::Decode{
+      type_name: name.to_string(),
+      error: format!("{:?}", e),
+    })
+  }
+
+  pub fn typed<T: TypedMsg + Encodable>(value: &T) -> Result<Msg<X>, TypedErr> {
+    Msg::typed_as(T::NAME, value)
+  }
+
+  pub fn decode<T: TypedMsg + Decodable>(&self) -> Result<T, TypedErr> {
+    self.decode_as(T::NAME)
+  }
+}
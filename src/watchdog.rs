@@ -0,0 +1,179 @@
+use crate::chan::*;
+use crate::frame::{bot_details};
+use crate::logging::{LogLevel, log};
+use crate::metrics::{metrics};
+
+use rustc_serialize::json::{Json};
+
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering as AtomicOrdering};
+use std::thread::{spawn};
+use std::time::{Duration as StdDuration, Instant};
+
+// Per-query time budgets. A handler cannot be interrupted, so when one
+// overruns its budget, the watchdog thread answers the query itself with
+//
+//     Bot {"error": "timeout", "tag": "<tag>", "budget_ms": <budget>}
+//
+// and shuts the connection down; the connection thread closes it once
+// the handler returns, and the late reply is dropped. The timeout reply
+// is cached in the session, if any, so a resumed session gets it too
+// rather than running the query again.
+
+const RUNNING: u8 = 0;
+const DONE: u8 = 1;
+const TIMED_OUT: u8 = 2;
+
+// Checked at least this often, even without a nearer deadline.
+const MAX_TICK: StdDuration = StdDuration::from_millis(100);
+
+struct InFlight {
+  conn: u64,
+  seq: u64,
+  tag: [u8; 3],
+  start: Instant,
+  deadline: Instant,
+  state: AtomicU8,
+  reply: SideReply,
+}
+
+struct WatchdogInner {
+  queries: Mutex<Vec<Arc<InFlight>>>,
+  cond: Condvar,
+  timeouts: AtomicU64,
+}
+
+impl WatchdogInner {
+  fn time_out(&self, q: &InFlight, budget: StdDuration) {
+    self.timeouts.fetch_add(1, AtomicOrdering::Relaxed);
+    let tag = String::from_utf8_lossy(&q.tag);
+    metrics().counter("chan_query_timeouts_total", "Queries that overran their time budget, by query tag.", &[("tag", &*tag)]).inc();
+    log(LogLevel::Warn, "query budget exceeded", &[
+        ("conn", &q.conn),
+        ("seq", &q.seq),
+        ("tag", &tag),
+        ("budget_ms", &budget.as_millis()),
+    ]);
+    let details = bot_details("timeout", q.tag, vec![("budget_ms", Json::U64(budget.as_millis() as u64))]);
+    let _ = q.reply.send(q.seq, *b"!!!", details.as_bytes());
+    q.reply.shutdown();
+  }
+
+  fn run(this: Weak<WatchdogInner>) {
+    loop {
+      let inner = match this.upgrade() {
+        None => return,
+        Some(inner) => inner
+      };
+      let mut queries = inner.queries.lock().unwrap();
+      let now = Instant::now();
+      let mut expired = Vec::new();
+      queries.retain(|q| {
+        if q.state.load(AtomicOrdering::Acquire) != RUNNING {
+          return false;
+        }
+        if q.deadline > now {
+          return true;
+        }
+        if q.state.compare_exchange(RUNNING, TIMED_OUT, AtomicOrdering::AcqRel, AtomicOrdering::Acquire).is_ok() {
+          expired.push(q.clone());
+        }
+        false
+      });
+      let next = queries.iter().map(|q| q.deadline).min();
+      let tick = match next {
+        None => MAX_TICK,
+        Some(d) => d.saturating_duration_since(now).min(MAX_TICK)
+      };
+      if expired.is_empty() {
+        let (guard, _) = inner.cond.wait_timeout(queries, tick).unwrap();
+        drop(guard);
+        continue;
+      }
+      drop(queries);
+      for q in expired.iter() {
+        inner.time_out(q, q.deadline - q.start);
+      }
+    }
+  }
+}
+
+// Watches the queries of the connections it is given to; the watchdog
+// thread exits once the last clone is dropped and it next wakes up.
+#[derive(Clone)]
+pub struct QueryBudget {
+  budget: StdDuration,
+  inner: Arc<WatchdogInner>,
+}
+
+impl QueryBudget {
+  pub fn new(budget: StdDuration) -> QueryBudget {
+    let inner = Arc::new(WatchdogInner{
+      queries: Mutex::new(Vec::new()),
+      cond: Condvar::new(),
+      timeouts: AtomicU64::new(0),
+    });
+    let weak = Arc::downgrade(&inner);
+    let _ = spawn(move || WatchdogInner::run(weak));
+    QueryBudget{budget, inner}
+  }
+
+  pub fn budget(&self) -> StdDuration {
+    self.budget
+  }
+
+  // The number of queries that overran the budget.
+  pub fn timeouts(&self) -> u64 {
+    self.inner.timeouts.load(AtomicOrdering::Relaxed)
+  }
+
+  pub(crate) fn start(&self, reply: SideReply, conn: u64, seq: u64, tag: [u8; 3]) -> BudgetGuard {
+    let start = Instant::now();
+    let q = Arc::new(InFlight{
+      conn,
+      seq,
+      tag,
+      start,
+      deadline: start + self.budget,
+      state: AtomicU8::new(RUNNING),
+      reply,
+    });
+    self.inner.queries.lock().unwrap().push(q.clone());
+    self.inner.cond.notify_one();
+    BudgetGuard{q}
+  }
+}
+
+pub(crate) struct BudgetGuard {
+  q: Arc<InFlight>,
+}
+
+impl BudgetGuard {
+  pub fn deadline(&self) -> Instant {
+    self.q.deadline
+  }
+
+  // Returns false if the watchdog has already answered the query, in
+  // which case the handler's reply must be dropped.
+  pub fn finish(&self) -> bool {
+    match self.q.state.compare_exchange(RUNNING, DONE, AtomicOrdering::AcqRel, AtomicOrdering::Acquire) {
+      Ok(_) => true,
+      Err(DONE) => true,
+      Err(_) => {
+        log(LogLevel::Warn, "handler overran its budget", &[
+            ("conn", &self.q.conn),
+            ("seq", &self.q.seq),
+            ("tag", &String::from_utf8_lossy(&self.q.tag)),
+            ("elapsed_ms", &self.q.start.elapsed().as_millis()),
+        ]);
+        false
+      }
+    }
+  }
+}
+
+impl Drop for BudgetGuard {
+  fn drop(&mut self) {
+    let _ = self.q.state.compare_exchange(RUNNING, DONE, AtomicOrdering::AcqRel, AtomicOrdering::Acquire);
+  }
+}
@@ -0,0 +1,114 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::capture::*;
+use service_base::msg::{Msg};
+use service_base::testkit::{chan_pair};
+
+use rustc_serialize::json::{Json};
+
+use std::env::{temp_dir};
+use std::fs::{remove_file};
+use std::io::{Cursor, ErrorKind as IoErrorKind};
+use std::process::{id as process_id};
+use std::sync::{Arc};
+use std::thread::{spawn};
+use std::time::{UNIX_EPOCH};
+
+fn handler(query: &Msg) -> Msg {
+  match query {
+    &Msg::OKQ => Msg::OKR,
+    &Msg::JSO(ref j) => Msg::JSO(j.clone()),
+    _ => panic!("boom")
+  }
+}
+
+fn queries() -> Vec<Msg> {
+  vec![Msg::OKQ, Msg::JSO(Json::U64(7)), Msg::OKR]
+}
+
+// Serves `queries()` with `handler` on a `chan_pair`, with the server side
+// captured to `cap`.
+fn serve_captured(cap: Option<Arc<CaptureWriter>>) {
+  let (mut client, mut server) = chan_pair::<()>();
+  server.set_capture(cap);
+  let h = spawn(move || {
+    for _ in 0 .. queries().len() {
+      assert!(!server.reply(handler).unwrap());
+    }
+  });
+  for query in queries().iter() {
+    let _ = client.query(query);
+  }
+  h.join().unwrap();
+}
+
+#[test]
+fn test_capture_replay_round_trip() {
+  let path = temp_dir().join(format!("svc-capture-test-{}.cap", process_id()));
+  serve_captured(Some(Arc::new(CaptureWriter::create(&path).unwrap())));
+  let records: Vec<CaptureRecord> = CaptureReader::open(&path).unwrap()
+    .collect::<Result<_, _>>().unwrap();
+  remove_file(&path).unwrap();
+  assert_eq!(records.len(), 6);
+  let dirs: Vec<_> = records.iter().map(|r| (r.dir, r.seq, r.tag)).collect();
+  assert_eq!(dirs, vec![
+    (CaptureDir::Recv, 1, *b"OK?"), (CaptureDir::Send, 1, *b"OK."),
+    (CaptureDir::Recv, 2, *b"JSO"), (CaptureDir::Send, 2, *b"JSO"),
+    (CaptureDir::Recv, 3, *b"OK."), (CaptureDir::Send, 3, *b"!!!"),
+  ]);
+  assert!(records.iter().all(|r| r.time > UNIX_EPOCH));
+
+  // Against a live service, the panic's Bot details compare as a reply.
+  let (mut client, mut server) = chan_pair::<()>();
+  let h = spawn(move || {
+    for _ in 0 .. 3 {
+      assert!(!server.reply(handler).unwrap());
+    }
+  });
+  let items = replay_live(&records, CaptureDir::Recv, &mut client).unwrap();
+  h.join().unwrap();
+  let matched: Vec<_> = items.iter().map(|i| (i.seq, i.matched)).collect();
+  assert_eq!(matched, vec![(1, Some(true)), (2, Some(true)), (3, Some(true))]);
+  assert!(matches!(items[2].reply, Msg::Bot));
+
+  // A changed handler shows up as a mismatch.
+  let items = replay_handler(&records, CaptureDir::Recv, |query: &Msg| {
+    match query {
+      &Msg::JSO(_) => Msg::JSO(Json::Null),
+      _ => Msg::OKR
+    }
+  }).unwrap();
+  let matched: Vec<_> = items.iter().map(|i| i.matched).collect();
+  assert_eq!(matched, vec![Some(true), Some(false), Some(false)]);
+}
+
+#[test]
+fn test_truncated_capture_is_an_error() {
+  let rec = CaptureRecord{
+    time: UNIX_EPOCH,
+    stream: 0,
+    dir: CaptureDir::Recv,
+    tag: *b"OK?",
+    seq: 1,
+    payload: b"abc".to_vec(),
+  };
+  let mut buf = CAPTURE_MAGIC.to_vec();
+  rec.write_to(&mut buf).unwrap();
+  let one = buf.len();
+  rec.write_to(&mut buf).unwrap();
+  // Whole records, then a clean end.
+  let mut r = CaptureReader::new(Cursor::new(buf.clone())).unwrap();
+  assert_eq!(r.next().unwrap().unwrap().payload, b"abc");
+  assert!(r.next().unwrap().is_ok());
+  assert!(r.next().is_none());
+  // Cut inside the leading u64 of a record, or further into it.
+  for &cut in [one + 3, one + 12, buf.len() - 1].iter() {
+    let mut r = CaptureReader::new(Cursor::new(buf[.. cut].to_vec())).unwrap();
+    assert!(r.next().unwrap().is_ok());
+    match r.next() {
+      Some(Err(ref e)) if e.kind() == IoErrorKind::UnexpectedEof => {}
+      x => panic!("unexpected record at {}: {:?}", cut, x),
+    }
+  }
+}
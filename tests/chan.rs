@@ -0,0 +1,106 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::chan::{Chan, FlowMode, FlowWindow, QueryErr, SendErr};
+use service_base::msg::{Msg};
+use service_base::testkit::{chan_pair};
+
+use rustc_serialize::json::{Json};
+
+use std::thread::{spawn, JoinHandle};
+
+#[test]
+fn test_handler_panic_surfaces_details() {
+  let (mut client, mut server) = chan_pair::<()>();
+  let h = spawn(move || {
+    let mut halts = Vec::new();
+    for _ in 0 .. 2 {
+      halts.push(server.reply(|query: &Msg| {
+        if let &Msg::OKQ = query {
+          panic!("no OKs today");
+        }
+        Msg::Bot
+      }).unwrap());
+    }
+    halts
+  });
+  match client.query(&Msg::OKQ) {
+    Err(QueryErr::Bot(details)) => {
+      let details = Json::from_str(&details).unwrap();
+      assert_eq!(details.find("error").and_then(|e| e.as_string()), Some("panic"));
+      assert_eq!(details.find("message").and_then(|e| e.as_string()), Some("no OKs today"));
+      assert_eq!(details.find("tag").and_then(|e| e.as_string()), Some("OK?"));
+    }
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  // The connection carries on after the panic; a plain `Bot` is still a
+  // reply.
+  match client.query(&Msg::JSO(Json::Null)) {
+    Ok(Msg::Bot) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  assert_eq!(h.join().unwrap(), vec![false, false]);
+}
+
+// Replies `OKR` to `n` queries.
+fn serve_ok(n: usize) -> (Chan, JoinHandle<()>) {
+  let (client, mut server) = chan_pair::<()>();
+  let h = spawn(move || {
+    for _ in 0 .. n {
+      assert!(!server.reply(|_: &Msg| Msg::OKR).unwrap());
+    }
+  });
+  (client, h)
+}
+
+fn recv_ok(client: &mut Chan, seq: u64) {
+  match client.recv() {
+    Ok((Msg::OKR, rseq)) if rseq == seq => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+}
+
+#[test]
+fn test_flow_window_would_block() {
+  let (mut client, h) = serve_ok(3);
+  client.set_flow_window(Some(FlowWindow{max_unanswered: 2, mode: FlowMode::WouldBlock}));
+  assert_eq!(client.send(&Msg::OKQ).unwrap(), 1);
+  assert_eq!(client.send(&Msg::OKQ).unwrap(), 2);
+  assert!(matches!(client.send(&Msg::OKQ), Err(SendErr::WouldBlock)));
+  assert_eq!(client.unanswered(), 2);
+  recv_ok(&mut client, 1);
+  assert_eq!(client.send(&Msg::OKQ).unwrap(), 3);
+  recv_ok(&mut client, 2);
+  recv_ok(&mut client, 3);
+  h.join().unwrap();
+}
+
+#[test]
+fn test_flow_window_block() {
+  let (mut client, h) = serve_ok(3);
+  client.set_flow_window(Some(FlowWindow{max_unanswered: 1, mode: FlowMode::Block}));
+  // Each send waits for the reply to the previous query, which is kept
+  // for `recv`.
+  for seq in 1 ..= 3 {
+    assert_eq!(client.send(&Msg::OKQ).unwrap(), seq);
+  }
+  assert_eq!(client.unanswered(), 1);
+  for seq in 1 ..= 3 {
+    recv_ok(&mut client, seq);
+  }
+  assert_eq!(client.unanswered(), 0);
+  h.join().unwrap();
+}
+
+#[test]
+fn test_flow_window_zero_is_unlimited() {
+  let (mut client, h) = serve_ok(4);
+  client.set_flow_window(Some(FlowWindow{max_unanswered: 0, mode: FlowMode::WouldBlock}));
+  for seq in 1 ..= 4 {
+    assert_eq!(client.send(&Msg::OKQ).unwrap(), seq);
+  }
+  for seq in 1 ..= 4 {
+    recv_ok(&mut client, seq);
+  }
+  h.join().unwrap();
+}
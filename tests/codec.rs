@@ -0,0 +1,181 @@
+extern crate service_base;
+
+use service_base::chan::{Chan, QueryErr, RecvErr, ReplyErr, SendErr};
+use service_base::msg::*;
+use service_base::testkit::{chan_pair, mem_stream_pair};
+
+use std::thread::{spawn};
+
+// A text-only codec, through the blanket `WireCodex` adapter.
+#[derive(PartialEq, Debug)]
+enum Note {
+  Text(String),
+  // Has no wire encoding.
+  Unsent,
+}
+
+impl MsgCodex for Note {
+  fn wire_tags() -> Vec<[u8; 3]> {
+    vec![*b"NT?"]
+  }
+
+  fn encode_wire(&self, buf: &mut String) -> Result<[u8; 3], ()> {
+    match self {
+      &Note::Text(ref s) => {
+        buf.push_str(s);
+        Ok(*b"NT?")
+      }
+      &Note::Unsent => Err(())
+    }
+  }
+
+  fn decode_wire(tag: [u8; 3], buf: &[u8]) -> Result<Note, ()> {
+    if &tag != b"NT?" {
+      return Err(());
+    }
+    String::from_utf8(buf.to_vec()).map(Note::Text).map_err(|_| ())
+  }
+}
+
+// Raw bytes, which a `MsgCodex` could only carry as text.
+#[derive(PartialEq, Debug)]
+struct Blob(Vec<u8>);
+
+impl WireCodex for Blob {
+  fn wire_tags() -> Vec<[u8; 3]> {
+    vec![*b"BLB"]
+  }
+
+  fn encode_bytes(&self, buf: &mut Vec<u8>) -> Result<[u8; 3], CodecErr> {
+    if self.0.is_empty() {
+      return Err(CodecErr::Unsupported);
+    }
+    buf.extend_from_slice(&self.0);
+    Ok(*b"BLB")
+  }
+
+  fn decode_bytes(tag: [u8; 3], buf: &[u8]) -> Result<Blob, CodecErr> {
+    if &tag != b"BLB" {
+      return Err(CodecErr::Tag(tag));
+    }
+    if buf.is_empty() {
+      return Err(CodecErr::Truncated);
+    }
+    Ok(Blob(buf.to_vec()))
+  }
+}
+
+#[test]
+fn test_msg_codex_adapter() {
+  assert_eq!(<Note as WireCodex>::wire_tags(), vec![*b"NT?"]);
+  let mut buf = Vec::new();
+  assert_eq!(Note::Text("héllo".to_string()).encode_bytes(&mut buf).unwrap(), *b"NT?");
+  assert_eq!(buf, "héllo".as_bytes());
+  assert_eq!(<Note as WireCodex>::decode_bytes(*b"NT?", &buf).unwrap(), Note::Text("héllo".to_string()));
+  assert!(matches!(Note::Unsent.encode_bytes(&mut Vec::new()), Err(CodecErr::Opaque)));
+  assert!(matches!(<Note as WireCodex>::decode_bytes(*b"NT?", b"\xff"), Err(CodecErr::Opaque)));
+
+  let (mut client, mut server) = chan_pair::<Note>();
+  let h = spawn(move || {
+    server.reply(|query: &Msg<Note>| {
+      match query {
+        &Msg::Ext(Note::Text(ref s)) => Msg::Ext(Note::Text(s.to_uppercase())),
+        _ => Msg::Bot
+      }
+    }).unwrap()
+  });
+  match client.query(&Msg::Ext(Note::Text("abc".to_string()))) {
+    Ok(Msg::Ext(Note::Text(ref s))) if s == "ABC" => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  assert!(!h.join().unwrap());
+}
+
+#[test]
+fn test_borrowed_decode_via_recv_raw() {
+  let (mut client, mut server) = chan_pair::<Blob>();
+  let payload = vec![0, 0xff, b'x', 0x80];
+  client.send(&Msg::Ext(Blob(payload.clone()))).unwrap();
+  client.send(&Msg::Ext(Blob(b"text".to_vec()))).unwrap();
+  {
+    let (hdr, buf) = server.recv_raw().unwrap();
+    assert_eq!((hdr.seq, hdr.tag), (1, *b"BLB"));
+    let bytes = <&[u8]>::decode_ref(hdr.tag, buf).unwrap();
+    assert_eq!(bytes, &payload[..]);
+    assert!(matches!(<&str>::decode_ref(hdr.tag, buf), Err(CodecErr::Utf8)));
+  }
+  let (hdr, buf) = server.recv_raw().unwrap();
+  assert_eq!(hdr.seq, 2);
+  assert_eq!(<&str>::decode_ref(hdr.tag, buf).unwrap(), "text");
+}
+
+// Like `Blob`, but with an empty payload, which `Blob` refuses.
+#[derive(PartialEq, Debug)]
+struct LaxBlob(Vec<u8>);
+
+impl WireCodex for LaxBlob {
+  fn wire_tags() -> Vec<[u8; 3]> {
+    vec![*b"BLB"]
+  }
+
+  fn encode_bytes(&self, buf: &mut Vec<u8>) -> Result<[u8; 3], CodecErr> {
+    buf.extend_from_slice(&self.0);
+    Ok(*b"BLB")
+  }
+
+  fn decode_bytes(_tag: [u8; 3], buf: &[u8]) -> Result<LaxBlob, CodecErr> {
+    Ok(LaxBlob(buf.to_vec()))
+  }
+}
+
+fn lax_pair() -> (Chan<Blob>, Chan<LaxBlob>) {
+  let (a, b) = mem_stream_pair();
+  (Chan::from_stream(Box::new(a)), Chan::from_stream(Box::new(b)))
+}
+
+#[test]
+fn test_codec_errors_reach_the_caller() {
+  let (mut client, mut server) = lax_pair();
+  // An encode error is a `SendErr`, and uses up neither the stream nor a
+  // seq.
+  match client.send(&Msg::Ext(Blob(Vec::new()))) {
+    Err(SendErr::Codec(CodecErr::Unsupported)) => {}
+    x => panic!("unexpected send: {:?}", x),
+  }
+  match client.query(&Msg::Ext(Blob(Vec::new()))) {
+    Err(QueryErr::Send(SendErr::Codec(CodecErr::Unsupported))) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  assert!(!client.is_broken());
+  assert_eq!(client.seqs(), (0, 0));
+
+  let h = spawn(move || {
+    for reply in vec![Vec::new(), b"ok".to_vec()].into_iter() {
+      server.reply(move |_: &Msg<LaxBlob>| Msg::Ext(LaxBlob(reply.clone()))).unwrap();
+    }
+  });
+  match client.query(&Msg::Ext(Blob(b"a".to_vec()))) {
+    Err(QueryErr::Recv(RecvErr::Codec(CodecErr::Truncated))) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  // A decode error consumes the frame, so the chan stays in step.
+  assert!(!client.is_broken());
+  match client.query(&Msg::Ext(Blob(b"b".to_vec()))) {
+    Ok(Msg::Ext(Blob(ref x))) if x == b"ok" => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  h.join().unwrap();
+
+  // Likewise for a query that fails to decode.
+  let (mut server, mut client) = lax_pair();
+  client.send(&Msg::Ext(LaxBlob(Vec::new()))).unwrap();
+  match server.reply(|_: &Msg<Blob>| Msg::OKR) {
+    Err(ReplyErr::Recv(RecvErr::Codec(CodecErr::Truncated))) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  client.send(&Msg::Ext(LaxBlob(b"c".to_vec()))).unwrap();
+  match server.recv() {
+    Ok((Msg::Ext(Blob(ref x)), 2)) if x == b"c" => {}
+    x => panic!("unexpected query: {:?}", x),
+  }
+}
@@ -0,0 +1,96 @@
+extern crate service_base;
+
+use service_base::chan::{Chan};
+use service_base::ctx::*;
+use service_base::msg::{Msg};
+use service_base::testkit::{chan_pair};
+
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixStream};
+use std::thread::{sleep, spawn};
+use std::time::{Duration as StdDuration, Instant, SystemTime};
+
+#[derive(PartialEq, Debug)]
+struct User(String);
+
+#[test]
+fn test_conn_ids_are_unique() {
+  let a = ConnCtx::new(PeerAddr::Unknown);
+  let b = ConnCtx::new(PeerAddr::Unknown);
+  assert_ne!(a.id(), b.id());
+  let (c1, c2) = chan_pair::<()>();
+  assert_ne!(c1.ctx().id(), c2.ctx().id());
+  assert_ne!(c1.ctx().id(), a.id());
+}
+
+#[test]
+fn test_typed_storage() {
+  let mut ctx = ConnCtx::new(PeerAddr::Unknown);
+  assert!(ctx.get::<User>().is_none());
+  assert!(ctx.insert(User("ann".to_string())).is_none());
+  assert!(ctx.insert(7_u32).is_none());
+  assert_eq!(ctx.insert(User("bob".to_string())), Some(User("ann".to_string())));
+  *ctx.get_mut::<u32>().unwrap() += 1;
+  assert_eq!(ctx.get::<u32>(), Some(&8));
+  assert_eq!(ctx.get::<User>(), Some(&User("bob".to_string())));
+  assert_eq!(ctx.remove::<User>(), Some(User("bob".to_string())));
+  assert!(ctx.get::<User>().is_none());
+  assert_eq!(ctx.get::<u32>(), Some(&8));
+}
+
+#[test]
+fn test_start_time() {
+  let before_i = Instant::now();
+  let before_t = SystemTime::now();
+  let ctx = ConnCtx::new(PeerAddr::Unknown);
+  assert!(ctx.started_at() >= before_i && ctx.started_at() <= Instant::now());
+  assert!(ctx.start_time() >= before_t && ctx.start_time() <= SystemTime::now());
+  sleep(StdDuration::from_millis(10));
+  assert!(ctx.started_at().elapsed() >= StdDuration::from_millis(10));
+}
+
+#[test]
+fn test_handler_sees_seq_tag_and_storage() {
+  let (mut client, mut server) = chan_pair::<()>();
+  let h = spawn(move || {
+    let mut seen = Vec::new();
+    for _ in 0 .. 3 {
+      server.reply_ctx(|ctx: &mut ConnCtx, query: &Msg| {
+        seen.push((ctx.seq(), ctx.tag(), ctx.get::<User>().map(|u| u.0.clone())));
+        if let &Msg::OKQ = query {
+          ctx.insert(User("ann".to_string()));
+        }
+        Msg::OKR
+      }).unwrap();
+    }
+    seen
+  });
+  for query in [Msg::OKQ, Msg::OKQ, Msg::OKR].iter() {
+    client.query(query).unwrap();
+  }
+  assert_eq!(h.join().unwrap(), vec![
+    (1, *b"OK?", None),
+    (2, *b"OK?", Some("ann".to_string())),
+    (3, *b"OK.", Some("ann".to_string())),
+  ]);
+}
+
+#[test]
+fn test_peer_addr() {
+  let bind = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = bind.local_addr().unwrap();
+  let client = TcpStream::connect(addr).unwrap();
+  let client_addr = client.local_addr().unwrap();
+  let (server, _) = bind.accept().unwrap();
+  let chan = Chan::<()>::from_stream(Box::new(server));
+  match chan.ctx().peer() {
+    &PeerAddr::Tcp(a) if a == client_addr => {}
+    x => panic!("unexpected peer: {:?}", x),
+  }
+  let (a, _b) = UnixStream::pair().unwrap();
+  let chan = Chan::<()>::from_stream(Box::new(a));
+  assert!(matches!(chan.ctx().peer(), &PeerAddr::Unix));
+  let mut ctx = ConnCtx::new(PeerAddr::Unknown);
+  ctx.set_peer(PeerAddr::Tcp(addr));
+  assert!(matches!(ctx.peer(), &PeerAddr::Tcp(a) if a == addr));
+}
@@ -0,0 +1,116 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::chan::{QueryErr, SendErr};
+use service_base::ctx::{ConnCtx};
+use service_base::deadline::{self, with_deadline};
+use service_base::frame::{FrameMeta};
+use service_base::msg::{Msg};
+use service_base::testkit::{chan_pair};
+
+use rustc_serialize::json::{Json};
+
+use std::str::{from_utf8};
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::thread::{sleep, spawn};
+use std::time::{Duration as StdDuration, Instant};
+
+// The remaining time seen by the handler, in milliseconds, or null.
+fn remaining_ms() -> Msg {
+  Msg::JSO(match deadline::remaining() {
+    None => Json::Null,
+    Some(d) => Json::U64(d.as_millis() as u64)
+  })
+}
+
+#[test]
+fn test_expired_query_is_not_dispatched() {
+  let (mut client, mut server) = chan_pair::<()>();
+  let called = Arc::new(AtomicBool::new(false));
+  let called2 = called.clone();
+  let h = spawn(move || {
+    server.reply(move |_: &Msg| {
+      called2.store(true, AtomicOrdering::SeqCst);
+      Msg::OKR
+    }).unwrap()
+  });
+  // Past deadlines go out as zero time left; `query_with` would refuse
+  // to send one at all.
+  let mut meta = FrameMeta::default();
+  meta.deadline = Some(Instant::now());
+  match client.query_with(&Msg::OKQ, &meta) {
+    Err(QueryErr::Deadline) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  assert_eq!(client.seqs(), (0, 0));
+  assert_eq!(client.send_with(&Msg::OKQ, &meta).unwrap(), 1);
+  let (hdr, buf) = client.recv_raw().unwrap();
+  assert_eq!((hdr.seq, hdr.tag), (1, *b"!!!"));
+  let details = Json::from_str(from_utf8(buf).unwrap()).unwrap();
+  assert_eq!(details.find("error").and_then(|e| e.as_string()), Some("deadline"));
+  assert_eq!(details.find("tag").and_then(|e| e.as_string()), Some("OK?"));
+  assert!(!h.join().unwrap());
+  assert!(!called.load(AtomicOrdering::SeqCst));
+}
+
+#[test]
+fn test_client_timeout_breaks_chan() {
+  let (mut client, mut server) = chan_pair::<()>();
+  let h = spawn(move || {
+    server.reply(|_: &Msg| {
+      sleep(StdDuration::from_millis(200));
+      Msg::OKR
+    })
+  });
+  let t0 = Instant::now();
+  match client.query_timeout(&Msg::OKQ, StdDuration::from_millis(20)) {
+    Err(QueryErr::Deadline) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  assert!(t0.elapsed() < StdDuration::from_millis(200));
+  // The late reply would be taken for the reply to the next query.
+  assert!(client.is_broken());
+  match client.query(&Msg::OKQ) {
+    Err(QueryErr::Send(SendErr::Broken)) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  drop(client);
+  let _ = h.join().unwrap();
+}
+
+#[test]
+fn test_nested_query_inherits_deadline() {
+  let (mut front, mut front_srv) = chan_pair::<()>();
+  let (mut back, mut back_srv) = chan_pair::<()>();
+  let back_h = spawn(move || {
+    for _ in 0 .. 2 {
+      back_srv.reply(|_: &Msg| remaining_ms()).unwrap();
+    }
+  });
+  let front_h = spawn(move || {
+    for _ in 0 .. 2 {
+      front_srv.reply_ctx(|_: &mut ConnCtx, _: &Msg| {
+        back.query(&Msg::OKQ).unwrap()
+      }).unwrap();
+    }
+  });
+  match front.query_timeout(&Msg::OKQ, StdDuration::from_secs(2)) {
+    Ok(Msg::JSO(Json::U64(ms))) => assert!(ms > 0 && ms <= 2000, "remaining: {}", ms),
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  // Without a deadline, there is nothing to inherit.
+  match front.query(&Msg::OKQ) {
+    Ok(Msg::JSO(Json::Null)) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  front_h.join().unwrap();
+  back_h.join().unwrap();
+
+  // Outside of a handler, `with_deadline` sets the deadline to inherit.
+  assert!(deadline::deadline().is_none());
+  let d = Instant::now() + StdDuration::from_secs(1);
+  assert_eq!(with_deadline(Some(d), deadline::deadline), Some(d));
+  assert!(with_deadline(Some(Instant::now()), deadline::expired));
+  assert!(deadline::deadline().is_none());
+}
@@ -0,0 +1,66 @@
+extern crate service_base;
+
+use service_base::{MsgCodex};
+use service_base::msg::{Msg, WireCodex};
+use service_base::testkit::{chan_pair};
+
+use std::thread::{spawn};
+
+#[derive(MsgCodex, PartialEq, Debug)]
+enum Ping {
+  #[tag = "PI?"]
+  Ping,
+  #[tag = "PI."]
+  Pong(u64),
+  #[tag = "PIP"]
+  Pair(String, Vec<u32>),
+  #[tag = "PIN"]
+  Named{seq: u64, note: Option<String>},
+}
+
+fn round_trip(x: Ping) {
+  let mut buf = Vec::new();
+  let tag = x.encode_bytes(&mut buf).unwrap();
+  assert_eq!(Ping::decode_bytes(tag, &buf).unwrap(), x);
+}
+
+#[test]
+fn test_derive_round_trip() {
+  assert_eq!(<Ping as WireCodex>::wire_tags(), vec![*b"PI?", *b"PI.", *b"PIP", *b"PIN"]);
+  let mut buf = Vec::new();
+  assert_eq!(Ping::Ping.encode_bytes(&mut buf).unwrap(), *b"PI?");
+  assert!(buf.is_empty());
+  round_trip(Ping::Ping);
+  round_trip(Ping::Pong(7));
+  round_trip(Ping::Pair("a".to_string(), vec![1, 2]));
+  round_trip(Ping::Named{seq: 3, note: None});
+  round_trip(Ping::Named{seq: 4, note: Some("n".to_string())});
+  // A unit variant has no payload, and a payload must match its variant.
+  assert!(Ping::decode_bytes(*b"PI?", b"1").is_err());
+  assert!(Ping::decode_bytes(*b"PI.", b"\"x\"").is_err());
+  assert!(Ping::decode_bytes(*b"PIX", b"").is_err());
+}
+
+#[test]
+fn test_derive_over_chan() {
+  let (mut client, mut server) = chan_pair::<Ping>();
+  let h = spawn(move || {
+    server.reply(|query: &Msg<Ping>| {
+      match query {
+        &Msg::Ext(Ping::Named{seq, ..}) => Msg::Ext(Ping::Pong(seq + 1)),
+        _ => Msg::Bot
+      }
+    }).unwrap()
+  });
+  match client.query(&Msg::Ext(Ping::Named{seq: 41, note: None})) {
+    Ok(Msg::Ext(Ping::Pong(42))) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  assert!(!h.join().unwrap());
+}
+
+#[test]
+fn test_derive_compile_fail() {
+  let t = trybuild::TestCases::new();
+  t.compile_fail("tests/ui/*.rs");
+}
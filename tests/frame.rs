@@ -0,0 +1,181 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::chan::{RecvErr};
+use service_base::frame::*;
+use service_base::http::{HttpPayload, HttpRequest, HttpResponse};
+use service_base::msg::{Msg};
+use service_base::route::{GET, POST};
+use service_base::trace::{TraceCtx};
+
+use rustc_serialize::json::{Json};
+
+use std::collections::{BTreeMap};
+use std::time::{Duration as StdDuration, Instant};
+
+// A small xorshift generator, so that failures are reproducible from
+// the seed alone.
+struct Rng(u64);
+
+impl Rng {
+  fn next(&mut self) -> u64 {
+    let mut x = self.0;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    self.0 = x;
+    x
+  }
+
+  fn below(&mut self, n: u64) -> u64 {
+    self.next() % n
+  }
+
+  fn bytes(&mut self, len: usize) -> Vec<u8> {
+    (0 .. len).map(|_| self.next() as u8).collect()
+  }
+
+  fn string(&mut self) -> String {
+    const CHARS: &'static [char] = &['a', 'Z', '0', ' ', '"', '\\', '/', '\n', '\t', '\u{1}', 'é', '∀', '😀'];
+    let len = self.below(12) as usize;
+    (0 .. len).map(|_| CHARS[self.below(CHARS.len() as u64) as usize]).collect()
+  }
+
+  fn json(&mut self, depth: u32) -> Json {
+    let k = if depth == 0 { self.below(5) } else { self.below(7) };
+    match k {
+      0 => Json::Null,
+      1 => Json::Boolean(self.below(2) == 0),
+      2 => Json::U64(self.next()),
+      3 => Json::I64(-((self.next() >> 1) as i64) - 1),
+      4 => Json::String(self.string()),
+      5 => Json::Array((0 .. self.below(4)).map(|_| self.json(depth - 1)).collect()),
+      _ => {
+        let mut obj = BTreeMap::new();
+        for _ in 0 .. self.below(4) {
+          obj.insert(self.string(), self.json(depth - 1));
+        }
+        Json::Object(obj)
+      }
+    }
+  }
+
+  fn msg(&mut self) -> Msg {
+    match self.below(8) {
+      0 => Msg::Top,
+      1 => Msg::HUP,
+      2 => Msg::OKQ,
+      3 => Msg::OKR,
+      4 => {
+        let mut params = BTreeMap::new();
+        params.insert(self.string().into(), self.string().into());
+        Msg::H1Q(HttpRequest{
+          method: if self.below(2) == 0 { GET } else { POST },
+          path: (0 .. self.below(4)).map(|_| self.string().into()).collect(),
+          params,
+          auth: if self.below(2) == 0 { None } else { Some(self.bytes(8).into()) },
+          cookies: BTreeMap::new(),
+          payload: if self.below(2) == 0 { None } else { Some(HttpPayload::Utf8(None, None, self.string())) },
+        })
+      }
+      5 => Msg::H1P(HttpResponse::ok().with_payload_str(self.string())),
+      6 => Msg::JSO(self.json(3)),
+      _ => Msg::Bot
+    }
+  }
+
+  fn meta(&mut self) -> FrameMeta {
+    let mut meta = FrameMeta::default();
+    if self.below(2) == 0 {
+      meta.deadline = Some(Instant::now() + StdDuration::from_millis(self.below(10_000)));
+    }
+    if self.below(2) == 0 {
+      meta.trace = Some(TraceCtx{
+        trace_id: ((self.next() as u128) << 64) | (self.next() as u128),
+        span_id: self.next() | 1,
+        parent_span_id: if self.below(2) == 0 { None } else { Some(self.next() | 1) },
+      });
+    }
+    meta
+  }
+}
+
+fn encode(msg: &Msg) -> ([u8; 3], Vec<u8>) {
+  let mut buf = Vec::new();
+  let tag = encode_msg(msg, &mut buf).unwrap();
+  (tag, buf)
+}
+
+#[test]
+fn test_roundtrip_builtin_variants() {
+  let mut rng = Rng(0x9e3779b97f4a7c15);
+  for _ in 0 .. 2000 {
+    let msg = rng.msg();
+    let meta = rng.meta();
+    let seq = rng.next();
+    let mut frame = Vec::new();
+    encode_frame(&msg, seq, &meta, &mut frame).unwrap();
+    let (msg2, hdr, n) = decode_frame::<()>(&frame).unwrap();
+    assert_eq!(n, frame.len());
+    assert_eq!(hdr.seq, seq);
+    assert_eq!(encode(&msg2), encode(&msg));
+    assert_eq!(hdr.tag, encode(&msg).0);
+    assert_eq!(hdr.meta.trace, meta.trace);
+    assert_eq!(hdr.meta.deadline.is_some(), meta.deadline.is_some());
+  }
+}
+
+#[test]
+fn test_arbitrary_input_never_panics() {
+  let mut rng = Rng(0x2545f4914f6cdd1d);
+  for _ in 0 .. 20000 {
+    let len = rng.below(96) as usize;
+    let buf = rng.bytes(len);
+    let _ = decode_frame::<()>(&buf);
+  }
+}
+
+#[test]
+fn test_mutated_frames_never_panic() {
+  let mut rng = Rng(0xd1b54a32d192ed03);
+  for _ in 0 .. 5000 {
+    let msg = rng.msg();
+    let meta = rng.meta();
+    let mut frame = Vec::new();
+    encode_frame(&msg, rng.next(), &meta, &mut frame).unwrap();
+    for _ in 0 .. 1 + rng.below(4) {
+      let i = rng.below(frame.len() as u64) as usize;
+      frame[i] = rng.next() as u8;
+    }
+    let cut = rng.below(frame.len() as u64 + 1) as usize;
+    let _ = decode_frame::<()>(&frame[ .. cut]);
+    let _ = decode_frame::<()>(&frame);
+  }
+}
+
+#[test]
+fn test_oversized_length_is_rejected_before_allocating() {
+  let mut hdr = [0; FRAME_HDR_LEN];
+  hdr[0] = 1;
+  hdr[8 .. 11].copy_from_slice(b"JSO");
+  hdr[12 .. 16].copy_from_slice(&u32::max_value().to_le_bytes());
+  match decode_frame_fixed(&hdr) {
+    Err(RecvErr::Overflow) => {}
+    res => panic!("expected overflow, got: {:?}", res)
+  }
+  match decode_frame::<()>(&hdr) {
+    Err(RecvErr::Overflow) => {}
+    res => panic!("expected overflow, got: {:?}", res.map(|(_, hdr, n)| (hdr, n)))
+  }
+}
+
+#[test]
+fn test_unknown_flags_are_rejected() {
+  let mut hdr = [0; FRAME_HDR_LEN];
+  hdr[8 .. 11].copy_from_slice(b"OK?");
+  hdr[11] = 0x80;
+  match decode_frame_fixed(&hdr) {
+    Err(RecvErr::Flags) => {}
+    res => panic!("expected flags error, got: {:?}", res)
+  }
+}
@@ -0,0 +1,188 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::chan::{Chan, ChanStream};
+use service_base::frame::{encode_frame_hdr, FrameMeta};
+use service_base::jsonrpc::*;
+use service_base::msg::{Msg};
+use service_base::testkit::{chan_pair, mem_stream_pair};
+
+use rustc_serialize::json::{Json};
+
+use std::io::{Write};
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::thread::{spawn};
+
+fn dispatcher(notes: Arc<AtomicUsize>) -> RpcDispatcher {
+  let mut rpc = RpcDispatcher::new();
+  rpc.insert_typed("add", |(a, b): (i64, i64)| Ok(a + b));
+  rpc.insert("fail", Box::new(|_: &RpcRequest| {
+    Err(RpcError::internal_error().with_data(Json::String("boom".to_string())))
+  }));
+  rpc.insert("note", Box::new(move |_: &RpcRequest| {
+    notes.fetch_add(1, AtomicOrdering::SeqCst);
+    Ok(Json::Null)
+  }));
+  rpc
+}
+
+fn json(s: &str) -> Json {
+  Json::from_str(s).unwrap()
+}
+
+// Params must be an array or an object, so `()` would not do.
+fn no_params() -> Vec<i64> {
+  Vec::new()
+}
+
+fn error_code(rep: &Json) -> Option<i64> {
+  rep.find_path(&["error", "code"]).and_then(|c| c.as_i64())
+}
+
+#[test]
+fn test_standard_error_codes() {
+  let rpc = dispatcher(Arc::new(AtomicUsize::new(0)));
+  let rep = rpc.handle(&json(r#"{"jsonrpc": "2.0", "method": "nope", "id": 1}"#)).unwrap();
+  assert_eq!(error_code(&rep), Some(RPC_METHOD_NOT_FOUND));
+  assert_eq!(rep.find("id"), Some(&Json::U64(1)));
+  let rep = rpc.handle(&json(r#"{"jsonrpc": "2.0", "method": "add", "params": ["x", 2], "id": 2}"#)).unwrap();
+  assert_eq!(error_code(&rep), Some(RPC_INVALID_PARAMS));
+  let rep = rpc.handle(&json(r#"{"jsonrpc": "2.0", "method": "fail", "id": 3}"#)).unwrap();
+  assert_eq!(error_code(&rep), Some(RPC_INTERNAL_ERROR));
+  assert_eq!(rep.find_path(&["error", "data"]), Some(&Json::String("boom".to_string())));
+  // A bad version still echoes the id; a bad id cannot be echoed.
+  let rep = rpc.handle(&json(r#"{"jsonrpc": "1.0", "method": "add", "id": 4}"#)).unwrap();
+  assert_eq!(error_code(&rep), Some(RPC_INVALID_REQUEST));
+  assert_eq!(rep.find("id"), Some(&Json::U64(4)));
+  let rep = rpc.handle(&json(r#"{"jsonrpc": "2.0", "method": "add", "id": [4]}"#)).unwrap();
+  assert_eq!(error_code(&rep), Some(RPC_INVALID_REQUEST));
+  assert_eq!(rep.find("id"), Some(&Json::Null));
+  let rep = rpc.handle(&json("[]")).unwrap();
+  assert_eq!(error_code(&rep), Some(RPC_INVALID_REQUEST));
+}
+
+#[test]
+fn test_notifications_get_no_response() {
+  let notes = Arc::new(AtomicUsize::new(0));
+  let rpc = dispatcher(notes.clone());
+  assert!(rpc.handle(&json(r#"{"jsonrpc": "2.0", "method": "note"}"#)).is_none());
+  // Even a failing notification is not answered.
+  assert!(rpc.handle(&json(r#"{"jsonrpc": "2.0", "method": "nope"}"#)).is_none());
+  assert!(rpc.handle(&json(r#"[{"jsonrpc": "2.0", "method": "note"}, {"jsonrpc": "2.0", "method": "note"}]"#)).is_none());
+  assert_eq!(notes.load(AtomicOrdering::SeqCst), 3);
+  match rpc.handle_msg::<()>(&Msg::JSO(json(r#"{"jsonrpc": "2.0", "method": "note"}"#))) {
+    Msg::OKR => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+}
+
+#[test]
+fn test_batch_mixed() {
+  let rpc = dispatcher(Arc::new(AtomicUsize::new(0)));
+  let rep = rpc.handle(&json(r#"[
+    {"jsonrpc": "2.0", "method": "add", "params": [1, 2], "id": "a"},
+    {"jsonrpc": "2.0", "method": "note"},
+    1,
+    {"jsonrpc": "2.0", "method": "nope", "id": "b"}
+  ]"#)).unwrap();
+  let reps = rep.as_array().unwrap();
+  assert_eq!(reps.len(), 3);
+  assert_eq!(reps[0].find("result"), Some(&Json::U64(3)));
+  assert_eq!(error_code(&reps[1]), Some(RPC_INVALID_REQUEST));
+  assert_eq!(reps[1].find("id"), Some(&Json::Null));
+  assert_eq!(error_code(&reps[2]), Some(RPC_METHOD_NOT_FOUND));
+}
+
+#[test]
+fn test_client_call_notify_batch() {
+  let notes = Arc::new(AtomicUsize::new(0));
+  let handler = dispatcher(notes.clone()).into_handler::<()>();
+  let (client, mut server) = chan_pair::<()>();
+  let h = spawn(move || {
+    // Runs until the client hangs up.
+    while let Ok(false) = server.reply(|query: &Msg| (handler)(query)) {}
+  });
+  let mut client = RpcClient::new(client);
+  let sum: i64 = client.call("add", &(2i64, 3i64)).unwrap();
+  assert_eq!(sum, 5);
+  match client.call::<_, _, i64>("nope", &no_params()) {
+    Err(RpcCallErr::Rpc(ref e)) if e.code == RPC_METHOD_NOT_FOUND => {}
+    x => panic!("unexpected result: {:?}", x),
+  }
+  client.notify("note", &no_params()).unwrap();
+  assert_eq!(notes.load(AtomicOrdering::SeqCst), 1);
+  // The second request has an id the server cannot echo, so its error
+  // comes back with a `null` id.
+  let add = client.request("add", &(1i64, 1i64)).unwrap();
+  let bad = RpcRequest::new("add", None, Json::Boolean(true));
+  let fail = client.request("fail", &no_params()).unwrap();
+  let note = RpcRequest::notification("note", None);
+  let reps = client.batch(&[add, bad, note, fail]).unwrap();
+  assert_eq!(reps.len(), 3);
+  assert_eq!(reps[0].decode_result::<i64>().unwrap(), 2);
+  match reps[1].result {
+    Err(ref e) if e.code == RPC_INVALID_REQUEST => {}
+    ref x => panic!("unexpected result: {:?}", x),
+  }
+  match reps[2].result {
+    Err(ref e) if e.code == RPC_INTERNAL_ERROR => {}
+    ref x => panic!("unexpected result: {:?}", x),
+  }
+  assert_eq!(notes.load(AtomicOrdering::SeqCst), 2);
+  drop(client);
+  h.join().unwrap();
+}
+
+#[test]
+fn test_parse_error() {
+  let rpc = dispatcher(Arc::new(AtomicUsize::new(0)));
+  let rep = rpc.handle_bytes(br#"{"jsonrpc": "2.0", "method"#).unwrap();
+  assert_eq!(error_code(&rep), Some(RPC_PARSE_ERROR));
+  assert_eq!(rep.find("id"), Some(&Json::Null));
+  let rep = rpc.handle_bytes(br#"{"jsonrpc": "2.0", "method": "add", "params": [1, 2], "id": 1}"#).unwrap();
+  assert_eq!(rep.find("result"), Some(&Json::U64(3)));
+  // Over a connection, the unparseable query gets an answer, and the
+  // connection carries on.
+  let (mut raw, server) = mem_stream_pair();
+  let mut client = Chan::<()>::from_stream(raw.try_clone_stream().unwrap());
+  let mut frames = Vec::new();
+  encode_frame_hdr(&mut frames, 1, *b"JSO", &FrameMeta::default(), 5).unwrap();
+  frames.extend_from_slice(b"[1, 2");
+  encode_frame_hdr(&mut frames, 2, *b"HUP", &FrameMeta::default(), 0).unwrap();
+  raw.write_all(&frames).unwrap();
+  let h = spawn(move || {
+    let mut server = Chan::<()>::from_stream(Box::new(server));
+    let mut halts = Vec::new();
+    for _ in 0 .. 2 {
+      halts.push(rpc.reply(&mut server).unwrap());
+    }
+    halts
+  });
+  match client.recv() {
+    Ok((Msg::JSO(ref rep), 1)) => assert_eq!(error_code(rep), Some(RPC_PARSE_ERROR)),
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  assert_eq!(h.join().unwrap(), vec![false, true]);
+}
+
+#[test]
+fn test_batch_null_id_result() {
+  let handler = dispatcher(Arc::new(AtomicUsize::new(0))).into_handler::<()>();
+  let (client, mut server) = chan_pair::<()>();
+  let h = spawn(move || {
+    while let Ok(false) = server.reply(|query: &Msg| (handler)(query)) {}
+  });
+  let mut client = RpcClient::new(client);
+  let add = RpcRequest::new("add", Some(json("[2, 2]")), Json::Null);
+  let fail = client.request("fail", &no_params()).unwrap();
+  let reps = client.batch(&[add, fail]).unwrap();
+  assert_eq!(reps.len(), 2);
+  assert_eq!(reps[0].decode_result::<i64>().unwrap(), 4);
+  match reps[1].result {
+    Err(ref e) if e.code == RPC_INTERNAL_ERROR => {}
+    ref x => panic!("unexpected result: {:?}", x),
+  }
+  drop(client);
+  h.join().unwrap();
+}
@@ -0,0 +1,151 @@
+extern crate once_cell;
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::ctx::{ConnCtx};
+use service_base::logging::*;
+use service_base::middleware::{Layered, MiddlewareChain};
+use service_base::msg::{Msg};
+use service_base::service::{FnService};
+use service_base::testkit::{chan_pair};
+use service_base::trace::{TraceCtx, with_trace};
+
+use once_cell::sync::{Lazy};
+use rustc_serialize::json::{Json};
+
+use std::env::{temp_dir};
+use std::fs::{read_to_string, remove_file};
+use std::path::{PathBuf};
+use std::process::{id as process_id};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{spawn};
+
+// The logger is global; tests that configure it take turns.
+static ONCE_LOGGER_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+struct LogFile {
+  path: PathBuf,
+  _lock: MutexGuard<'static, ()>,
+}
+
+impl LogFile {
+  fn new(name: &str, level: LogLevel, format: LogFormat) -> LogFile {
+    let lock = ONCE_LOGGER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let path = temp_dir().join(format!("svc-log-test-{}-{}.log", name, process_id()));
+    let _ = remove_file(&path);
+    configure_logging(&LogConfig{
+      level,
+      format,
+      output: LogOutput::File(path.clone()),
+      level_signals: false,
+    }).unwrap();
+    LogFile{path, _lock: lock}
+  }
+
+  fn lines(&self) -> Vec<String> {
+    read_to_string(&self.path).unwrap().lines().map(|l| l.to_string()).collect()
+  }
+}
+
+impl Drop for LogFile {
+  fn drop(&mut self) {
+    let _ = configure_logging(&LogConfig::default());
+    let _ = remove_file(&self.path);
+  }
+}
+
+// Drops the leading timestamp of a logfmt line.
+fn strip_ts(line: &str) -> &str {
+  assert!(line.starts_with("ts="), "{}", line);
+  &line[line.find(' ').unwrap() + 1 ..]
+}
+
+#[test]
+fn test_logfmt() {
+  let f = LogFile::new("logfmt", LogLevel::Info, LogFormat::Logfmt);
+  log(LogLevel::Info, "query done", &[("seq", &7), ("tag", &"OK?"), ("note", &"a \"b\"\nc"), ("empty", &"")]);
+  log(LogLevel::Warn, "plain", &[]);
+  let lines = f.lines();
+  assert_eq!(lines.len(), 2);
+  assert_eq!(strip_ts(&lines[0]), "level=info msg=\"query done\" seq=7 tag=OK? note=\"a \\\"b\\\"\\nc\" empty=\"\"");
+  assert_eq!(strip_ts(&lines[1]), "level=warn msg=plain");
+  let ts: f64 = lines[0][3 .. lines[0].find(' ').unwrap()].parse().unwrap();
+  assert!(ts > 1.0e9);
+}
+
+#[test]
+fn test_json_lines() {
+  let f = LogFile::new("json", LogLevel::Info, LogFormat::JsonLines);
+  let trace = TraceCtx::root();
+  log(LogLevel::Error, "failed", &[("error", &"a \"b\"\nc"), ("n", &3)]);
+  with_trace(Some(trace), || log(LogLevel::Info, "traced", &[]));
+  let lines = f.lines();
+  assert_eq!(lines.len(), 2);
+  let j = Json::from_str(&lines[0]).unwrap();
+  let obj = j.as_object().unwrap();
+  assert_eq!(obj.keys().map(|k| &k[..]).collect::<Vec<_>>(), vec!["error", "level", "msg", "n", "ts"]);
+  assert_eq!(j.find("level").and_then(|x| x.as_string()), Some("error"));
+  assert_eq!(j.find("msg").and_then(|x| x.as_string()), Some("failed"));
+  assert_eq!(j.find("error").and_then(|x| x.as_string()), Some("a \"b\"\nc"));
+  // Field values are written as strings.
+  assert_eq!(j.find("n").and_then(|x| x.as_string()), Some("3"));
+  assert!(j.find("ts").unwrap().is_number());
+  let j = Json::from_str(&lines[1]).unwrap();
+  assert_eq!(j.find("trace_id").and_then(|x| x.as_string()), Some(&format!("{:032x}", trace.trace_id)[..]));
+  assert_eq!(j.find("span_id").and_then(|x| x.as_string()), Some(&format!("{:016x}", trace.span_id)[..]));
+}
+
+#[test]
+fn test_level_filtering() {
+  let f = LogFile::new("level", LogLevel::Warn, LogFormat::Logfmt);
+  assert!(log_enabled(LogLevel::Error) && log_enabled(LogLevel::Warn));
+  assert!(!log_enabled(LogLevel::Info));
+  for &level in [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace].iter() {
+    log(level, level.as_str(), &[]);
+  }
+  set_log_level(LogLevel::Debug);
+  assert_eq!(logger().level(), LogLevel::Debug);
+  log(LogLevel::Debug, "now", &[]);
+  log(LogLevel::Trace, "still not", &[]);
+  let lines: Vec<String> = f.lines().iter().map(|l| strip_ts(l).to_string()).collect();
+  assert_eq!(lines, vec!["level=error msg=error", "level=warn msg=warn", "level=debug msg=now"]);
+  assert_eq!(LogLevel::parse("trace"), Some(LogLevel::Trace));
+  assert_eq!(LogLevel::parse("verbose"), None);
+}
+
+fn level_reply(reply: Msg) -> Option<String> {
+  match reply {
+    Msg::JSO(ref j) => j.find(LOG_LEVEL_QUERY_KEY).and_then(|x| x.as_string()).map(|s| s.to_string()),
+    Msg::Bot => None,
+    x => panic!("unexpected reply: {:?}", x),
+  }
+}
+
+#[test]
+fn test_log_level_query() {
+  let f = LogFile::new("query", LogLevel::Info, LogFormat::Logfmt);
+  let mut chain = MiddlewareChain::new();
+  chain.push(Arc::new(LogLevelQuery));
+  let (mut client, mut server) = chan_pair::<()>();
+  let h = spawn(move || {
+    let mut svc = Layered::new(Arc::new(chain), FnService(|_: &mut ConnCtx, _: &Msg| Msg::OKR));
+    server.serve(&mut svc);
+  });
+  let q = |level: Option<LogLevel>| LogLevelQuery::query::<()>(level);
+  assert_eq!(level_reply(client.query(&q(None)).unwrap()), Some("info".to_string()));
+  assert_eq!(level_reply(client.query(&q(Some(LogLevel::Debug))).unwrap()), Some("debug".to_string()));
+  assert_eq!(logger().level(), LogLevel::Debug);
+  let mut bad = q(None);
+  if let Msg::JSO(Json::Object(ref mut obj)) = bad {
+    obj.insert(LOG_LEVEL_QUERY_KEY.to_string(), Json::String("verbose".to_string()));
+  }
+  assert_eq!(level_reply(client.query(&bad).unwrap()), None);
+  assert_eq!(logger().level(), LogLevel::Debug);
+  // Other queries, including other JSON objects, go to the handler.
+  assert!(matches!(client.query(&Msg::JSO(Json::Null)), Ok(Msg::OKR)));
+  drop(client);
+  h.join().unwrap();
+  let lines = f.lines();
+  assert_eq!(lines.len(), 1);
+  assert!(strip_ts(&lines[0]).starts_with("level=info msg=\"log level changed\" level=debug conn="), "{}", lines[0]);
+}
@@ -0,0 +1,74 @@
+extern crate service_base;
+
+use service_base::metrics::*;
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+#[test]
+fn test_render_prometheus() {
+  let reg = MetricsRegistry::new();
+  reg.counter("svc_queries_total", "Queries handled.", &[("tag", "OK?"), ("conn", "tcp")]).add(3);
+  reg.counter("svc_queries_total", "Queries handled.", &[("conn", "tcp"), ("tag", "JSO")]).inc();
+  let g = reg.gauge("svc_conns", "Open connections.", &[]);
+  g.set(5);
+  g.dec();
+  assert_eq!(reg.render_prometheus(), "\
+# HELP svc_conns Open connections.
+# TYPE svc_conns gauge
+svc_conns 4
+# HELP svc_queries_total Queries handled.
+# TYPE svc_queries_total counter
+svc_queries_total{conn=\"tcp\",tag=\"JSO\"} 1
+svc_queries_total{conn=\"tcp\",tag=\"OK?\"} 3
+");
+}
+
+#[test]
+fn test_histogram_buckets() {
+  let reg = MetricsRegistry::new();
+  let h = reg.histogram_with("svc_latency_seconds", "Latency.", &[("tag", "OK?")], &[0.1, 1.0]);
+  for &x in [0.25, 0.5, 4.0].iter() {
+    h.observe(x);
+  }
+  assert_eq!(h.count(), 3);
+  assert_eq!(h.sum(), 4.75);
+  // The bounds of an existing histogram stay put.
+  reg.histogram_with("svc_latency_seconds", "Latency.", &[("tag", "OK?")], &[2.0]).observe(0.0625);
+  assert_eq!(reg.render_prometheus(), "\
+# HELP svc_latency_seconds Latency.
+# TYPE svc_latency_seconds histogram
+svc_latency_seconds_bucket{tag=\"OK?\",le=\"0.1\"} 1
+svc_latency_seconds_bucket{tag=\"OK?\",le=\"1\"} 3
+svc_latency_seconds_bucket{tag=\"OK?\",le=\"+Inf\"} 4
+svc_latency_seconds_sum{tag=\"OK?\"} 4.8125
+svc_latency_seconds_count{tag=\"OK?\"} 4
+");
+}
+
+#[test]
+fn test_escaping() {
+  let reg = MetricsRegistry::new();
+  reg.counter("svc_errs", "Errors,\nby \\kind.", &[("kind", "a\"b\\c\nd")]).inc();
+  assert_eq!(reg.render_prometheus(), "\
+# HELP svc_errs Errors,\\nby \\\\kind.
+# TYPE svc_errs counter
+svc_errs{kind=\"a\\\"b\\\\c\\nd\"} 1
+");
+}
+
+#[test]
+fn test_kind_mismatch_keeps_registry_usable() {
+  let reg = MetricsRegistry::new();
+  reg.counter("svc_x", "X.", &[]).inc();
+  let res = catch_unwind(AssertUnwindSafe(|| {
+    reg.gauge("svc_x", "X.", &[("a", "b")]);
+  }));
+  assert!(res.is_err());
+  // No stray series, and the lock is not poisoned.
+  reg.counter("svc_x", "X.", &[]).inc();
+  assert_eq!(reg.render_prometheus(), "\
+# HELP svc_x X.
+# TYPE svc_x counter
+svc_x 2
+");
+}
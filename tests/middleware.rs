@@ -0,0 +1,145 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::chan::{Chan, SpawnPool};
+use service_base::ctx::{ConnCtx};
+use service_base::middleware::*;
+use service_base::msg::{Msg};
+use service_base::service::{FnService, Outcome};
+use service_base::testkit::{chan_pair};
+
+use rustc_serialize::json::{Json};
+
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+
+// Records the order in which it sees queries and outcomes.
+struct Trace {
+  name: &'static str,
+  events: Arc<Mutex<Vec<String>>>,
+}
+
+impl Middleware for Trace {
+  fn call(&self, ctx: &mut ConnCtx, query: &Msg, next: &mut dyn FnMut(&mut ConnCtx, &Msg) -> Outcome) -> Outcome {
+    self.events.lock().unwrap().push(format!("{} in", self.name));
+    let outcome = (next)(ctx, query);
+    self.events.lock().unwrap().push(format!("{} out", self.name));
+    outcome
+  }
+}
+
+// Answers `OKQ` itself, without calling the rest of the chain.
+struct ShortCircuit;
+
+impl Middleware for ShortCircuit {
+  fn call(&self, ctx: &mut ConnCtx, query: &Msg, next: &mut dyn FnMut(&mut ConnCtx, &Msg) -> Outcome) -> Outcome {
+    match query {
+      &Msg::OKQ => Msg::JSO(Json::String("short".to_string())).into(),
+      _ => (next)(ctx, query)
+    }
+  }
+}
+
+// Serves a `Layered` echo service (`OKQ` is answered with `OKR`, and
+// anything else with itself) over a `chan_pair`.
+fn serve_layered(chain: MiddlewareChain, events: Arc<Mutex<Vec<String>>>) -> (Chan, JoinHandle<()>) {
+  let (client, mut server) = chan_pair::<()>();
+  let h = spawn(move || {
+    let mut svc = Layered::new(Arc::new(chain), FnService(move |_: &mut ConnCtx, query: &Msg| {
+      events.lock().unwrap().push("handler".to_string());
+      match query {
+        &Msg::OKQ => Msg::OKR,
+        &Msg::JSO(ref j) => Msg::JSO(j.clone()),
+        _ => Msg::Bot
+      }
+    }));
+    server.serve(&mut svc);
+  });
+  (client, h)
+}
+
+fn take(events: &Arc<Mutex<Vec<String>>>) -> Vec<String> {
+  events.lock().unwrap().drain(..).collect()
+}
+
+#[test]
+fn test_middleware_order() {
+  let events = Arc::new(Mutex::new(Vec::new()));
+  let mut chain = MiddlewareChain::new();
+  chain.push(Arc::new(Trace{name: "a", events: events.clone()}));
+  chain.push(Arc::new(ShortCircuit));
+  chain.push(Arc::new(Trace{name: "b", events: events.clone()}));
+  assert_eq!(chain.len(), 3);
+  let (mut client, h) = serve_layered(chain, events.clone());
+  assert!(matches!(client.query(&Msg::JSO(Json::Null)), Ok(Msg::JSO(Json::Null))));
+  assert_eq!(take(&events), vec!["a in", "b in", "handler", "b out", "a out"]);
+  // The inner layers and the handler are skipped.
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::JSO(Json::String(ref s))) if s == "short" => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  assert_eq!(take(&events), vec!["a in", "a out"]);
+  drop(client);
+  h.join().unwrap();
+}
+
+#[test]
+fn test_tag_allow_list() {
+  let latency = Arc::new(Latency::new());
+  let mut chain = MiddlewareChain::new();
+  chain.push(latency.clone());
+  chain.push(Arc::new(TagAllowList::new(&[*b"JSO"])));
+  let events = Arc::new(Mutex::new(Vec::new()));
+  let (mut client, h) = serve_layered(chain, events.clone());
+  assert!(matches!(client.query(&Msg::OKQ), Ok(Msg::Bot)));
+  assert!(take(&events).is_empty());
+  assert!(matches!(client.query(&Msg::JSO(Json::U64(1))), Ok(Msg::JSO(Json::U64(1)))));
+  assert_eq!(take(&events), vec!["handler"]);
+  drop(client);
+  h.join().unwrap();
+  // Rejected queries go through the outer layers all the same.
+  assert_eq!(latency.stats().count, 2);
+  assert!(latency.stats().max <= latency.stats().total);
+}
+
+#[test]
+fn test_shared_secret_auth() {
+  let mut chain = MiddlewareChain::new();
+  chain.push(Arc::new(SharedSecretAuth::new("s3cret")));
+  let events = Arc::new(Mutex::new(Vec::new()));
+  let (mut client, h) = serve_layered(chain.clone(), events.clone());
+  // Nothing gets through before the auth query.
+  assert!(matches!(client.query(&Msg::JSO(Json::Null)), Ok(Msg::Bot)));
+  assert!(matches!(client.query(&SharedSecretAuth::auth_query("s3cret")), Ok(Msg::OKR)));
+  assert!(take(&events).is_empty());
+  // Once authenticated, the auth query is an ordinary query.
+  assert!(matches!(client.query(&Msg::OKQ), Ok(Msg::OKR)));
+  assert!(matches!(client.query(&SharedSecretAuth::auth_query("x")), Ok(Msg::JSO(_))));
+  assert_eq!(take(&events), vec!["handler", "handler"]);
+  drop(client);
+  h.join().unwrap();
+
+  // A wrong secret closes the connection.
+  let (mut client, h) = serve_layered(chain, events.clone());
+  assert!(matches!(client.query(&SharedSecretAuth::auth_query("guess")), Ok(Msg::Bot)));
+  h.join().unwrap();
+  assert!(client.query(&SharedSecretAuth::auth_query("s3cret")).is_err());
+  assert!(take(&events).is_empty());
+}
+
+#[test]
+fn test_spawn_pool_middleware() {
+  let bind = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = bind.local_addr().unwrap();
+  let mut pool = SpawnPool::<()>::new(bind);
+  pool.push_middleware(Arc::new(SharedSecretAuth::new("s3cret")));
+  pool.push_middleware(Arc::new(TagAllowList::new(&[*b"OK?"])));
+  // Never returns; the thread goes away with the test process.
+  spawn(move || pool.replying(Arc::new(|_: &Msg| Msg::OKR)));
+  let mut client = Chan::<()>::new(TcpStream::connect(addr).unwrap());
+  // The auth query is answered before the allow-list sees it.
+  assert!(matches!(client.query(&SharedSecretAuth::auth_query("s3cret")), Ok(Msg::OKR)));
+  assert!(matches!(client.query(&Msg::OKQ), Ok(Msg::OKR)));
+  assert!(matches!(client.query(&Msg::JSO(Json::Null)), Ok(Msg::Bot)));
+}
@@ -0,0 +1,193 @@
+extern crate service_base;
+
+use service_base::msg::*;
+use service_base::tag::{register_tags, TagErr};
+use service_base::testkit::{chan_pair};
+
+use std::convert::{TryInto};
+use std::str::{from_utf8};
+use std::thread::{spawn};
+
+// A text payload under a declared tag.
+#[derive(PartialEq, Debug)]
+struct Kv(String);
+
+impl WireCodex for Kv {
+  fn wire_tags() -> Vec<[u8; 3]> {
+    vec![*b"KV?"]
+  }
+
+  fn wire_size_hint(&self) -> usize {
+    self.0.len()
+  }
+
+  fn encode_bytes(&self, buf: &mut Vec<u8>) -> Result<[u8; 3], CodecErr> {
+    buf.extend_from_slice(self.0.as_bytes());
+    Ok(*b"KV?")
+  }
+
+  fn decode_bytes(tag: [u8; 3], buf: &[u8]) -> Result<Kv, CodecErr> {
+    if &tag != b"KV?" {
+      return Err(CodecErr::Tag(tag));
+    }
+    from_utf8(buf).map(|s| Kv(s.to_string())).map_err(|_| CodecErr::Utf8)
+  }
+}
+
+// Declares the same tag as `Kv`.
+#[derive(PartialEq, Debug)]
+struct Kv2;
+
+impl WireCodex for Kv2 {
+  fn wire_tags() -> Vec<[u8; 3]> {
+    vec![*b"KV2", *b"KV?"]
+  }
+
+  fn encode_bytes(&self, _buf: &mut Vec<u8>) -> Result<[u8; 3], CodecErr> {
+    Ok(*b"KV2")
+  }
+
+  fn decode_bytes(tag: [u8; 3], _buf: &[u8]) -> Result<Kv2, CodecErr> {
+    Err(CodecErr::Tag(tag))
+  }
+}
+
+// A little-endian u64 under a declared tag.
+#[derive(PartialEq, Debug)]
+struct Num(u64);
+
+impl WireCodex for Num {
+  fn wire_tags() -> Vec<[u8; 3]> {
+    vec![*b"NM?"]
+  }
+
+  fn wire_size_hint(&self) -> usize {
+    8
+  }
+
+  fn encode_bytes(&self, buf: &mut Vec<u8>) -> Result<[u8; 3], CodecErr> {
+    buf.extend_from_slice(&self.0.to_le_bytes());
+    Ok(*b"NM?")
+  }
+
+  fn decode_bytes(tag: [u8; 3], buf: &[u8]) -> Result<Num, CodecErr> {
+    if &tag != b"NM?" {
+      return Err(CodecErr::Tag(tag));
+    }
+    let x = buf.try_into().map_err(|_| CodecErr::Truncated)?;
+    Ok(Num(u64::from_le_bytes(x)))
+  }
+}
+
+// Takes any tag, and declares none.
+#[derive(PartialEq, Debug)]
+struct Raw([u8; 3], Vec<u8>);
+
+impl WireCodex for Raw {
+  fn encode_bytes(&self, buf: &mut Vec<u8>) -> Result<[u8; 3], CodecErr> {
+    buf.extend_from_slice(&self.1);
+    Ok(self.0)
+  }
+
+  fn decode_bytes(tag: [u8; 3], buf: &[u8]) -> Result<Raw, CodecErr> {
+    Ok(Raw(tag, buf.to_vec()))
+  }
+}
+
+fn encode<X: WireCodex>(x: &X) -> ([u8; 3], Vec<u8>) {
+  let mut buf = Vec::new();
+  let tag = x.encode_bytes(&mut buf).unwrap();
+  (tag, buf)
+}
+
+#[test]
+fn test_either_encodes_both_arms() {
+  type KvNum = Either<Kv, Num>;
+  assert_eq!(KvNum::wire_tags(), vec![*b"KV?", *b"NM?"]);
+  let left: KvNum = Either::Left(Kv("k=v".to_string()));
+  let right: KvNum = Either::Right(Num(7));
+  assert_eq!(left.wire_size_hint(), 3);
+  assert_eq!(right.wire_size_hint(), 8);
+  assert_eq!(encode(&left), (*b"KV?", b"k=v".to_vec()));
+  assert_eq!(encode(&right), (*b"NM?", 7_u64.to_le_bytes().to_vec()));
+  match KvNum::decode_bytes(*b"KV?", b"k=v") {
+    Ok(Either::Left(Kv(ref s))) if s == "k=v" => {}
+    x => panic!("unexpected decode: {:?}", x),
+  }
+  match KvNum::decode_bytes(*b"NM?", &7_u64.to_le_bytes()) {
+    Ok(Either::Right(Num(7))) => {}
+    x => panic!("unexpected decode: {:?}", x),
+  }
+  assert!(KvNum::decode_bytes(*b"ZZ?", b"").is_err());
+}
+
+#[test]
+fn test_either_decode_dispatch_and_fallback() {
+  // A declared tag goes to its side, even if the other side would take it.
+  match Either::<Raw, Num>::decode_bytes(*b"NM?", &1_u64.to_le_bytes()) {
+    Ok(Either::Right(Num(1))) => {}
+    x => panic!("unexpected decode: {:?}", x),
+  }
+  // An undeclared tag is offered to `A`, then to `B`.
+  match Either::<Raw, Num>::decode_bytes(*b"XY?", b"x") {
+    Ok(Either::Left(Raw(tag, ref buf))) if &tag == b"XY?" && buf == b"x" => {}
+    x => panic!("unexpected decode: {:?}", x),
+  }
+  match Either::<Kv, Raw>::decode_bytes(*b"XY?", b"x") {
+    Ok(Either::Right(Raw(tag, _))) if &tag == b"XY?" => {}
+    x => panic!("unexpected decode: {:?}", x),
+  }
+  // A declared tag that fails to decode does not fall back.
+  match Either::<Kv, Raw>::decode_bytes(*b"KV?", b"\xff") {
+    Err(CodecErr::Utf8) => {}
+    x => panic!("unexpected decode: {:?}", x),
+  }
+  // Nested, the inner `Either` declares the tags of both its sides.
+  type Nested = Either<Raw, Either<Kv, Num>>;
+  match Nested::decode_bytes(*b"NM?", &2_u64.to_le_bytes()) {
+    Ok(Either::Right(Either::Right(Num(2)))) => {}
+    x => panic!("unexpected decode: {:?}", x),
+  }
+  match Nested::decode_bytes(*b"KV?", b"a") {
+    Ok(Either::Right(Either::Left(Kv(_)))) => {}
+    x => panic!("unexpected decode: {:?}", x),
+  }
+}
+
+#[test]
+fn test_either_overlapping_tags_are_rejected() {
+  assert!(register_tags::<Either<Kv, Num>>().is_ok());
+  match register_tags::<Either<Kv, Kv2>>() {
+    Err(TagErr::Duplicate(tag, _)) if &tag == b"KV?" => {}
+    x => panic!("unexpected registration: {:?}", x),
+  }
+  match register_tags::<Either<Num, Either<Kv2, Kv>>>() {
+    Err(TagErr::Duplicate(tag, _)) if &tag == b"KV?" => {}
+    x => panic!("unexpected registration: {:?}", x),
+  }
+}
+
+#[test]
+fn test_either_over_chan() {
+  let (mut client, mut server) = chan_pair::<Either<Kv, Num>>();
+  let h = spawn(move || {
+    for _ in 0 .. 2 {
+      server.reply(|query: &Msg<Either<Kv, Num>>| {
+        match query {
+          &Msg::Ext(Either::Left(Kv(ref s))) => Msg::Ext(Either::Right(Num(s.len() as u64))),
+          &Msg::Ext(Either::Right(Num(x))) => Msg::Ext(Either::Left(Kv(x.to_string()))),
+          _ => Msg::Bot
+        }
+      }).unwrap();
+    }
+  });
+  match client.query(&Msg::Ext(Either::Left(Kv("abc".to_string())))) {
+    Ok(Msg::Ext(Either::Right(Num(3)))) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  match client.query(&Msg::Ext(Either::Right(Num(42)))) {
+    Ok(Msg::Ext(Either::Left(Kv(ref s)))) if s == "42" => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  h.join().unwrap();
+}
@@ -0,0 +1,120 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::chan::{Chan, QueryErr};
+use service_base::msg::{Msg};
+use service_base::ratelimit::*;
+use service_base::testkit::{chan_pair};
+
+use rustc_serialize::json::{Json};
+
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration as StdDuration, Instant};
+
+fn limiter(action: ThrottleAction, refill_per_sec: f64) -> Arc<RateLimiter> {
+  Arc::new(RateLimiter::new(RateLimitConfig{
+    per_conn: Some(RateLimit{burst: 1, refill_per_sec}),
+    per_peer: None,
+    action,
+  }))
+}
+
+// Replies `OKR` to `n` queries, returning whether each closed the
+// connection.
+fn serve(limiter: &Arc<RateLimiter>, n: usize) -> (Chan, JoinHandle<Vec<bool>>) {
+  let (client, mut server) = chan_pair::<()>();
+  server.set_rate_limit(Some(ConnLimiter::new(limiter.clone(), None)));
+  let h = spawn(move || {
+    let mut halts = Vec::new();
+    for _ in 0 .. n {
+      let halt = server.reply(|_: &Msg| Msg::OKR).unwrap();
+      halts.push(halt);
+      if halt {
+        break;
+      }
+    }
+    halts
+  });
+  (client, h)
+}
+
+#[test]
+fn test_delay() {
+  let limit = limiter(ThrottleAction::Delay, 20.0);
+  let (mut client, h) = serve(&limit, 2);
+  let t0 = Instant::now();
+  for _ in 0 .. 2 {
+    match client.query(&Msg::OKQ) {
+      Ok(Msg::OKR) => {}
+      x => panic!("unexpected reply: {:?}", x),
+    }
+  }
+  assert!(t0.elapsed() >= StdDuration::from_millis(40));
+  assert_eq!(h.join().unwrap(), vec![false, false]);
+  assert_eq!(limit.stats().delayed, 1);
+}
+
+#[test]
+fn test_delay_is_capped_at_deadline() {
+  let limit = limiter(ThrottleAction::Delay, 0.1);
+  let mut conn = ConnLimiter::new(limit.clone(), None);
+  assert!(conn.admit(None).is_none());
+  let t0 = Instant::now();
+  assert_eq!(conn.admit(Some(t0 + StdDuration::from_millis(50))), Some(ThrottleAction::Delay));
+  let dt = t0.elapsed();
+  assert!(dt >= StdDuration::from_millis(50) && dt < StdDuration::from_secs(5));
+}
+
+#[test]
+fn test_reject() {
+  let limit = limiter(ThrottleAction::Reject, 0.1);
+  let (mut client, h) = serve(&limit, 3);
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::OKR) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  for _ in 0 .. 2 {
+    match client.query(&Msg::OKQ) {
+      Err(QueryErr::Bot(details)) => {
+        let details = Json::from_str(&details).unwrap();
+        assert_eq!(details.find("error").and_then(|e| e.as_string()), Some("rate_limited"));
+        assert_eq!(details.find("tag").and_then(|e| e.as_string()), Some("OK?"));
+      }
+      x => panic!("unexpected reply: {:?}", x),
+    }
+  }
+  assert_eq!(h.join().unwrap(), vec![false, false, false]);
+  assert_eq!(limit.stats().rejected, 2);
+}
+
+#[test]
+fn test_disconnect() {
+  let limit = limiter(ThrottleAction::Disconnect, 0.1);
+  let (mut client, h) = serve(&limit, 2);
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::OKR) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  // The server end is gone without a reply.
+  assert!(client.query(&Msg::OKQ).is_err());
+  assert_eq!(h.join().unwrap(), vec![false, true]);
+  assert_eq!(limit.stats().disconnected, 1);
+}
+
+#[test]
+fn test_peer_buckets_are_capped() {
+  let limit = Arc::new(RateLimiter::new(RateLimitConfig{
+    per_conn: None,
+    per_peer: Some(RateLimit{burst: 2, refill_per_sec: 0.001}),
+    action: ThrottleAction::Reject,
+  }));
+  // Each peer takes a token, so that no bucket is full.
+  for i in 0 .. 2 * MAX_PEERS as u16 {
+    let peer = PeerKey::Ip(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, i)));
+    assert!(ConnLimiter::new(limit.clone(), Some(peer)).admit(None).is_none());
+    assert!(limit.peer_count() <= MAX_PEERS);
+  }
+  assert_eq!(limit.peer_count(), MAX_PEERS);
+}
@@ -0,0 +1,161 @@
+extern crate service_base;
+
+use service_base::chan::{QueryErr, SpawnPool};
+use service_base::msg::{Msg};
+use service_base::retry::*;
+
+use std::net::{TcpListener};
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::thread::{sleep, spawn};
+use std::time::{Duration as StdDuration, Instant};
+
+fn breaker(probe_timeout_ms: u64) -> CircuitBreaker {
+  CircuitBreaker::new(BreakerConfig{
+    failure_threshold: 3,
+    cooldown: StdDuration::from_millis(50),
+    probe_timeout: StdDuration::from_millis(probe_timeout_ms),
+  })
+}
+
+fn fail(b: &CircuitBreaker, n: u32) {
+  for _ in 0 .. n {
+    assert!(b.acquire());
+    b.on_failure();
+  }
+}
+
+#[test]
+fn test_breaker_opens_at_threshold() {
+  let b = breaker(1000);
+  fail(&b, 2);
+  b.on_success();
+  // Only consecutive failures count.
+  fail(&b, 2);
+  assert_eq!(b.state(), BreakerState::Closed);
+  fail(&b, 1);
+  assert_eq!(b.state(), BreakerState::Open);
+  assert!(!b.acquire());
+  let stats = b.stats();
+  assert_eq!(stats.failures, 5);
+  assert_eq!(stats.successes, 1);
+  assert_eq!(stats.rejected, 1);
+  assert_eq!(stats.trips, 1);
+}
+
+#[test]
+fn test_breaker_half_open_single_probe_closes() {
+  let b = breaker(1000);
+  fail(&b, 3);
+  assert_eq!(b.state(), BreakerState::Open);
+  sleep(StdDuration::from_millis(60));
+  assert_eq!(b.state(), BreakerState::HalfOpen);
+  assert!(b.acquire());
+  // The probe is outstanding, so everyone else is rejected.
+  assert!(!b.acquire());
+  assert!(!b.acquire());
+  b.on_success();
+  assert_eq!(b.state(), BreakerState::Closed);
+  assert!(b.acquire());
+  assert!(b.acquire());
+  assert_eq!(b.stats().consecutive_failures, 0);
+}
+
+#[test]
+fn test_breaker_failed_probe_reopens() {
+  let b = breaker(1000);
+  fail(&b, 3);
+  sleep(StdDuration::from_millis(60));
+  assert!(b.acquire());
+  b.on_failure();
+  assert_eq!(b.state(), BreakerState::Open);
+  assert!(!b.acquire());
+  assert_eq!(b.stats().trips, 2);
+  sleep(StdDuration::from_millis(60));
+  assert_eq!(b.state(), BreakerState::HalfOpen);
+}
+
+#[test]
+fn test_breaker_probe_times_out() {
+  let b = breaker(30);
+  fail(&b, 3);
+  sleep(StdDuration::from_millis(60));
+  assert!(b.acquire());
+  // The probe never reports back.
+  sleep(StdDuration::from_millis(40));
+  assert_eq!(b.state(), BreakerState::Open);
+  assert_eq!(b.stats().trips, 2);
+  sleep(StdDuration::from_millis(60));
+  assert_eq!(b.state(), BreakerState::HalfOpen);
+  assert!(b.acquire());
+}
+
+#[test]
+fn test_retry_chan_probe_has_deadline() {
+  // Accepted by the kernel, but never answered.
+  let bind = TcpListener::bind("127.0.0.1:0").unwrap();
+  let b = Arc::new(CircuitBreaker::new(BreakerConfig{
+    failure_threshold: 1,
+    cooldown: StdDuration::from_millis(200),
+    probe_timeout: StdDuration::from_millis(100),
+  }));
+  b.on_failure();
+  sleep(StdDuration::from_millis(250));
+  let mut chan = RetryChan::<()>::new(bind.local_addr().unwrap(), RetryPolicy::no_retry(), b.clone()).unwrap();
+  let t0 = Instant::now();
+  match chan.query(&Msg::OKQ) {
+    Err(QueryErr::Deadline) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  assert!(t0.elapsed() < StdDuration::from_secs(5));
+  assert_eq!(b.state(), BreakerState::Open);
+}
+
+#[test]
+fn test_retry_chan_probe_connect_has_deadline() {
+  // Not routable, so a connect either fails outright or hangs.
+  let b = Arc::new(CircuitBreaker::new(BreakerConfig{
+    failure_threshold: 1,
+    cooldown: StdDuration::from_millis(10),
+    probe_timeout: StdDuration::from_millis(200),
+  }));
+  b.on_failure();
+  sleep(StdDuration::from_millis(20));
+  let mut chan = RetryChan::<()>::new("10.255.255.1:9", RetryPolicy::no_retry(), b.clone()).unwrap();
+  let t0 = Instant::now();
+  assert!(chan.query(&Msg::OKQ).is_err());
+  assert!(t0.elapsed() < StdDuration::from_secs(2));
+  assert_eq!(b.stats().trips, 2);
+}
+
+#[test]
+fn test_bot_is_not_a_failure() {
+  let bind = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = bind.local_addr().unwrap();
+  let calls = Arc::new(AtomicUsize::new(0));
+  let calls2 = calls.clone();
+  spawn(move || {
+    SpawnPool::<()>::new(bind).replying(Arc::new(move |_: &Msg| -> Msg {
+      calls2.fetch_add(1, AtomicOrdering::SeqCst);
+      panic!("no OKs today");
+    }));
+  });
+  let b = Arc::new(breaker(1000));
+  let policy = RetryPolicy{
+    max_attempts: 3,
+    backoff_init: StdDuration::from_millis(10),
+    .. RetryPolicy::default()
+  };
+  let mut chan = RetryChan::<()>::new(addr, policy, b.clone()).unwrap();
+  for _ in 0 .. 4 {
+    match chan.query_idempotent(&Msg::OKQ) {
+      Err(QueryErr::Bot(_)) => {}
+      x => panic!("unexpected reply: {:?}", x),
+    }
+  }
+  // Each query ran once, and the breaker stayed closed.
+  assert_eq!(calls.load(AtomicOrdering::SeqCst), 4);
+  assert_eq!(b.state(), BreakerState::Closed);
+  assert_eq!(b.stats().failures, 0);
+  assert_eq!(b.stats().successes, 4);
+}
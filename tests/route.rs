@@ -0,0 +1,48 @@
+extern crate service_base;
+
+use service_base::http::{HttpRequest};
+use service_base::route::*;
+
+fn never() -> RouteFire {
+  Box::new(|_: &[Pat], _: &RouteArgs, _: &HttpRequest| None)
+}
+
+fn router() -> Router {
+  let mut router = Router::new();
+  router.insert_get(("user", "{id:u64}"), never());
+  router.insert_post(("user", "{id:u64}", "name"), never());
+  router.insert_get(("key", vec![1_u8, 2, 3]), never());
+  router.insert_get(("static", "index"), never());
+  router
+}
+
+#[test]
+fn test_elide_declared_params() {
+  let router = router();
+  // A miss on the type of the parameter, or on the method, still has
+  // the parameter elided.
+  assert_eq!(router.elide_path(&["user", "c2VjcmV0LXRva2VuLXZhbHVl"]), "/user/*");
+  assert_eq!(router.elide_path(&["user", "42", "name"]), "/user/*/name");
+  assert_eq!(router.elide_path(&["user", "42", "email"]), "/user/42/email");
+  assert_eq!(router.elide_path(&["user", "42", ""]), "/user/*/");
+  assert_eq!(router.elide_path(&["key", "AQIDBA"]), "/key/*");
+}
+
+#[test]
+fn test_keep_undeclared_segments() {
+  let router = router();
+  // Long segments are kept, even if they would decode as base64.
+  assert_eq!(router.elide_path(&["static", "GettingStartedWithServiceBase"]), "/static/GettingStartedWithServiceBase");
+  // Only rules whose literal parts match count.
+  assert_eq!(router.elide_path(&["account", "c2VjcmV0LXRva2VuLXZhbHVl"]), "/account/c2VjcmV0LXRva2VuLXZhbHVl");
+  assert_eq!(router.elide_path(&["a", "b", "c", "d"]), "/a/b/c/d");
+  assert_eq!(router.elide_path::<&str>(&[]), "/");
+  assert_eq!(Router::new().elide_path(&["user", "42"]), "/user/42");
+}
+
+#[test]
+fn test_pattern() {
+  assert_eq!(RoutePath::from(("user", "{id:u64}", "name")).pattern(), "/user/{id}/name");
+  assert_eq!(RoutePath::from(("key", vec![1_u8, 2, 3])).pattern(), "/key/*");
+  assert_eq!(RoutePath::from(()).pattern(), "/");
+}
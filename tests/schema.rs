@@ -0,0 +1,94 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::schema::*;
+
+use rustc_serialize::json::{Json};
+
+fn json(s: &str) -> Json {
+  Json::from_str(s).unwrap()
+}
+
+fn violations(schema: &Schema, j: &str) -> Vec<(String, String)> {
+  match schema.validate(&json(j)) {
+    Ok(()) => Vec::new(),
+    Err(e) => e.violations.into_iter().map(|v| (v.path, v.message)).collect()
+  }
+}
+
+fn v(path: &str, message: &str) -> (String, String) {
+  (path.to_string(), message.to_string())
+}
+
+#[test]
+fn test_length_and_items_are_separate() {
+  // Both orders, since the keywords used to share a field.
+  for src in [
+    r#"{"type": ["string", "array"], "minLength": 3, "maxLength": 5, "minItems": 2, "maxItems": 3}"#,
+    r#"{"type": ["string", "array"], "minItems": 2, "maxItems": 3, "minLength": 3, "maxLength": 5}"#,
+  ].iter() {
+    let schema = Schema::from_json(&json(src)).unwrap();
+    assert_eq!(schema.min_length, Some(3));
+    assert_eq!(schema.max_length, Some(5));
+    assert_eq!(schema.min_items, Some(2));
+    assert_eq!(schema.max_items, Some(3));
+    assert!(violations(&schema, r#""abcd""#).is_empty());
+    assert!(violations(&schema, "[1, 2]").is_empty());
+    assert_eq!(violations(&schema, r#""ab""#), vec![v("$", "expected a length of at least 3")]);
+    assert_eq!(violations(&schema, "[1, 2, 3, 4]"), vec![v("$", "expected at most 3 items")]);
+    assert_eq!(violations(&schema, "[]"), vec![v("$", "expected at least 2 items")]);
+  }
+}
+
+#[test]
+fn test_nested_violation_paths() {
+  let schema = Schema::from_json(&json(r#"{
+    "type": "object",
+    "required": ["name", "tags"],
+    "additionalProperties": false,
+    "properties": {
+      "name": {"type": "string", "minLength": 1},
+      "tags": {
+        "type": "array",
+        "maxItems": 2,
+        "items": {
+          "type": "object",
+          "required": ["key"],
+          "properties": {
+            "key": {"type": "string"},
+            "weight": {"type": "number", "minimum": 0}
+          }
+        }
+      },
+      "odd key": {"type": "array", "items": {"type": "integer"}}
+    }
+  }"#)).unwrap();
+  assert!(violations(&schema, r#"{"name": "a", "tags": [{"key": "k"}]}"#).is_empty());
+  assert_eq!(violations(&schema, r#"{
+    "name": "",
+    "tags": [{"key": "k"}, {"weight": -1}, {"key": 7}],
+    "odd key": [1, "two"],
+    "extra": null
+  }"#), vec![
+    v("$.extra", "unexpected key"),
+    v("$.name", "expected a length of at least 1"),
+    v("$[\"odd key\"][1]", "expected integer"),
+    v("$.tags", "expected at most 2 items"),
+    v("$.tags[1]", "missing required key \"key\""),
+    v("$.tags[1].weight", "expected at least 0"),
+    v("$.tags[2].key", "expected string"),
+  ]);
+  assert_eq!(violations(&schema, r#"{"tags": "none"}"#), vec![
+    v("$", "missing required key \"name\""),
+    v("$.tags", "expected array"),
+  ]);
+}
+
+#[test]
+fn test_builder_matches_keywords() {
+  let schema = Schema::new(SchemaType::Array)
+    .item_count(Some(2), None)
+    .items(Schema::new(SchemaType::String).length(None, Some(2)));
+  assert_eq!(violations(&schema, r#"["ab", "abc"]"#), vec![v("$[1]", "expected a length of at most 2")]);
+  assert_eq!(violations(&schema, "[]"), vec![v("$", "expected at least 2 items")]);
+}
@@ -0,0 +1,283 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::chan::{Chan, ReplyErr, RecvErr, SpawnPool};
+use service_base::ctx::{ConnCtx, PeerAddr};
+use service_base::msg::*;
+use service_base::service::*;
+use service_base::testkit::{chan_pair, mem_stream_pair};
+
+use rustc_serialize::json::{Json};
+
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{spawn, JoinHandle};
+
+// Records its hooks, and counts queries in per-connection state.
+#[derive(Default)]
+struct Recorder {
+  events: Vec<String>,
+  queries: u64,
+  accept: bool,
+}
+
+impl Service for Recorder {
+  fn on_connect(&mut self, ctx: &mut ConnCtx) -> bool {
+    self.events.push(format!("connect {}", ctx.seq()));
+    self.accept
+  }
+
+  fn on_query(&mut self, ctx: &mut ConnCtx, _query: &Msg) -> Outcome {
+    self.queries += 1;
+    self.events.push(format!("query {}", ctx.seq()));
+    Msg::JSO(Json::U64(self.queries)).into()
+  }
+
+  fn on_error(&mut self, _ctx: &mut ConnCtx, err: &ReplyErr) {
+    self.events.push(format!("error {:?}", err));
+  }
+
+  fn on_disconnect(&mut self, _ctx: &mut ConnCtx) {
+    self.events.push("disconnect".to_string());
+  }
+}
+
+fn recorder(accept: bool) -> Recorder {
+  Recorder{accept, ..Recorder::default()}
+}
+
+fn serve(mut server: Chan, mut svc: Recorder) -> JoinHandle<Recorder> {
+  spawn(move || {
+    server.serve(&mut svc);
+    svc
+  })
+}
+
+fn count(reply: Msg) -> u64 {
+  match reply {
+    Msg::JSO(ref j) if j.as_u64().is_some() => j.as_u64().unwrap(),
+    x => panic!("unexpected reply: {:?}", x),
+  }
+}
+
+#[test]
+fn test_lifecycle_hooks() {
+  let (mut client, server) = chan_pair::<()>();
+  let h = serve(server, recorder(true));
+  for i in 1 ..= 3 {
+    assert_eq!(count(client.query(&Msg::OKQ).unwrap()), i);
+  }
+  // The peer going away is not an error.
+  drop(client);
+  let svc = h.join().unwrap();
+  assert_eq!(svc.queries, 3);
+  assert_eq!(svc.events, vec!["connect 0", "query 1", "query 2", "query 3", "disconnect"]);
+}
+
+#[test]
+fn test_on_connect_can_refuse() {
+  let (mut client, server) = chan_pair::<()>();
+  let h = serve(server, recorder(false));
+  let svc = h.join().unwrap();
+  assert_eq!(svc.events, vec!["connect 0", "disconnect"]);
+  assert!(client.query(&Msg::OKQ).is_err());
+}
+
+// An extension message that the server, speaking `Msg<()>`, cannot
+// decode.
+struct Unknown;
+
+impl WireCodex for Unknown {
+  fn encode_bytes(&self, _buf: &mut Vec<u8>) -> Result<[u8; 3], CodecErr> {
+    Ok(*b"UNK")
+  }
+
+  fn decode_bytes(tag: [u8; 3], _buf: &[u8]) -> Result<Unknown, CodecErr> {
+    Err(CodecErr::Tag(tag))
+  }
+}
+
+#[test]
+fn test_on_error() {
+  let (a, b) = mem_stream_pair();
+  let mut client = Chan::<Unknown>::from_stream(Box::new(a));
+  let h = serve(Chan::from_stream(Box::new(b)), recorder(true));
+  client.send(&Msg::OKQ).unwrap();
+  client.send(&Msg::Ext(Unknown)).unwrap();
+  let svc = h.join().unwrap();
+  assert_eq!(svc.queries, 1);
+  assert_eq!(svc.events.len(), 4);
+  assert_eq!(&svc.events[.. 2], &["connect 0", "query 1"]);
+  assert!(svc.events[2].starts_with("error "), "{}", svc.events[2]);
+  assert_eq!(svc.events[3], "disconnect");
+  assert!(matches!(client.recv(), Ok((Msg::JSO(_), 1))));
+  assert!(matches!(client.recv(), Err(RecvErr::IO)));
+}
+
+#[test]
+fn test_fn_service_and_factory() {
+  // Handlers may keep state without sharing it.
+  let mut n = 0_u64;
+  let mut svc = FnService(|_: &mut ConnCtx, _: &Msg| {
+    n += 1;
+    Msg::JSO(Json::U64(n))
+  });
+  let mut ctx = ConnCtx::new(PeerAddr::Unknown);
+  for i in 1 ..= 2 {
+    match svc.on_query(&mut ctx, &Msg::OKQ) {
+      Outcome::Reply(reply) => assert_eq!(count(reply), i),
+      _ => panic!("unexpected outcome"),
+    }
+  }
+  let factory = |ctx: &ConnCtx| Recorder{queries: ctx.id(), ..recorder(true)};
+  assert_eq!(ServiceFactory::<()>::new_service(&factory, &ctx).queries, ctx.id());
+}
+
+// Forwards the events of a `Recorder` as they happen.
+struct Forward(Recorder, Sender<String>);
+
+impl Service for Forward {
+  fn on_connect(&mut self, ctx: &mut ConnCtx) -> bool {
+    self.0.on_connect(ctx)
+  }
+
+  fn on_query(&mut self, ctx: &mut ConnCtx, query: &Msg) -> Outcome {
+    self.0.on_query(ctx, query)
+  }
+
+  fn on_disconnect(&mut self, ctx: &mut ConnCtx) {
+    self.0.on_disconnect(ctx);
+    for e in self.0.events.drain(..) {
+      self.1.send(e).unwrap();
+    }
+  }
+}
+
+#[test]
+fn test_spawn_pool_drives_hooks() {
+  let bind = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = bind.local_addr().unwrap();
+  let (tx, rx) = channel();
+  let tx = Arc::new(Mutex::new(tx));
+  // Never returns; the thread goes away with the test process.
+  spawn(move || {
+    SpawnPool::<()>::new(bind).serving(Arc::new(move |_: &ConnCtx| {
+      Forward(recorder(true), tx.lock().unwrap().clone())
+    }));
+  });
+  // Each connection gets its own state.
+  for _ in 0 .. 2 {
+    let mut client = Chan::<()>::new(TcpStream::connect(addr).unwrap());
+    assert_eq!(count(client.query(&Msg::OKQ).unwrap()), 1);
+    assert_eq!(count(client.query(&Msg::OKQ).unwrap()), 2);
+    drop(client);
+    let events: Vec<String> = rx.iter().take(4).collect();
+    assert_eq!(events, vec!["connect 0", "query 1", "query 2", "disconnect"]);
+  }
+}
+
+// Replies to queries until `reply` ends the loop, returning how many
+// were handled.
+fn serve_outcomes<P: 'static + Send + Fn(&Msg) -> Outcome>(server: Chan, proc_: P) -> JoinHandle<u64> {
+  let mut server = server;
+  spawn(move || {
+    let mut n = 0;
+    loop {
+      n += 1;
+      match server.reply(&proc_) {
+        Ok(false) => {}
+        Ok(true) => return n,
+        Err(e) => panic!("unexpected error: {:?}", e),
+      }
+    }
+  })
+}
+
+fn echo_or(query: &Msg, outcome: Outcome) -> Outcome {
+  match query {
+    &Msg::OKQ => Msg::OKR.into(),
+    _ => outcome
+  }
+}
+
+#[test]
+fn test_reply_close() {
+  let (mut client, server) = chan_pair::<()>();
+  let h = serve_outcomes(server, |q: &Msg| echo_or(q, Outcome::ReplyClose(Msg::JSO(Json::Null))));
+  assert!(matches!(client.query(&Msg::OKQ), Ok(Msg::OKR)));
+  assert!(matches!(client.query(&Msg::JSO(Json::Null)), Ok(Msg::JSO(Json::Null))));
+  assert_eq!(h.join().unwrap(), 2);
+  assert!(client.query(&Msg::OKQ).is_err());
+}
+
+#[test]
+fn test_close_without_reply() {
+  let (mut client, server) = chan_pair::<()>();
+  let h = serve_outcomes(server, |q: &Msg| echo_or(q, Outcome::Close));
+  assert!(matches!(client.query(&Msg::OKQ), Ok(Msg::OKR)));
+  client.send(&Msg::JSO(Json::Null)).unwrap();
+  assert_eq!(h.join().unwrap(), 2);
+  assert!(matches!(client.recv(), Err(RecvErr::IO)));
+}
+
+#[test]
+fn test_hup_ends_the_loop() {
+  let (mut client, server) = chan_pair::<()>();
+  let h = serve_outcomes(server, |q: &Msg| echo_or(q, Msg::Bot.into()));
+  assert!(matches!(client.query(&Msg::OKQ), Ok(Msg::OKR)));
+  client.send(&Msg::HUP).unwrap();
+  assert_eq!(h.join().unwrap(), 2);
+
+  // `replying` returns on `HUP`, too.
+  let (mut client, mut server) = chan_pair::<()>();
+  let h = spawn(move || server.replying(|_: &Msg| Msg::OKR));
+  assert!(matches!(client.query(&Msg::OKQ), Ok(Msg::OKR)));
+  client.send(&Msg::HUP).unwrap();
+  h.join().unwrap();
+}
+
+#[test]
+fn test_defer_from_another_thread() {
+  let (mut client, mut server) = chan_pair::<()>();
+  let h = spawn(move || {
+    let mut workers = Vec::new();
+    let mut halts = Vec::new();
+    for _ in 0 .. 3 {
+      halts.push(server.reply_ctx(|_: &mut ConnCtx, query: &Msg| {
+        let (outcome, replier) = Outcome::defer();
+        let drop_it = matches!(query, &Msg::OKR);
+        workers.push(spawn(move || {
+          if drop_it {
+            // Dropped without completing: closes the connection.
+            drop(replier);
+          } else {
+            replier.reply(Msg::OKR);
+          }
+        }));
+        outcome
+      }).unwrap());
+      if *halts.last().unwrap() {
+        break;
+      }
+    }
+    for w in workers.into_iter() {
+      w.join().unwrap();
+    }
+    halts
+  });
+  assert!(matches!(client.query(&Msg::OKQ), Ok(Msg::OKR)));
+  assert!(matches!(client.query(&Msg::OKQ), Ok(Msg::OKR)));
+  client.send(&Msg::OKR).unwrap();
+  assert_eq!(h.join().unwrap(), vec![false, false, true]);
+  assert!(matches!(client.recv(), Err(RecvErr::IO)));
+
+  let (mut client, server) = chan_pair::<()>();
+  let h = serve_outcomes(server, |_: &Msg| {
+    let (outcome, replier) = Outcome::defer();
+    spawn(move || replier.reply_close(Msg::JSO(Json::Boolean(true))));
+    outcome
+  });
+  assert!(matches!(client.query(&Msg::OKQ), Ok(Msg::JSO(Json::Boolean(true)))));
+  assert_eq!(h.join().unwrap(), 1);
+}
@@ -0,0 +1,165 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::chan::{QueryErr, SpawnPool};
+use service_base::msg::{Msg};
+use service_base::retry::{RetryPolicy};
+use service_base::session::*;
+
+use rustc_serialize::json::{Json};
+
+use std::io::{copy};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::mpsc::{channel};
+use std::thread::{sleep, spawn};
+use std::time::{Duration as StdDuration};
+
+// Forwards connections to `dst`, keeping both sockets of each so that
+// the test can cut a connection.
+fn spawn_proxy(dst: SocketAddr) -> (SocketAddr, Arc<Mutex<Vec<(TcpStream, TcpStream)>>>) {
+  let bind = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = bind.local_addr().unwrap();
+  let conns = Arc::new(Mutex::new(Vec::new()));
+  let conns2 = conns.clone();
+  spawn(move || {
+    for src in bind.incoming() {
+      let src = match src {
+        Err(_) => break,
+        Ok(s) => s
+      };
+      let dst = TcpStream::connect(dst).unwrap();
+      conns2.lock().unwrap().push((src.try_clone().unwrap(), dst.try_clone().unwrap()));
+      let (mut src_r, mut dst_w) = (src.try_clone().unwrap(), dst.try_clone().unwrap());
+      spawn(move || {
+        let _ = copy(&mut src_r, &mut dst_w);
+        let _ = dst_w.shutdown(Shutdown::Both);
+      });
+      let (mut dst_r, mut src_w) = (dst, src);
+      spawn(move || {
+        let _ = copy(&mut dst_r, &mut src_w);
+        let _ = src_w.shutdown(Shutdown::Both);
+      });
+    }
+  });
+  (addr, conns)
+}
+
+#[test]
+fn test_dropped_connection_runs_handler_once() {
+  let bind = TcpListener::bind("127.0.0.1:0").unwrap();
+  let server_addr = bind.local_addr().unwrap();
+  let calls = Arc::new(AtomicUsize::new(0));
+  let (started_tx, started_rx) = channel();
+  let started_tx = Mutex::new(started_tx);
+  let mut pool = SpawnPool::<()>::new(bind);
+  pool.set_sessions(Some(Arc::new(SessionStore::new(SessionConfig::default()))));
+  let calls2 = calls.clone();
+  spawn(move || {
+    pool.replying(Arc::new(move |_: &Msg| {
+      calls2.fetch_add(1, AtomicOrdering::SeqCst);
+      let _ = started_tx.lock().unwrap().send(());
+      // Still running when the client reconnects and resends.
+      sleep(StdDuration::from_millis(300));
+      Msg::OKR
+    }));
+  });
+  let (proxy_addr, conns) = spawn_proxy(server_addr);
+  let cutter = spawn(move || {
+    started_rx.recv().unwrap();
+    let conns = conns.lock().unwrap();
+    let &(ref src, ref dst) = &conns[0];
+    let _ = src.shutdown(Shutdown::Both);
+    let _ = dst.shutdown(Shutdown::Both);
+  });
+  let policy = RetryPolicy{
+    max_attempts: 5,
+    backoff_init: StdDuration::from_millis(10),
+    .. RetryPolicy::default()
+  };
+  let mut client = SessionChan::<()>::new(proxy_addr, policy).unwrap();
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::OKR) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  cutter.join().unwrap();
+  assert_eq!(calls.load(AtomicOrdering::SeqCst), 1);
+  // The session carries on with the next query.
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::OKR) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  assert_eq!(calls.load(AtomicOrdering::SeqCst), 2);
+}
+
+// Serves with sessions; the handler gets the 1-based count of its calls.
+fn spawn_server<F>(handler: F) -> SocketAddr
+where F: 'static + Send + Sync + Fn(usize) -> Msg {
+  let bind = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = bind.local_addr().unwrap();
+  let calls = AtomicUsize::new(0);
+  let mut pool = SpawnPool::<()>::new(bind);
+  pool.set_sessions(Some(Arc::new(SessionStore::new(SessionConfig::default()))));
+  spawn(move || {
+    pool.replying(Arc::new(move |_: &Msg| {
+      (handler)(calls.fetch_add(1, AtomicOrdering::SeqCst) + 1)
+    }));
+  });
+  addr
+}
+
+#[test]
+fn test_bot_is_not_retried_and_next_query_runs() {
+  let addr = spawn_server(|n| {
+    if n == 1 {
+      panic!("first call fails");
+    }
+    Msg::JSO(Json::U64(n as u64))
+  });
+  let policy = RetryPolicy{
+    max_attempts: 3,
+    backoff_init: StdDuration::from_millis(10),
+    .. RetryPolicy::default()
+  };
+  let mut client = SessionChan::<()>::new(addr, policy).unwrap();
+  match client.query(&Msg::OKQ) {
+    Err(SessionErr::Query(QueryErr::Bot(_))) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::JSO(Json::U64(2))) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+}
+
+#[test]
+fn test_failed_query_does_not_shadow_next_query() {
+  let (started_tx, started_rx) = channel();
+  let started_tx = Mutex::new(started_tx);
+  let server_addr = spawn_server(move |n| {
+    let _ = started_tx.lock().unwrap().send(());
+    if n == 1 {
+      sleep(StdDuration::from_millis(300));
+    }
+    Msg::JSO(Json::U64(n as u64))
+  });
+  let (proxy_addr, conns) = spawn_proxy(server_addr);
+  let cutter = spawn(move || {
+    started_rx.recv().unwrap();
+    let conns = conns.lock().unwrap();
+    let &(ref src, ref dst) = &conns[0];
+    let _ = src.shutdown(Shutdown::Both);
+    let _ = dst.shutdown(Shutdown::Both);
+  });
+  let mut client = SessionChan::<()>::new(proxy_addr, RetryPolicy::no_retry()).unwrap();
+  // Out of attempts, with the query started on the server.
+  assert!(client.query(&Msg::OKQ).is_err());
+  cutter.join().unwrap();
+  // The next query gets a fresh seq, instead of the cached reply of the
+  // failed one.
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::JSO(Json::U64(2))) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+}
@@ -0,0 +1,18 @@
+extern crate service_base;
+
+use service_base::msg::{BUILTIN_TAGS};
+use service_base::tag::{FUTURE_TAGS, is_reserved_tag};
+
+// The list shared with the derive crate, which `include!`s the same file.
+mod shared {
+  include!("../src/reserved_tags.rs");
+}
+
+#[test]
+fn test_reserved_tags_are_shared() {
+  assert_eq!(BUILTIN_TAGS, shared::RESERVED_BUILTIN_TAGS);
+  assert_eq!(FUTURE_TAGS, shared::RESERVED_FUTURE_TAGS);
+  for &(tag, name) in shared::RESERVED_BUILTIN_TAGS.iter().chain(shared::RESERVED_FUTURE_TAGS.iter()) {
+    assert!(is_reserved_tag(&tag), "{} is not reserved", name);
+  }
+}
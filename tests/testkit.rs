@@ -0,0 +1,103 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::msg::{Msg};
+use service_base::testkit::*;
+
+use rustc_serialize::json::{Json};
+
+use std::thread::{spawn};
+
+fn echo(query: &Msg) -> Msg {
+  match query {
+    &Msg::OKQ => Msg::OKR,
+    &Msg::JSO(ref j) => Msg::JSO(j.clone()),
+    _ => Msg::Bot
+  }
+}
+
+#[test]
+fn test_chan_pair_query_round_trip() {
+  let (mut client, mut server) = chan_pair::<()>();
+  let h = spawn(move || {
+    for _ in 0 .. 2 {
+      assert!(!server.reply(echo).unwrap());
+    }
+  });
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::OKR) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  let j = Json::from_str(r#"{"a": [1, "two", null]}"#).unwrap();
+  match client.query(&Msg::JSO(j.clone())) {
+    Ok(Msg::JSO(ref r)) if r == &j => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  assert_eq!(client.seqs(), (2, 2));
+  h.join().unwrap();
+}
+
+#[test]
+fn test_run_handler() {
+  let replies = run_handler(echo, &[Msg::OKQ, Msg::JSO(Json::Null), Msg::OKR]).unwrap();
+  assert_eq!(replies.len(), 3);
+  match (&replies[0], &replies[1], &replies[2]) {
+    (&Msg::OKR, &Msg::JSO(Json::Null), &Msg::Bot) => {}
+    x => panic!("unexpected replies: {:?}", x),
+  }
+}
+
+#[test]
+fn test_mock_service_expectations() {
+  let mock = MockService::<()>::new()
+    .expect(Msg::OKQ, Msg::OKR)
+    .expect_tag(*b"JSO", Msg::JSO(Json::Boolean(true)))
+    .expect_with("a null JSO", |q| matches!(q, &Msg::JSO(Json::Null)), |_| Msg::OKR);
+  let (mut client, handle) = mock.spawn();
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::OKR) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  match client.query(&Msg::JSO(Json::U64(7))) {
+    Ok(Msg::JSO(Json::Boolean(true))) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  match client.query(&Msg::JSO(Json::Null)) {
+    Ok(Msg::OKR) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  drop(client);
+  handle.finish();
+}
+
+#[test]
+#[should_panic(expected = "MockService: step 1: expected a query with tag \"JSO\"")]
+fn test_mock_service_mismatch() {
+  let mock = MockService::<()>::new()
+    .expect(Msg::OKQ, Msg::OKR)
+    .expect_tag(*b"JSO", Msg::OKR);
+  let (mut client, handle) = mock.spawn();
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::OKR) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  // The mock panics instead of replying, which drops its end.
+  assert!(client.query(&Msg::OKQ).is_err());
+  drop(client);
+  handle.finish();
+}
+
+#[test]
+#[should_panic(expected = "MockService: expected queries never received")]
+fn test_mock_service_missing_query() {
+  let mock = MockService::<()>::new()
+    .expect(Msg::OKQ, Msg::OKR)
+    .expect(Msg::OKQ, Msg::OKR);
+  let (mut client, handle) = mock.spawn();
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::OKR) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  drop(client);
+  handle.finish();
+}
@@ -0,0 +1,143 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::chan::{Chan};
+use service_base::ctx::{ConnCtx};
+use service_base::frame::{FrameMeta};
+use service_base::msg::{Msg};
+use service_base::testkit::{chan_pair};
+use service_base::trace::*;
+
+use rustc_serialize::json::{Json};
+
+use std::env::{temp_dir};
+use std::fs::{read_to_string, remove_file};
+use std::process::{id as process_id};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration as StdDuration, UNIX_EPOCH};
+
+// Replies with the trace context seen by the handler, or null.
+fn serve_current(n: usize) -> (Chan, JoinHandle<()>) {
+  let (client, mut server) = chan_pair::<()>();
+  let h = spawn(move || {
+    for _ in 0 .. n {
+      server.reply(|_: &Msg| {
+        Msg::JSO(match current() {
+          None => Json::Null,
+          Some(t) => Json::String(t.to_string())
+        })
+      }).unwrap();
+    }
+  });
+  (client, h)
+}
+
+fn seen(reply: Msg) -> Option<String> {
+  match reply {
+    Msg::JSO(Json::Null) => None,
+    Msg::JSO(Json::String(s)) => Some(s),
+    x => panic!("unexpected reply: {:?}", x),
+  }
+}
+
+#[test]
+fn test_trace_ctx_in_header() {
+  let (mut client, h) = serve_current(3);
+  let root = TraceCtx::root();
+  let mut meta = FrameMeta::default();
+  meta.trace = Some(root);
+  assert_eq!(seen(client.query_with(&Msg::OKQ, &meta).unwrap()), Some(root.to_string()));
+  // Outside of a handler, `with_trace` sets the context that queries
+  // send a child span of.
+  let child = seen(with_trace(Some(root), || client.query(&Msg::OKQ)).unwrap()).unwrap();
+  assert!(child.starts_with(&format!("trace_id={:032x} span_id=", root.trace_id)));
+  assert!(child.ends_with(&format!(" parent_span_id={:016x}", root.span_id)));
+  assert!(!child.contains(&format!("span_id={:016x} ", root.span_id)));
+  assert_eq!(seen(client.query(&Msg::OKQ).unwrap()), None);
+  h.join().unwrap();
+}
+
+#[test]
+fn test_child_ctx() {
+  let root = TraceCtx::root();
+  assert_ne!(root.trace_id, 0);
+  assert_ne!(root.span_id, 0);
+  assert_eq!(root.parent_span_id, None);
+  let child = root.child();
+  assert_eq!(child.trace_id, root.trace_id);
+  assert_eq!(child.parent_span_id, Some(root.span_id));
+  assert_ne!(child.span_id, root.span_id);
+  assert_ne!(TraceCtx::root().trace_id, root.trace_id);
+  assert!(current().is_none());
+  assert_eq!(with_root_trace(current).map(|t| t.parent_span_id), Some(None));
+  assert_eq!(with_trace(Some(child), || with_root_trace(current)), Some(child));
+}
+
+#[derive(Default)]
+struct Collect(Mutex<Vec<SpanRecord>>);
+
+impl SpanExporter for Collect {
+  fn export(&self, span: &SpanRecord) {
+    self.0.lock().unwrap().push(span.clone());
+  }
+}
+
+// The only test that sets the (global) span exporter.
+#[test]
+fn test_nested_query_exports_child_span() {
+  let spans = Arc::new(Collect::default());
+  set_span_exporter(Some(spans.clone()));
+  let (mut front, mut front_srv) = chan_pair::<()>();
+  let (mut back, h) = serve_current(1);
+  let front_h = spawn(move || {
+    front_srv.reply_ctx(|_: &mut ConnCtx, _: &Msg| back.query(&Msg::OKQ).unwrap()).unwrap();
+  });
+  let root = TraceCtx::root();
+  let mut meta = FrameMeta::default();
+  meta.trace = Some(root);
+  let back_seen = seen(front.query_with(&Msg::JSO(Json::Null), &meta).unwrap()).unwrap();
+  front_h.join().unwrap();
+  h.join().unwrap();
+  set_span_exporter(None);
+
+  // The back end's span finishes first, as a child of the front end's.
+  let spans: Vec<SpanRecord> = spans.0.lock().unwrap().iter()
+    .filter(|s| s.ctx.trace_id == root.trace_id)
+    .cloned().collect();
+  assert_eq!(spans.len(), 2);
+  assert_eq!(spans[0].name, "OK?");
+  assert_eq!(spans[0].ctx.parent_span_id, Some(root.span_id));
+  assert_eq!(spans[0].ctx.to_string(), back_seen);
+  assert_eq!(spans[1].name, "JSO");
+  assert_eq!(spans[1].ctx, root);
+  assert!(spans[1].duration >= spans[0].duration);
+}
+
+#[test]
+fn test_span_json_line() {
+  let span = SpanRecord{
+    ctx: TraceCtx{trace_id: (0xab_u128 << 64) | 1, span_id: 0x2a, parent_span_id: None},
+    name: "K\"V?".to_string(),
+    start: UNIX_EPOCH + StdDuration::from_micros(1_500_000),
+    duration: StdDuration::from_micros(250),
+  };
+  let line = "{\"trace_id\":\"00000000000000ab0000000000000001\",\"span_id\":\"000000000000002a\",\
+              \"parent_span_id\":null,\"name\":\"K\\\"V?\",\"start_us\":1500000,\"duration_us\":250}";
+  assert_eq!(span.to_json_line(), line);
+  let child = SpanRecord{ctx: TraceCtx{parent_span_id: Some(0x2a), span_id: 0x2b, ..span.ctx}, ..span.clone()};
+  assert!(child.to_json_line().contains("\"span_id\":\"000000000000002b\",\"parent_span_id\":\"000000000000002a\","));
+
+  // The file exporter appends one line per span.
+  let path = temp_dir().join(format!("svc-trace-test-{}.jsonl", process_id()));
+  let _ = remove_file(&path);
+  let exporter = FileSpanExporter::open(&path).unwrap();
+  exporter.export(&span);
+  exporter.export(&child);
+  let text = read_to_string(&path).unwrap();
+  remove_file(&path).unwrap();
+  assert_eq!(text, format!("{}\n{}\n", line, child.to_json_line()));
+  for l in text.lines() {
+    assert!(Json::from_str(l).unwrap().is_object());
+  }
+}
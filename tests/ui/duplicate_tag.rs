@@ -0,0 +1,11 @@
+use service_base::{MsgCodex};
+
+#[derive(MsgCodex)]
+enum Dup {
+  #[tag = "DUP"]
+  A,
+  #[tag = "DUP"]
+  B(u64),
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use service_base::{MsgCodex};
+
+#[derive(MsgCodex)]
+enum Reserved {
+  #[tag = "FOO"]
+  Foo,
+  #[tag = "PV?"]
+  Version(u64),
+}
+
+fn main() {}
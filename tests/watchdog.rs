@@ -0,0 +1,66 @@
+extern crate rustc_serialize;
+extern crate service_base;
+
+use service_base::msg::{Msg};
+use service_base::testkit::{chan_pair};
+use service_base::watchdog::{QueryBudget};
+
+use rustc_serialize::json::{Json};
+
+use std::str::{from_utf8};
+use std::thread::{sleep, spawn};
+use std::time::{Duration as StdDuration};
+
+#[test]
+fn test_budget_timeout_replies_and_closes() {
+  let (mut client, mut server) = chan_pair::<()>();
+  let budget = QueryBudget::new(StdDuration::from_millis(50));
+  server.set_query_budget(Some(budget.clone())).unwrap();
+  let h = spawn(move || {
+    server.reply(|_: &Msg| {
+      sleep(StdDuration::from_millis(300));
+      Msg::OKR
+    })
+  });
+  let tseq = client.send(&Msg::OKQ).unwrap();
+  {
+    let (hdr, payload) = client.recv_raw().unwrap();
+    assert_eq!(hdr.seq, tseq);
+    assert_eq!(&hdr.tag, b"!!!");
+    let details = Json::from_str(from_utf8(payload).unwrap()).unwrap();
+    assert_eq!(details.find("error").and_then(|e| e.as_string()), Some("timeout"));
+    assert_eq!(details.find("tag").and_then(|e| e.as_string()), Some("OK?"));
+  }
+  // The watchdog shut the connection down.
+  assert!(client.recv().is_err());
+  // The handler's late reply is dropped, and the connection closed.
+  assert!(h.join().unwrap().unwrap());
+  assert_eq!(budget.timeouts(), 1);
+}
+
+#[test]
+fn test_budget_finish_before_timeout() {
+  let (mut client, mut server) = chan_pair::<()>();
+  let budget = QueryBudget::new(StdDuration::from_millis(100));
+  server.set_query_budget(Some(budget.clone())).unwrap();
+  let h = spawn(move || {
+    let mut halts = Vec::new();
+    for _ in 0 .. 2 {
+      halts.push(server.reply(|_: &Msg| Msg::OKR).unwrap());
+    }
+    halts
+  });
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::OKR) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  // Well past the budget of the finished query, the connection is
+  // still up.
+  sleep(StdDuration::from_millis(300));
+  match client.query(&Msg::OKQ) {
+    Ok(Msg::OKR) => {}
+    x => panic!("unexpected reply: {:?}", x),
+  }
+  assert_eq!(h.join().unwrap(), vec![false, false]);
+  assert_eq!(budget.timeouts(), 0);
+}